@@ -0,0 +1,31 @@
+//! Serves lookups to concurrent tokio tasks from one store.
+//!
+//! Run with `cargo run --example async --features async -- FILE`.
+
+use std::env;
+
+use libactionkv::AsyncActionKV;
+
+#[tokio::main]
+async fn main() -> libactionkv::Result<()> {
+    let path = env::args().nth(1).expect("usage: async FILE");
+    let store = AsyncActionKV::open(&path).await?;
+
+    let tasks: Vec<_> = (0..4)
+        .map(|worker| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let key = format!("worker-{}", worker);
+                store.insert(key.as_bytes(), b"done").await?;
+                store.get(key.as_bytes()).await
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        let value = task.await.expect("worker panicked")?;
+        println!("{:?}", value.map(String::from_utf8));
+    }
+
+    store.sync().await
+}
@@ -0,0 +1,135 @@
+//! An async handle for tokio, behind the `async` feature.
+
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use tokio::task;
+
+use crate::{ActionKV, ByteStr, ByteString, Result, SharedActionKV};
+
+/// A store for async code, cloned cheaply into every task that needs it.
+///
+/// Each call runs the blocking store on tokio's blocking thread pool with
+/// `spawn_blocking`, so reading the log and computing checksums never stall
+/// the executor. Calls share one [`SharedActionKV`] and lock the same way.
+///
+/// The log is the one [`ActionKV`] writes, so files move freely between
+/// the two.
+///
+/// Calls must be made from within a tokio runtime.
+#[derive(Debug, Clone)]
+pub struct AsyncActionKV {
+    store: SharedActionKV,
+}
+
+impl AsyncActionKV {
+    /// Opens and loads the store at `path`.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path: PathBuf = path.as_ref().to_path_buf();
+        let store = blocking(move || SharedActionKV::open(&path)).await?;
+        Ok(AsyncActionKV { store })
+    }
+
+    /// Shares `store`, which should already be loaded.
+    pub fn new(store: ActionKV) -> Self {
+        AsyncActionKV {
+            store: SharedActionKV::new(store),
+        }
+    }
+
+    /// The blocking handle underneath, for use outside async code.
+    pub fn blocking(&self) -> &SharedActionKV {
+        &self.store
+    }
+
+    pub async fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        let key = key.to_vec();
+        self.run(move |store| store.get(&key)).await
+    }
+
+    pub async fn contains_key(&self, key: &ByteStr) -> Result<bool> {
+        let key = key.to_vec();
+        self.run(move |store| Ok(store.contains_key(&key))).await
+    }
+
+    /// See [`ActionKV::insert`], including its durability caveat.
+    pub async fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.run(move |store| store.insert(&key, &value)).await
+    }
+
+    pub async fn update(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.insert(key, value).await
+    }
+
+    pub async fn delete(&self, key: &ByteStr) -> Result<()> {
+        let key = key.to_vec();
+        self.run(move |store| store.delete(&key)).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|store| store.flush()).await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.run(|store| store.sync()).await
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&SharedActionKV) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.store.clone();
+        blocking(move || f(&store)).await
+    }
+}
+
+/// Runs `f` on the blocking pool, passing its panics on to the caller.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+        Err(err) => Err(io::Error::other(err).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn files_move_between_the_async_and_blocking_handles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"sync", b"1").unwrap();
+        drop(store);
+
+        let store = AsyncActionKV::open(&path).await.unwrap();
+        assert_eq!(store.get(b"sync").await.unwrap(), Some(b"1".to_vec()));
+        let tasks: Vec<_> = (0..8u8)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move { store.insert(&[i], &[i]).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        store.delete(b"sync").await.unwrap();
+        store.flush().await.unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.load().unwrap(), 10);
+        assert_eq!(store.len(), 8);
+        assert_eq!(store.get(&[7]).unwrap(), Some(vec![7]));
+    }
+}
@@ -0,0 +1,198 @@
+//! Chainable configuration for opening a store by path.
+
+use std::path::Path;
+
+use crate::checksum::ChecksumAlgorithm;
+#[cfg(feature = "compression")]
+use crate::compression::Compression;
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
+use crate::format::Endianness;
+use crate::index::IndexKind;
+use crate::options::{CorruptionPolicy, Options, SyncPolicy};
+use crate::{ActionKV, Result};
+
+/// Builds the [`Options`] for a store one setting at a time and opens it,
+/// from [`ActionKV::builder`].
+///
+/// Each setter sets the [`Options`] field of the same name, documented
+/// there, and anything left unset keeps its default. Settings that only
+/// describe the layout of a new log are ignored for one that exists.
+///
+/// ```
+/// use libactionkv::{ActionKV, ChecksumAlgorithm, SyncPolicy};
+///
+/// let dir = tempfile::tempdir()?;
+/// let mut store = ActionKV::builder()
+///     .checksum(ChecksumAlgorithm::Crc32c)
+///     .sync_policy(SyncPolicy::Always)
+///     .open(&dir.path().join("store.akv"))?;
+/// store.insert(b"key", b"value")?;
+/// drop(store);
+///
+/// let mut reader = ActionKV::builder()
+///     .read_only(true)
+///     .verify_checksums(false)
+///     .open(&dir.path().join("store.akv"))?;
+/// reader.load()?;
+/// assert_eq!(reader.get(b"key")?, Some(b"value".to_vec()));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ActionKVBuilder {
+    options: Options,
+    read_only: bool,
+}
+
+impl ActionKV {
+    /// Starts configuring a store to open, with every setting at its
+    /// default.
+    pub fn builder() -> ActionKVBuilder {
+        ActionKVBuilder::default()
+    }
+}
+
+impl ActionKVBuilder {
+    /// Starts from `options` instead of the defaults.
+    pub fn with_options(options: Options) -> Self {
+        ActionKVBuilder {
+            options,
+            read_only: false,
+        }
+    }
+
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.options.endianness = endianness;
+        self
+    }
+
+    pub fn checksum(mut self, checksum: ChecksumAlgorithm) -> Self {
+        self.options.checksum = checksum;
+        self
+    }
+
+    pub fn index(mut self, index: IndexKind) -> Self {
+        self.options.index = index;
+        self
+    }
+
+    pub fn index_capacity(mut self, capacity: usize) -> Self {
+        self.options.index_capacity = capacity;
+        self
+    }
+
+    pub fn build_index(mut self, build: bool) -> Self {
+        self.options.build_index = build;
+        self
+    }
+
+    pub fn recover_torn_tail(mut self, recover: bool) -> Self {
+        self.options.recover_torn_tail = recover;
+        self
+    }
+
+    pub fn ignore_zeroed_tail(mut self, ignore: bool) -> Self {
+        self.options.ignore_zeroed_tail = ignore;
+        self
+    }
+
+    pub fn on_corruption(mut self, policy: CorruptionPolicy) -> Self {
+        self.options.on_corruption = policy;
+        self
+    }
+
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.options.sync_policy = policy;
+        self
+    }
+
+    pub fn max_segment_bytes(mut self, bytes: u64) -> Self {
+        self.options.max_segment_bytes = Some(bytes);
+        self
+    }
+
+    pub fn verify_checksums(mut self, verify: bool) -> Self {
+        self.options.verify_checksums = verify;
+        self
+    }
+
+    pub fn reject_empty_keys(mut self, reject: bool) -> Self {
+        self.options.reject_empty_keys = reject;
+        self
+    }
+
+    pub fn skip_identical_writes(mut self, skip: bool) -> Self {
+        self.options.skip_identical_writes = skip;
+        self
+    }
+
+    pub fn max_value_bytes(mut self, bytes: u64) -> Self {
+        self.options.max_value_bytes = Some(bytes);
+        self
+    }
+
+    pub fn max_total_bytes(mut self, bytes: u64) -> Self {
+        self.options.max_total_bytes = Some(bytes);
+        self
+    }
+
+    pub fn buffer_capacity(mut self, capacity: usize) -> Self {
+        self.options.buffer_capacity = Some(capacity);
+        self
+    }
+
+    pub fn record_alignment(mut self, alignment: u64) -> Self {
+        self.options.record_alignment = Some(alignment);
+        self
+    }
+
+    pub fn record_write_times(mut self, record: bool) -> Self {
+        self.options.record_write_times = record;
+        self
+    }
+
+    pub fn auto_compact_ratio(mut self, ratio: f64) -> Self {
+        self.options.auto_compact_ratio = Some(ratio);
+        self
+    }
+
+    /// ```
+    /// use libactionkv::{ActionKV, Compression};
+    ///
+    /// let dir = tempfile::tempdir()?;
+    /// let mut store = ActionKV::builder()
+    ///     .compression(Compression::default())
+    ///     .open(&dir.path().join("store.akv"))?;
+    /// store.insert(b"key", &[b'a'; 1000])?;
+    /// assert_eq!(store.get(b"key")?, Some(vec![b'a'; 1000]));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = Some(compression);
+        self
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: EncryptionKey) -> Self {
+        self.options.encryption = Some(key);
+        self
+    }
+
+    /// Opens an existing log for reading only, under a shared lock, as
+    /// [`ActionKV::open_shared`] does, instead of opening or creating it
+    /// for writing. Settings for writes have no effect then.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Opens the store at `path` with the settings so far.
+    pub fn open(self, path: &Path) -> Result<ActionKV> {
+        if self.read_only {
+            ActionKV::open_read(path, true, self.options)
+        } else {
+            ActionKV::open_with_options(path, self.options)
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Record checksums.
+//!
+//! Every record starts with a checksum over its flags, sequence number,
+//! expiry, key and value. The algorithm is chosen when a log is created and
+//! recorded in its header.
+
+use std::hash::Hasher;
+
+use crc::crc32;
+
+/// Checksum algorithm of a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgorithm {
+    /// CRC-32 (IEEE), 4 bytes. The only choice for logs from before the
+    /// algorithm was recorded.
+    #[default]
+    Crc32,
+    /// CRC-32C (Castagnoli), 4 bytes. Hardware accelerated on most CPUs.
+    Crc32c,
+    /// 64-bit xxHash, 8 bytes.
+    XxHash64,
+}
+
+impl ChecksumAlgorithm {
+    /// Number of bytes the checksum takes in each record.
+    pub(crate) fn width(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Crc32 | ChecksumAlgorithm::Crc32c => 4,
+            ChecksumAlgorithm::XxHash64 => 8,
+        }
+    }
+
+    pub(crate) fn hasher(self) -> Box<dyn Checksum> {
+        match self {
+            ChecksumAlgorithm::Crc32 => Box::new(Crc32(0)),
+            ChecksumAlgorithm::Crc32c => Box::new(Crc32c(0)),
+            ChecksumAlgorithm::XxHash64 => Box::new(XxHash64(twox_hash::XxHash64::with_seed(0))),
+        }
+    }
+
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc32 => 0,
+            ChecksumAlgorithm::Crc32c => 1,
+            ChecksumAlgorithm::XxHash64 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<ChecksumAlgorithm> {
+        match id {
+            0 => Some(ChecksumAlgorithm::Crc32),
+            1 => Some(ChecksumAlgorithm::Crc32c),
+            2 => Some(ChecksumAlgorithm::XxHash64),
+            _ => None,
+        }
+    }
+}
+
+/// An incremental checksum. 32-bit algorithms zero-extend their result.
+pub(crate) trait Checksum {
+    fn update(&mut self, bytes: &[u8]);
+    fn finish(&self) -> u64;
+}
+
+struct Crc32(u32);
+
+impl Checksum for Crc32 {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0 = crc32::update(self.0, &crc32::IEEE_TABLE, bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0 as u64
+    }
+}
+
+struct Crc32c(u32);
+
+impl Checksum for Crc32c {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0 = crc32c::crc32c_append(self.0, bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0 as u64
+    }
+}
+
+struct XxHash64(twox_hash::XxHash64);
+
+impl Checksum for XxHash64 {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksum(algorithm: ChecksumAlgorithm, parts: &[&[u8]]) -> u64 {
+        let mut hasher = algorithm.hasher();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn known_values() {
+        let data: &[u8] = b"123456789";
+        assert_eq!(checksum(ChecksumAlgorithm::Crc32, &[data]), 0xcbf4_3926);
+        assert_eq!(checksum(ChecksumAlgorithm::Crc32c, &[data]), 0xe306_9283);
+        assert_eq!(
+            checksum(ChecksumAlgorithm::XxHash64, &[data]),
+            twox_hash::XxHash64::oneshot(0, data)
+        );
+    }
+
+    #[test]
+    fn updates_are_incremental() {
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::XxHash64,
+        ] {
+            assert_eq!(
+                checksum(algorithm, &[b"1234", b"56789"]),
+                checksum(algorithm, &[b"123456789"])
+            );
+        }
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::XxHash64,
+        ] {
+            assert_eq!(ChecksumAlgorithm::from_id(algorithm.id()), Some(algorithm));
+        }
+        assert_eq!(ChecksumAlgorithm::from_id(9), None);
+    }
+}
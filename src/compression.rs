@@ -0,0 +1,132 @@
+//! Per-value compression, behind the `compression` feature.
+//!
+//! A compressed value is stored as a single zstd frame and its record carries
+//! [`FLAG_COMPRESSED`](crate::format::FLAG_COMPRESSED). The record checksum
+//! covers the compressed bytes, so corruption is caught before anything is
+//! decompressed.
+
+use std::borrow::Cow;
+use std::io;
+
+use crate::format::Format;
+#[cfg(feature = "compression")]
+use crate::format::FLAG_COMPRESSED;
+use crate::options::Options;
+#[cfg(feature = "compression")]
+use crate::Error;
+use crate::{ByteStr, ByteString, Result};
+
+/// How values are compressed when they are written.
+///
+/// Reading never needs this: compressed records are recognised by their flag
+/// whatever the store was opened with.
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// zstd compression level. Defaults to 3, zstd's own default.
+    pub level: i32,
+    /// Values shorter than this are stored verbatim. Defaults to 64 bytes.
+    pub min_len: usize,
+}
+
+#[cfg(feature = "compression")]
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            level: 3,
+            min_len: 64,
+        }
+    }
+}
+
+/// Returns the bytes to store for `value` and the record flags describing
+/// them.
+///
+/// Values are only stored compressed when compression is enabled, the format
+/// has a flags byte, the value is at least `min_len` long, and compressing it
+/// actually saves space.
+#[cfg(feature = "compression")]
+pub(crate) fn encode<'v>(
+    options: &Options,
+    format: Format,
+    value: &'v ByteStr,
+) -> io::Result<(Cow<'v, ByteStr>, u8)> {
+    if let Some(compression) = options.compression {
+        if format.has_flags() && value.len() >= compression.min_len {
+            let compressed = zstd::bulk::compress(value, compression.level)?;
+            if compressed.len() < value.len() {
+                return Ok((Cow::Owned(compressed), FLAG_COMPRESSED));
+            }
+        }
+    }
+
+    Ok((Cow::Borrowed(value), 0))
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn encode<'v>(
+    _options: &Options,
+    _format: Format,
+    value: &'v ByteStr,
+) -> io::Result<(Cow<'v, ByteStr>, u8)> {
+    Ok((Cow::Borrowed(value), 0))
+}
+
+/// Decompresses a value whose checksum has already been verified.
+#[cfg(feature = "compression")]
+pub(crate) fn decode(stored: &ByteStr) -> Result<ByteString> {
+    zstd::stream::decode_all(stored)
+        .map_err(|err| Error::BadFormat(format!("failed to decompress value: {}", err)))
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decode(_stored: &ByteStr) -> Result<ByteString> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compressed record needs the `compression` feature",
+    )
+    .into())
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            compression: Some(Compression::default()),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = b"compressible ".repeat(20);
+        let (stored, flags) = encode(&options(), Format::CURRENT, &value).unwrap();
+
+        assert_eq!(flags, FLAG_COMPRESSED);
+        assert!(stored.len() < value.len());
+        assert_eq!(decode(&stored).unwrap(), value);
+    }
+
+    #[test]
+    fn short_or_incompressible_values_are_stored_verbatim() {
+        let (stored, flags) = encode(&options(), Format::CURRENT, b"tiny").unwrap();
+        assert_eq!((&*stored, flags), (&b"tiny"[..], 0));
+
+        let mut state = 0x2545_f491_u32;
+        let noise: ByteString = (0..256)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let (stored, flags) = encode(&options(), Format::CURRENT, &noise).unwrap();
+        assert_eq!((&*stored, flags), (&noise[..], 0));
+
+        let (_, flags) = encode(&options(), Format::LEGACY, &b"x".repeat(100)).unwrap();
+        assert_eq!(flags, 0);
+    }
+}
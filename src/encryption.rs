@@ -0,0 +1,203 @@
+//! Encryption of values at rest, behind the `encryption` feature.
+//!
+//! An encrypted value is stored as a random 96-bit nonce followed by its
+//! AES-256-GCM ciphertext and tag, and its record carries
+//! [`FLAG_ENCRYPTED`](crate::format::FLAG_ENCRYPTED). The record's key is
+//! the cipher's associated data, so a value can't be moved to another key
+//! without failing to decrypt. The record checksum covers the stored bytes,
+//! so corruption is caught before anything is decrypted.
+//!
+//! Keys, lengths, flags, sequence numbers and expiry times stay in the
+//! clear.
+
+use std::borrow::Cow;
+use std::io;
+
+#[cfg(feature = "encryption")]
+use std::fmt;
+
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "encryption")]
+use aes_gcm::{Aes256Gcm, Nonce};
+
+use crate::format::Format;
+#[cfg(feature = "encryption")]
+use crate::format::FLAG_ENCRYPTED;
+use crate::options::Options;
+use crate::{ByteStr, ByteString, Result};
+
+/// Length of the nonce stored in front of each encrypted value.
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit AES-GCM key for values written and read through a store.
+///
+/// The key is never stored, in the log or anywhere else. Losing it means
+/// losing every value written with it: there is no way to get them back.
+#[cfg(feature = "encryption")]
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+#[cfg(feature = "encryption")]
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        EncryptionKey(key)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Returns the bytes to store for `value`, already encoded as described by
+/// `flags`, under `key`, and the flags describing them.
+///
+/// Values are only encrypted when a key is set. Legacy logs have no flags
+/// byte to mark an encrypted value, so writing to one with a key set fails
+/// with `InvalidInput` rather than storing the value in the clear.
+#[cfg(feature = "encryption")]
+pub(crate) fn encrypt<'v>(
+    options: &Options,
+    format: Format,
+    key: &ByteStr,
+    value: Cow<'v, ByteStr>,
+    flags: u8,
+) -> io::Result<(Cow<'v, ByteStr>, u8)> {
+    let encryption = match &options.encryption {
+        Some(encryption) => encryption,
+        None => return Ok((value, flags)),
+    };
+    if !format.has_flags() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "legacy logs can't hold encrypted values",
+        ));
+    }
+
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: &value,
+        aad: key,
+    };
+    let ciphertext = encryption
+        .cipher()
+        .encrypt(&nonce, payload)
+        .map_err(|_| io::Error::other("failed to encrypt value"))?;
+
+    let mut stored = ByteString::with_capacity(NONCE_LEN + ciphertext.len());
+    stored.extend_from_slice(&nonce);
+    stored.extend_from_slice(&ciphertext);
+    Ok((Cow::Owned(stored), flags | FLAG_ENCRYPTED))
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn encrypt<'v>(
+    _options: &Options,
+    _format: Format,
+    _key: &ByteStr,
+    value: Cow<'v, ByteStr>,
+    flags: u8,
+) -> io::Result<(Cow<'v, ByteStr>, u8)> {
+    Ok((value, flags))
+}
+
+/// Whether values written through a store with `options` get encrypted.
+#[cfg(feature = "encryption")]
+pub(crate) fn enabled(options: &Options) -> bool {
+    options.encryption.is_some()
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn enabled(_options: &Options) -> bool {
+    false
+}
+
+/// Decrypts the value of `key` whose checksum has already been verified.
+///
+/// Fails with `InvalidInput` when the store has no key, and with
+/// `InvalidData` when the value doesn't decrypt under it: the key is wrong
+/// or the record was altered along with its checksum.
+#[cfg(feature = "encryption")]
+pub(crate) fn decrypt(options: &Options, key: &ByteStr, stored: &ByteStr) -> Result<ByteString> {
+    let encryption = options.encryption.as_ref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encrypted record needs an encryption key",
+        )
+    })?;
+    let undecryptable = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "failed to decrypt value: wrong key or altered record",
+        )
+    };
+    if stored.len() < NONCE_LEN {
+        return Err(undecryptable().into());
+    }
+
+    let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: key,
+    };
+    encryption
+        .cipher()
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| undecryptable().into())
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn decrypt(_options: &Options, _key: &ByteStr, _stored: &ByteStr) -> Result<ByteString> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "encrypted record needs the `encryption` feature",
+    )
+    .into())
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+
+    fn options(key: u8) -> Options {
+        Options {
+            encryption: Some(EncryptionKey::new([key; 32])),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn round_trip_with_a_fresh_nonce_each_time() {
+        let value = Cow::Borrowed(&b"secret"[..]);
+        let (first, flags) = encrypt(&options(1), Format::CURRENT, b"k", value.clone(), 0).unwrap();
+        let (second, _) = encrypt(&options(1), Format::CURRENT, b"k", value, 0).unwrap();
+
+        assert_eq!(flags, FLAG_ENCRYPTED);
+        assert_eq!(first.len(), NONCE_LEN + 6 + 16);
+        assert_ne!(first, second);
+        assert_eq!(decrypt(&options(1), b"k", &first).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn the_wrong_key_or_record_key_fails_to_decrypt() {
+        let value = Cow::Borrowed(&b"secret"[..]);
+        let (stored, _) = encrypt(&options(1), Format::CURRENT, b"k", value.clone(), 0).unwrap();
+
+        for (options, key) in [(options(2), &b"k"[..]), (options(1), b"other")] {
+            let err = decrypt(&options, key, &stored).unwrap_err();
+            assert!(
+                matches!(err, crate::Error::Io(err) if err.kind() == io::ErrorKind::InvalidData)
+            );
+        }
+        let err = encrypt(&options(1), Format::LEGACY, b"k", value, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,98 @@
+//! The errors a store reports.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Result of a store operation.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What went wrong in a store operation.
+///
+/// Damage to the log comes back as [`Corrupt`](Error::Corrupt),
+/// [`Truncated`](Error::Truncated) or [`BadFormat`](Error::BadFormat), never
+/// as [`Io`](Error::Io), so a caller can tell a broken log from a failing
+/// disk. Arguments the store refuses, such as a key too long for the format,
+/// are `Io` errors of kind `InvalidInput`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing the underlying storage failed.
+    Io(io::Error),
+    /// The record at `offset` doesn't match its stored checksum. 32-bit
+    /// checksums are zero-extended.
+    Corrupt {
+        offset: u64,
+        expected: u64,
+        actual: u64,
+    },
+    /// The record at `offset` is shorter than its header promises, because
+    /// the log ends partway through it.
+    Truncated { offset: u64 },
+    /// The log header or a record can't be decoded by this version.
+    BadFormat(String),
+}
+
+impl Error {
+    /// Whether the error describes damage to the log rather than a failure
+    /// to reach it.
+    pub(crate) fn is_damage(&self) -> bool {
+        match self {
+            Error::Io(_) => false,
+            Error::Corrupt { .. } | Error::Truncated { .. } | Error::BadFormat(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::Corrupt {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "corrupt record at offset {} ({:08x} != {:08x})",
+                offset, actual, expected
+            ),
+            Error::Truncated { offset } => {
+                write!(f, "log ends in a partial record at offset {}", offset)
+            }
+            Error::BadFormat(msg) => write!(f, "unreadable log: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_are_the_source() {
+        let err = Error::from(io::Error::other("disk on fire"));
+        assert!(!err.is_damage());
+        assert_eq!(err.to_string(), "disk on fire");
+        assert!(error::Error::source(&err).is_some());
+
+        let err = Error::Truncated { offset: 6 };
+        assert!(err.is_damage());
+        assert!(error::Error::source(&err).is_none());
+    }
+}
@@ -0,0 +1,295 @@
+//! Column families: separate key spaces sharing one log.
+//!
+//! A record outside the default family carries the id of its family in a
+//! field of its own, flagged with
+//! [`FLAG_FAMILY`](crate::format::FLAG_FAMILY), and every family has its
+//! own index, which [`load`](ActionKV::load) rebuilds along with the
+//! default one. The names behind the ids live in the log as well, as the
+//! keys of a reserved catalog family: each is the family's id in big-endian
+//! bytes followed by its name.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+
+use crate::format::FLAG_TOMBSTONE;
+use crate::index::{Index, IndexKind};
+use crate::{ActionKV, ByteStr, ByteString, Extras, Result, Storage};
+
+/// Id of the family whose keys name the others.
+pub(crate) const CATALOG: u32 = u32::MAX;
+
+/// The index of `family`: `index` for the default family, and otherwise
+/// its entry in `families`, which starts out empty.
+pub(crate) fn index_mut<'a>(
+    index: &'a mut Index,
+    families: &'a mut HashMap<u32, Index>,
+    kind: IndexKind,
+    family: u32,
+) -> &'a mut Index {
+    match family {
+        0 => index,
+        // The catalog is read by its keys, so it always keeps them.
+        CATALOG => families
+            .entry(CATALOG)
+            .or_insert_with(|| Index::new(IndexKind::Hash)),
+        family => families.entry(family).or_insert_with(|| Index::new(kind)),
+    }
+}
+
+/// Positions of the live records of every family, in log order.
+pub(crate) fn positions(index: &Index, families: &HashMap<u32, Index>) -> Vec<u64> {
+    let mut positions: Vec<u64> = index
+        .values()
+        .chain(families.values().flat_map(Index::values))
+        .copied()
+        .collect();
+    positions.sort_unstable();
+    positions
+}
+
+/// One column family of a store, from [`ActionKV::cf`].
+///
+/// Its keys are a space of their own: the same key can hold a different
+/// value in every family, and neither the store's own methods nor any other
+/// family see this family's keys. Values are read, written and compacted
+/// exactly as the store's own are.
+#[derive(Debug)]
+pub struct ColumnFamily<'a, S: Storage = File> {
+    store: &'a mut ActionKV<S>,
+    id: u32,
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// The column family called `name`, which is created in the log if it
+    /// doesn't exist yet. Every method of the store itself works on the
+    /// default family, which has no name.
+    ///
+    /// Families need a record flags byte, so legacy logs refuse to create
+    /// one with `InvalidInput`, and so do read-only handles, with
+    /// `PermissionDenied`.
+    ///
+    /// Index sidecars and hint files only have room for the default family,
+    /// so once a store has other families,
+    /// [`save_index`](ActionKV::save_index) fails and compaction writes no
+    /// hints.
+    pub fn cf(&mut self, name: &str) -> Result<ColumnFamily<'_, S>> {
+        self.check_indexed()?;
+        let id = match self.family_id(name) {
+            Some(id) => id,
+            None => self.create_family(name)?,
+        };
+        Ok(ColumnFamily { store: self, id })
+    }
+
+    /// Names of the column families besides the default one, sorted.
+    pub fn column_families(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .catalog()
+            .map(|(_, name)| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The id and name of every family in the catalog.
+    fn catalog(&self) -> impl Iterator<Item = (u32, &ByteStr)> {
+        self.families
+            .get(&CATALOG)
+            .into_iter()
+            .flat_map(Index::keys)
+            .filter_map(|key| {
+                let (id, name) = key.split_at_checked(4)?;
+                Some((u32::from_be_bytes(id.try_into().ok()?), name))
+            })
+    }
+
+    fn family_id(&self, name: &str) -> Option<u32> {
+        self.catalog()
+            .find(|(_, known)| *known == name.as_bytes())
+            .map(|(id, _)| id)
+    }
+
+    fn create_family(&mut self, name: &str) -> Result<u32> {
+        if !self.format.has_flags() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "legacy logs cannot hold column families",
+            )
+            .into());
+        }
+
+        let id = self.catalog().map(|(id, _)| id).max().unwrap_or(0) + 1;
+        if id == CATALOG {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "the store has run out of column family ids",
+            )
+            .into());
+        }
+
+        let mut key = id.to_be_bytes().to_vec();
+        key.extend_from_slice(name.as_bytes());
+        ColumnFamily {
+            store: self,
+            id: CATALOG,
+        }
+        .insert(&key, b"")?;
+        Ok(id)
+    }
+}
+
+impl<S: Storage> ColumnFamily<'_, S> {
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        let position = match self.index().and_then(|index| index.get(key)) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        // An index of hashed keys can point at another key's record.
+        Ok(self
+            .store
+            .get_live(position)?
+            .filter(|kv| kv.key == key)
+            .map(|kv| kv.value))
+    }
+
+    /// Whether `key` has a live value. With an index of hashed keys the key
+    /// of the record is read and compared, and a failed read counts as no.
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.index().is_some_and(|index| {
+            self.store
+                .position_in(index, key)
+                .is_ok_and(|position| position.is_some())
+        })
+    }
+
+    /// Number of live keys in the family.
+    pub fn len(&self) -> usize {
+        self.index().map_or(0, Index::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// See [`ActionKV::insert`], including its durability caveat.
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        let position = self.store.append(key, value, 0, self.extras())?;
+        self.index_mut().insert(key.to_vec(), position);
+        Ok(())
+    }
+
+    /// See [`ActionKV::delete`].
+    pub fn delete(&mut self, key: &ByteStr) -> Result<()> {
+        self.store.append(key, b"", FLAG_TOMBSTONE, self.extras())?;
+        self.index_mut().remove(key);
+        Ok(())
+    }
+
+    /// The fields that put a record in this family.
+    fn extras(&self) -> Extras {
+        Extras {
+            family: self.id,
+            ..Extras::default()
+        }
+    }
+
+    fn index(&self) -> Option<&Index> {
+        self.store.families.get(&self.id)
+    }
+
+    fn index_mut(&mut self) -> &mut Index {
+        let store = &mut *self.store;
+        let kind = store.index.kind();
+        index_mut(&mut store.index, &mut store.families, kind, self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn families_keep_their_keys_apart_across_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"k", b"default").unwrap();
+        store.cf("users").unwrap().insert(b"k", b"1").unwrap();
+        let mut orders = store.cf("orders").unwrap();
+        orders.insert(b"k", b"2").unwrap();
+        orders.insert(b"gone", b"2").unwrap();
+        orders.delete(b"gone").unwrap();
+        assert_eq!(orders.len(), 1);
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.column_families(), ["orders", "users"]);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(b"k").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(
+            store.cf("users").unwrap().get(b"k").unwrap(),
+            Some(b"1".to_vec())
+        );
+        let orders = store.cf("orders").unwrap();
+        assert_eq!(orders.get(b"k").unwrap(), Some(b"2".to_vec()));
+        assert!(!orders.contains_key(b"gone"));
+        assert!(store.cf("new").unwrap().is_empty());
+        assert_eq!(store.read_from(0).count(), 1);
+    }
+
+    #[test]
+    fn lookups_compare_the_key_of_the_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = crate::Options {
+            index: IndexKind::HashedKeys,
+            ..crate::Options::default()
+        };
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        let mut family = store.cf("f").unwrap();
+        family.insert(b"a", b"1").unwrap();
+        let id = family.id;
+
+        // Points "b" at the record of "a", as colliding hashes would.
+        let index = store.families.get_mut(&id).unwrap();
+        let position = *index.get(b"a").unwrap();
+        index.insert(b"b".to_vec(), position);
+
+        let family = store.cf("f").unwrap();
+        assert_eq!(family.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(family.contains_key(b"a"));
+        assert_eq!(family.get(b"b").unwrap(), None);
+        assert!(!family.contains_key(b"b"));
+    }
+
+    #[test]
+    fn compaction_keeps_every_family() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let mut family = store.cf("f").unwrap();
+        family.insert(b"a", b"old").unwrap();
+        family.insert(b"a", b"new").unwrap();
+
+        store.compact(&dir.path().join("store.compact")).unwrap();
+        assert!(!crate::segment::hint_path(&path, 0).exists());
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.dead_bytes, 0);
+        let err = store.save_index(&dir.path().join("store.idx")).unwrap_err();
+        assert!(matches!(err, crate::Error::Io(err) if err.kind() == io::ErrorKind::InvalidInput));
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(
+            store.cf("f").unwrap().get(b"a").unwrap(),
+            Some(b"new".to_vec())
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! On-disk layout of the log: the file header and the per-version record
+//! fields.
+//!
+//! A log starts with a header of `MAGIC` followed by a one-byte format
+//! version and any header fields that version adds. Files written before the
+//! header existed have no magic and are read as [`LEGACY`](Format::LEGACY)
+//! logs, as long as they start with an intact record.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::{Error, Result};
+
+/// Marks the start of a log that carries a format header.
+pub(crate) const MAGIC: &[u8; 3] = b"AKV";
+
+/// Record flag: the record deletes its key.
+pub(crate) const FLAG_TOMBSTONE: u8 = 0x01;
+
+/// Record flag: the value is stored zstd compressed.
+pub(crate) const FLAG_COMPRESSED: u8 = 0x02;
+
+/// Record flag: a u64 expiry time, in Unix milliseconds, follows the flags
+/// byte.
+pub(crate) const FLAG_EXPIRES: u8 = 0x04;
+
+/// Record flag: the value is stored encrypted, after any compression.
+pub(crate) const FLAG_ENCRYPTED: u8 = 0x08;
+
+/// Record flag: a u32 column family id follows the expiry field, if any.
+/// Records without it belong to the default family, 0.
+pub(crate) const FLAG_FAMILY: u8 = 0x10;
+
+/// Record flag: the record only fills the log up to where the next record
+/// starts, as [`Options::record_alignment`](crate::Options) asks. It has an
+/// empty key and a value of zeros, and belongs to no key.
+pub(crate) const FLAG_PADDING: u8 = 0x20;
+
+/// Record flag: a u64 write time, in Unix milliseconds, follows the column
+/// family field, if any, as [`Options::record_write_times`](crate::Options)
+/// asks.
+pub(crate) const FLAG_WRITTEN_AT: u8 = 0x40;
+
+/// Every record flag this version understands.
+pub(crate) const KNOWN_FLAGS: u8 = FLAG_TOMBSTONE
+    | FLAG_COMPRESSED
+    | FLAG_EXPIRES
+    | FLAG_ENCRYPTED
+    | FLAG_FAMILY
+    | FLAG_PADDING
+    | FLAG_WRITTEN_AT;
+
+/// Byte order of the integers in a log.
+///
+/// A new log records its byte order in the header, and reopening it uses the
+/// recorded order whatever the caller asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Format {
+    pub(crate) version: u8,
+    pub(crate) endianness: Endianness,
+    pub(crate) checksum: ChecksumAlgorithm,
+}
+
+impl Format {
+    /// Headerless logs: `checksum | key_len | val_len | key | value`.
+    pub(crate) const LEGACY: Format = Format {
+        version: 0,
+        endianness: Endianness::Little,
+        checksum: ChecksumAlgorithm::Crc32,
+    };
+
+    /// Adds a flags byte after the lengths, covered by the checksum.
+    #[cfg(test)]
+    pub(crate) const V1: Format = Format {
+        version: 1,
+        endianness: Endianness::Little,
+        checksum: ChecksumAlgorithm::Crc32,
+    };
+
+    // Version 2 adds the byte order to the header, version 3 the checksum
+    // algorithm, version 4 widens the key and value lengths to u64, and
+    // version 5 adds a sequence number to every record.
+    const LATEST_VERSION: u8 = 5;
+
+    /// The format of new logs with the default options.
+    #[cfg(test)]
+    pub(crate) const CURRENT: Format = Format::new(Endianness::Little, ChecksumAlgorithm::Crc32);
+
+    /// The latest format, with the given byte order and checksum.
+    pub(crate) const fn new(endianness: Endianness, checksum: ChecksumAlgorithm) -> Format {
+        Format {
+            version: Format::LATEST_VERSION,
+            endianness,
+            checksum,
+        }
+    }
+
+    /// Whether records carry a flags byte, and so can be tombstones.
+    pub(crate) fn has_flags(&self) -> bool {
+        self.version >= 1
+    }
+
+    /// Whether records store their key and value lengths as u64 rather than
+    /// u32.
+    pub(crate) fn has_wide_lengths(&self) -> bool {
+        self.version >= 4
+    }
+
+    /// Whether records carry a u64 sequence number after the flags byte.
+    pub(crate) fn has_sequence(&self) -> bool {
+        self.version >= 5
+    }
+
+    /// Longest key or value a record can hold.
+    pub(crate) fn max_len(&self) -> u64 {
+        if self.has_wide_lengths() {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        }
+    }
+
+    /// Bytes a record takes before its expiry and key: the checksum, both
+    /// lengths, the flags byte and the sequence number.
+    pub(crate) fn record_header_len(&self) -> usize {
+        let len_width = if self.has_wide_lengths() { 8 } else { 4 };
+        let sequence_width = if self.has_sequence() { 8 } else { 0 };
+        self.checksum.width() + 2 * len_width + self.has_flags() as usize + sequence_width
+    }
+
+    /// Offset of the first record.
+    pub(crate) fn data_start(&self) -> u64 {
+        self.header().len() as u64
+    }
+
+    pub(crate) fn header(&self) -> Vec<u8> {
+        if self.version == 0 {
+            return Vec::new();
+        }
+
+        let mut header = MAGIC.to_vec();
+        header.push(self.version);
+        if self.version >= 2 {
+            header.push(match self.endianness {
+                Endianness::Little => 0,
+                Endianness::Big => 1,
+            });
+        }
+        if self.version >= 3 {
+            header.push(self.checksum.id());
+        }
+        header
+    }
+
+    /// Reads the header of an existing log, or writes a fresh one in the
+    /// `preferred` format when the log is empty.
+    ///
+    /// A file without a header is only taken for a legacy log when it
+    /// starts with an intact legacy record. Anything else, such as a file
+    /// that was never a log, or a legacy log whose first record is
+    /// damaged, is refused with `InvalidData`.
+    pub(crate) fn detect<F: Read + Write + Seek>(f: &mut F, preferred: Format) -> Result<Format> {
+        let len = f.seek(SeekFrom::End(0))?;
+        if len == 0 {
+            f.write_all(&preferred.header())?;
+            return Ok(preferred);
+        }
+
+        f.seek(SeekFrom::Start(0))?;
+        let mut header = [0; 6];
+        let mut read = 0;
+        while read < header.len() {
+            match f.read(&mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        f.seek(SeekFrom::Start(0))?;
+
+        if read < 4 || &header[..3] != MAGIC {
+            return Format::legacy(f);
+        }
+
+        let version = header[3];
+        if version == 0 || version > Format::LATEST_VERSION {
+            return Err(Error::BadFormat(format!(
+                "unsupported format version {}",
+                version
+            )));
+        }
+
+        let expected = Format {
+            version,
+            ..Format::LEGACY
+        }
+        .header()
+        .len();
+        if read < expected {
+            return Err(Error::BadFormat("truncated header".to_string()));
+        }
+
+        let endianness = if version >= 2 {
+            match header[4] {
+                0 => Endianness::Little,
+                1 => Endianness::Big,
+                byte => return Err(Error::BadFormat(format!("unknown byte order {}", byte))),
+            }
+        } else {
+            Endianness::Little
+        };
+
+        let checksum = if version >= 3 {
+            ChecksumAlgorithm::from_id(header[5]).ok_or_else(|| {
+                Error::BadFormat(format!("unknown checksum algorithm {}", header[5]))
+            })?
+        } else {
+            ChecksumAlgorithm::Crc32
+        };
+
+        Ok(Format {
+            version,
+            endianness,
+            checksum,
+        })
+    }
+
+    /// Checks that the headerless file `f` starts with an intact legacy
+    /// record, leaving its cursor at the start.
+    fn legacy<F: Read + Seek>(f: &mut F) -> Result<Format> {
+        let first = crate::process_record(f, Format::LEGACY, 0, true);
+        f.seek(SeekFrom::Start(0))?;
+        match first {
+            Ok(_) => Ok(Format::LEGACY),
+            Err(err) if err.is_damage() => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an actionkv log: it has no header and doesn't start with a record",
+            )
+            .into()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn read_u32<R: Read>(&self, r: &mut R) -> io::Result<u32> {
+        match self.endianness {
+            Endianness::Little => r.read_u32::<LittleEndian>(),
+            Endianness::Big => r.read_u32::<BigEndian>(),
+        }
+    }
+
+    pub(crate) fn write_u32<W: Write>(&self, w: &mut W, n: u32) -> io::Result<()> {
+        match self.endianness {
+            Endianness::Little => w.write_u32::<LittleEndian>(n),
+            Endianness::Big => w.write_u32::<BigEndian>(n),
+        }
+    }
+
+    pub(crate) fn read_u64<R: Read>(&self, r: &mut R) -> io::Result<u64> {
+        match self.endianness {
+            Endianness::Little => r.read_u64::<LittleEndian>(),
+            Endianness::Big => r.read_u64::<BigEndian>(),
+        }
+    }
+
+    pub(crate) fn write_u64<W: Write>(&self, w: &mut W, n: u64) -> io::Result<()> {
+        match self.endianness {
+            Endianness::Little => w.write_u64::<LittleEndian>(n),
+            Endianness::Big => w.write_u64::<BigEndian>(n),
+        }
+    }
+
+    /// Reads a key or value length, which is as wide as the format allows.
+    pub(crate) fn read_len<R: Read>(&self, r: &mut R) -> io::Result<u64> {
+        if self.has_wide_lengths() {
+            self.read_u64(r)
+        } else {
+            self.read_u32(r).map(u64::from)
+        }
+    }
+
+    /// Writes a key or value length, failing with `InvalidInput` when it is
+    /// longer than the format can record.
+    pub(crate) fn write_len<W: Write>(&self, w: &mut W, len: u64) -> io::Result<()> {
+        if len > self.max_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "length {} exceeds the format version {} limit of {} bytes",
+                    len,
+                    self.version,
+                    self.max_len()
+                ),
+            ));
+        }
+
+        if self.has_wide_lengths() {
+            self.write_u64(w, len)
+        } else {
+            self.write_u32(w, len as u32)
+        }
+    }
+
+    /// Reads a record checksum, which is as wide as the log's algorithm.
+    pub(crate) fn read_checksum<R: Read>(&self, r: &mut R) -> io::Result<u64> {
+        match self.checksum.width() {
+            4 => self.read_u32(r).map(u64::from),
+            _ => self.read_u64(r),
+        }
+    }
+
+    pub(crate) fn write_checksum<W: Write>(&self, w: &mut W, checksum: u64) -> io::Result<()> {
+        match self.checksum.width() {
+            4 => self.write_u32(w, checksum as u32),
+            _ => self.write_u64(w, checksum),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn detect_writes_header_to_empty_log() {
+        let mut f = Cursor::new(Vec::new());
+        assert_eq!(
+            Format::detect(&mut f, Format::CURRENT).unwrap(),
+            Format::CURRENT
+        );
+        assert_eq!(f.into_inner(), b"AKV\x05\x00\x00");
+
+        let big = Format::new(Endianness::Big, ChecksumAlgorithm::XxHash64);
+        let mut f = Cursor::new(Vec::new());
+        assert_eq!(Format::detect(&mut f, big).unwrap(), big);
+        assert_eq!(f.into_inner(), b"AKV\x05\x01\x02");
+    }
+
+    #[test]
+    fn detect_reads_existing_header() {
+        let mut f = Cursor::new(b"AKV\x01\x07rest".to_vec());
+        assert_eq!(Format::detect(&mut f, Format::CURRENT).unwrap(), Format::V1);
+
+        let mut f = Cursor::new(b"AKV\x02\x01rest".to_vec());
+        let v2 = Format::detect(&mut f, Format::CURRENT).unwrap();
+        assert_eq!(v2.version, 2);
+        assert_eq!(v2.endianness, Endianness::Big);
+        assert_eq!(v2.checksum, ChecksumAlgorithm::Crc32);
+
+        let mut f = Cursor::new(b"AKV\x03\x00\x01rest".to_vec());
+        let v3 = Format::detect(&mut f, Format::CURRENT).unwrap();
+        assert_eq!(v3.version, 3);
+        assert_eq!(v3.checksum, ChecksumAlgorithm::Crc32c);
+        assert!(!v3.has_wide_lengths());
+
+        let mut f = Cursor::new(b"AKV\x04\x00\x01rest".to_vec());
+        let v4 = Format::detect(&mut f, Format::CURRENT).unwrap();
+        assert!(v4.has_wide_lengths());
+        assert!(!v4.has_sequence());
+
+        let mut f = Cursor::new(b"AKV\x05\x00\x01rest".to_vec());
+        assert_eq!(
+            Format::detect(&mut f, Format::CURRENT).unwrap(),
+            Format::new(Endianness::Little, ChecksumAlgorithm::Crc32c)
+        );
+    }
+
+    #[test]
+    fn detect_treats_headerless_log_as_legacy() {
+        let mut log = Vec::new();
+        crate::write_record(
+            &mut log,
+            Format::LEGACY,
+            b"a",
+            b"1",
+            0,
+            0,
+            Default::default(),
+        )
+        .unwrap();
+        let mut f = Cursor::new(log);
+        assert_eq!(
+            Format::detect(&mut f, Format::CURRENT).unwrap(),
+            Format::LEGACY
+        );
+        assert_eq!(f.position(), 0);
+    }
+
+    #[test]
+    fn detect_refuses_files_that_are_not_logs() {
+        for bytes in [
+            &b"not a log, just some text\n"[..],
+            b"AK",
+            &[0x12, 0x34, 0x56, 0x78, 0, 0, 0, 9],
+        ] {
+            let mut f = Cursor::new(bytes.to_vec());
+            match Format::detect(&mut f, Format::CURRENT).unwrap_err() {
+                Error::Io(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+                err => panic!("expected InvalidData, got {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn detect_rejects_unknown_version() {
+        let mut f = Cursor::new(b"AKV\x7f".to_vec());
+        let err = Format::detect(&mut f, Format::CURRENT).unwrap_err();
+        assert!(matches!(err, Error::BadFormat(_)));
+
+        for header in [&b"AKV\x02\x09"[..], b"AKV\x03\x00\x09", b"AKV\x03\x00"] {
+            let mut f = Cursor::new(header.to_vec());
+            let err = Format::detect(&mut f, Format::CURRENT).unwrap_err();
+            assert!(matches!(err, Error::BadFormat(_)));
+        }
+    }
+
+    #[test]
+    fn integers_follow_the_byte_order() {
+        let mut buf = Vec::new();
+        Format::new(Endianness::Big, ChecksumAlgorithm::Crc32)
+            .write_u32(&mut buf, 1)
+            .unwrap();
+        Format::CURRENT.write_u32(&mut buf, 1).unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn lengths_at_the_u32_boundary() {
+        let v3 = Format {
+            version: 3,
+            ..Format::CURRENT
+        };
+        let limit = u32::MAX as u64;
+
+        let mut buf = Vec::new();
+        v3.write_len(&mut buf, limit).unwrap();
+        assert_eq!(buf, [0xff; 4]);
+        assert_eq!(v3.read_len(&mut &buf[..]).unwrap(), u32::MAX as u64);
+
+        let err = v3.write_len(&mut Vec::new(), limit + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut buf = Vec::new();
+        Format::CURRENT.write_len(&mut buf, limit + 1).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(
+            Format::CURRENT.read_len(&mut &buf[..]).unwrap(),
+            u32::MAX as u64 + 1
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! The in-memory index from each live key to the offset of its record.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use crate::options::Options;
+use crate::{ByteStr, ByteString};
+
+/// How the index of a store is organised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// A hash map: the fastest point lookups, keys in no particular order.
+    #[default]
+    Hash,
+    /// A B-tree: keys kept sorted, which
+    /// [`range`](crate::ActionKV::range) needs to avoid sorting on every
+    /// call.
+    Sorted,
+    /// A hash map from a 64-bit hash of each key, rather than the key
+    /// itself, which bounds the index at a few dozen bytes a key however
+    /// long the keys are. The log still stores every key in full.
+    ///
+    /// Two keys whose hashes collide are told apart by a second, independent
+    /// 64-bit hash, and the later one is kept in full. Lookups compare the
+    /// key of the record they read with the one asked for, so even keys
+    /// that collide in both hashes never return each other's values.
+    ///
+    /// The index has no keys to hand out, so [`keys`](crate::ActionKV::keys),
+    /// [`range`](crate::ActionKV::range) and
+    /// [`scan_prefix`](crate::ActionKV::scan_prefix) find nothing, and
+    /// [`save_index`](crate::ActionKV::save_index),
+    /// [`validate_index`](crate::ActionKV::validate_index),
+    /// [`filter_keys`](crate::ActionKV::filter_keys),
+    /// [`delete_prefix`](crate::ActionKV::delete_prefix) and
+    /// [`export_jsonl`](crate::ActionKV::export_jsonl) fail with
+    /// `Unsupported`. Compaction and merging write no hint files.
+    /// [`iter`](crate::ActionKV::iter) reads the keys from the log.
+    HashedKeys,
+}
+
+#[derive(Debug)]
+pub(crate) enum Index {
+    Hash(HashMap<ByteString, u64>),
+    Sorted(BTreeMap<ByteString, u64>),
+    HashedKeys(HashedKeys),
+    /// No index, for stores opened with
+    /// [`Options::build_index`](crate::Options) off. Every key is missing
+    /// and insertions are dropped.
+    Disabled,
+}
+
+/// The index of [`IndexKind::HashedKeys`].
+#[derive(Debug, Default)]
+pub(crate) struct HashedKeys {
+    /// By the first hash of each key, its second hash and position.
+    slots: HashMap<u64, (u64, u64)>,
+    /// Keys whose first hash was taken by another key when they were
+    /// inserted, in full.
+    overflow: HashMap<ByteString, u64>,
+}
+
+impl HashedKeys {
+    fn hashes(key: &ByteStr) -> (u64, u64) {
+        (
+            twox_hash::XxHash64::oneshot(0, key),
+            twox_hash::XxHash64::oneshot(1, key),
+        )
+    }
+
+    fn get(&self, key: &ByteStr) -> Option<&u64> {
+        if let Some(position) = self.overflow.get(key) {
+            return Some(position);
+        }
+        let (first, second) = HashedKeys::hashes(key);
+        match self.slots.get(&first) {
+            Some((check, position)) if *check == second => Some(position),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: ByteString, position: u64) -> Option<u64> {
+        if let Some(old) = self.overflow.get_mut(&key) {
+            return Some(std::mem::replace(old, position));
+        }
+        let (first, second) = HashedKeys::hashes(&key);
+        match self.slots.entry(first) {
+            Entry::Vacant(entry) => {
+                entry.insert((second, position));
+                None
+            }
+            Entry::Occupied(mut entry) if entry.get().0 == second => {
+                Some(std::mem::replace(&mut entry.get_mut().1, position))
+            }
+            Entry::Occupied(_) => self.overflow.insert(key, position),
+        }
+    }
+
+    fn remove(&mut self, key: &ByteStr) -> Option<u64> {
+        if let Some(position) = self.overflow.remove(key) {
+            return Some(position);
+        }
+        let (first, second) = HashedKeys::hashes(key);
+        match self.slots.entry(first) {
+            Entry::Occupied(entry) if entry.get().0 == second => Some(entry.remove().1),
+            _ => None,
+        }
+    }
+}
+
+impl Index {
+    pub(crate) fn new(kind: IndexKind) -> Index {
+        Index::with_capacity(kind, 0)
+    }
+
+    /// The empty index `options` ask for: none at all with
+    /// [`Options::build_index`] off.
+    pub(crate) fn for_options(options: &Options) -> Index {
+        if options.build_index {
+            Index::with_capacity(options.index, options.index_capacity)
+        } else {
+            Index::Disabled
+        }
+    }
+
+    /// An empty index of `kind` with room for `capacity` keys, if it is a
+    /// kind that can reserve room.
+    pub(crate) fn with_capacity(kind: IndexKind, capacity: usize) -> Index {
+        match kind {
+            IndexKind::Hash => Index::Hash(HashMap::with_capacity(capacity)),
+            IndexKind::Sorted => Index::Sorted(BTreeMap::new()),
+            IndexKind::HashedKeys => Index::HashedKeys(HashedKeys {
+                slots: HashMap::with_capacity(capacity),
+                overflow: HashMap::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn from_entries<I>(kind: IndexKind, entries: I) -> Index
+    where
+        I: IntoIterator<Item = (ByteString, u64)>,
+    {
+        match kind {
+            IndexKind::Hash => Index::Hash(entries.into_iter().collect()),
+            IndexKind::Sorted => Index::Sorted(entries.into_iter().collect()),
+            IndexKind::HashedKeys => {
+                let mut index = Index::new(kind);
+                for (key, position) in entries {
+                    index.insert(key, position);
+                }
+                index
+            }
+        }
+    }
+
+    /// The kind of index this is. A disabled index reports the default
+    /// kind.
+    pub(crate) fn kind(&self) -> IndexKind {
+        match self {
+            Index::Hash(_) | Index::Disabled => IndexKind::Hash,
+            Index::Sorted(_) => IndexKind::Sorted,
+            Index::HashedKeys(_) => IndexKind::HashedKeys,
+        }
+    }
+
+    pub(crate) fn get(&self, key: &ByteStr) -> Option<&u64> {
+        match self {
+            Index::Hash(map) => map.get(key),
+            Index::Sorted(map) => map.get(key),
+            Index::HashedKeys(index) => index.get(key),
+            Index::Disabled => None,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: ByteString, position: u64) -> Option<u64> {
+        match self {
+            Index::Hash(map) => map.insert(key, position),
+            Index::Sorted(map) => map.insert(key, position),
+            Index::HashedKeys(index) => index.insert(key, position),
+            Index::Disabled => None,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &ByteStr) -> Option<u64> {
+        match self {
+            Index::Hash(map) => map.remove(key),
+            Index::Sorted(map) => map.remove(key),
+            Index::HashedKeys(index) => index.remove(key),
+            Index::Disabled => None,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        match self {
+            Index::Hash(map) => map.clear(),
+            Index::Sorted(map) => map.clear(),
+            Index::HashedKeys(index) => {
+                index.slots.clear();
+                index.overflow.clear();
+            }
+            Index::Disabled => {}
+        }
+    }
+
+    /// Gives back whatever room the index has beyond its keys. A sorted
+    /// index never has any.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        match self {
+            Index::Hash(map) => map.shrink_to_fit(),
+            Index::HashedKeys(index) => {
+                index.slots.shrink_to_fit();
+                index.overflow.shrink_to_fit();
+            }
+            Index::Sorted(_) | Index::Disabled => {}
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Index::Hash(map) => map.len(),
+            Index::Sorted(map) => map.len(),
+            Index::HashedKeys(index) => index.slots.len() + index.overflow.len(),
+            Index::Disabled => 0,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the index holds its keys, which
+    /// [`IndexKind::HashedKeys`] doesn't.
+    pub(crate) fn has_keys(&self) -> bool {
+        !matches!(self, Index::HashedKeys(_))
+    }
+
+    /// Every key and its position. An index that doesn't hold its keys,
+    /// as [`has_keys`](Index::has_keys) tells, has none to yield.
+    pub(crate) fn iter(&self) -> Box<dyn ExactSizeIterator<Item = (&ByteString, &u64)> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.iter()),
+            Index::Sorted(map) => Box::new(map.iter()),
+            Index::HashedKeys(_) | Index::Disabled => Box::new(std::iter::empty()),
+        }
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &ByteString> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub(crate) fn values(&self) -> Box<dyn Iterator<Item = &u64> + '_> {
+        match self {
+            Index::HashedKeys(index) => Box::new(
+                index
+                    .slots
+                    .values()
+                    .map(|(_, position)| position)
+                    .chain(index.overflow.values()),
+            ),
+            index => Box::new(index.iter().map(|(_, position)| position)),
+        }
+    }
+
+    /// Entries with keys in `[start, end)`, in key order.
+    ///
+    /// A hash index has to collect and sort the matching entries first.
+    pub(crate) fn range<'a>(
+        &'a self,
+        start: &ByteStr,
+        end: &ByteStr,
+    ) -> Box<dyn Iterator<Item = (&'a ByteString, u64)> + 'a> {
+        if start >= end {
+            return Box::new(std::iter::empty());
+        }
+
+        match self {
+            Index::Hash(map) => {
+                let mut entries: Vec<_> = map
+                    .iter()
+                    .filter(|(key, _)| start <= key.as_slice() && key.as_slice() < end)
+                    .map(|(key, position)| (key, *position))
+                    .collect();
+                entries.sort_unstable();
+                Box::new(entries.into_iter())
+            }
+            Index::Sorted(map) => Box::new(
+                map.range::<ByteStr, _>((Bound::Included(start), Bound::Excluded(end)))
+                    .map(|(key, position)| (key, *position)),
+            ),
+            Index::HashedKeys(_) | Index::Disabled => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Entries whose keys start with `prefix`, in key order.
+    ///
+    /// A sorted index stops at the first key past the prefix. A hash index
+    /// has to collect and sort the matching entries first.
+    pub(crate) fn prefix<'a>(
+        &'a self,
+        prefix: &'a ByteStr,
+    ) -> Box<dyn Iterator<Item = (&'a ByteString, u64)> + 'a> {
+        match self {
+            Index::Hash(map) => {
+                let mut entries: Vec<_> = map
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, position)| (key, *position))
+                    .collect();
+                entries.sort_unstable();
+                Box::new(entries.into_iter())
+            }
+            Index::Sorted(map) => Box::new(
+                map.range::<ByteStr, _>((Bound::Included(prefix), Bound::Unbounded))
+                    .take_while(move |(key, _)| key.starts_with(prefix))
+                    .map(|(key, position)| (key, *position)),
+            ),
+            Index::HashedKeys(_) | Index::Disabled => Box::new(std::iter::empty()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(kind: IndexKind) -> Index {
+        let keys: [&[u8]; 5] = [b"b", b"d", b"a", b"c", b"e"];
+        Index::from_entries(
+            kind,
+            keys.iter()
+                .enumerate()
+                .map(|(i, key)| (key.to_vec(), i as u64)),
+        )
+    }
+
+    #[test]
+    fn range_is_sorted_and_half_open_in_both_kinds() {
+        for kind in [IndexKind::Hash, IndexKind::Sorted] {
+            let index = index(kind);
+            let keys: Vec<&[u8]> = index
+                .range(b"b", b"e")
+                .map(|(key, _)| key.as_slice())
+                .collect();
+            assert_eq!(keys, [&b"b"[..], b"c", b"d"]);
+            assert_eq!(index.range(b"d", b"b").count(), 0);
+            assert_eq!(index.range(b"c", b"c").count(), 0);
+        }
+    }
+
+    #[test]
+    fn hashed_keys_keep_a_colliding_key_in_full() {
+        let mut index = HashedKeys::default();
+        // Another key with the same first hash as `b`.
+        let (first, _) = HashedKeys::hashes(b"b");
+        index.slots.insert(first, (0xdead, 7));
+
+        assert_eq!(index.insert(b"b".to_vec(), 1), None);
+        assert_eq!(index.insert(b"b".to_vec(), 2), Some(1));
+        assert_eq!(index.get(b"b"), Some(&2));
+        assert_eq!(index.overflow.len(), 1);
+        assert_eq!(index.remove(b"b"), Some(2));
+        assert_eq!(index.get(b"b"), None);
+        assert_eq!(index.slots[&first], (0xdead, 7));
+    }
+
+    #[test]
+    fn prefix_matches_only_keys_that_start_with_it() {
+        for kind in [IndexKind::Hash, IndexKind::Sorted] {
+            let keys: [&[u8]; 6] = [b"abd", b"ab", b"b", b"abc", b"a", b"abcd"];
+            let index = Index::from_entries(kind, keys.iter().map(|key| (key.to_vec(), 0)));
+
+            let matching = |prefix: &[u8]| -> Vec<ByteString> {
+                index.prefix(prefix).map(|(key, _)| key.clone()).collect()
+            };
+            assert_eq!(matching(b"ab"), [&b"ab"[..], b"abc", b"abcd", b"abd"]);
+            assert_eq!(matching(b"abc"), [&b"abc"[..], b"abcd"]);
+            assert_eq!(matching(b"").len(), keys.len());
+            assert!(matching(b"c").is_empty());
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! A portable dump format: JSON lines of base64 encoded pairs.
+//!
+//! Each line is one object, `{"key":"…","value":"…"}`, with both fields in
+//! standard base64 with padding.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{ActionKV, Error, Result, Storage};
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    key: String,
+    value: String,
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Writes every live pair to `w` as JSON lines, in key order.
+    ///
+    /// An index of hashed keys has no keys to put in order, so it fails
+    /// with `Unsupported` before anything is written.
+    pub fn export_jsonl<W: Write>(&self, w: &mut W) -> Result<()> {
+        if !self.index.has_keys() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an index of hashed keys can't be exported in key order",
+            )
+            .into());
+        }
+        for kv in self.scan_prefix(b"") {
+            let kv = kv?;
+            let line = Line {
+                key: STANDARD.encode(&kv.key),
+                value: STANDARD.encode(&kv.value),
+            };
+            serde_json::to_writer(&mut *w, &line).map_err(io::Error::from)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(w.flush()?)
+    }
+
+    /// Inserts every pair of a JSON lines dump read from `r`, returning how
+    /// many were inserted. Blank lines are skipped.
+    ///
+    /// A malformed line fails with an `InvalidData` error naming it, after
+    /// the pairs before it have been inserted.
+    pub fn import_jsonl<R: Read>(&mut self, r: &mut R) -> Result<usize> {
+        let mut count = 0;
+        for (n, line) in BufReader::new(r).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, value) = parse(&line).map_err(|msg| {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", n + 1, msg),
+                ))
+            })?;
+            self.insert(&key, &value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+fn parse(line: &str) -> std::result::Result<(Vec<u8>, Vec<u8>), String> {
+    let line: Line = serde_json::from_str(line).map_err(|err| err.to_string())?;
+    let key = STANDARD
+        .decode(line.key)
+        .map_err(|err| format!("key: {}", err))?;
+    let value = STANDARD
+        .decode(line.value)
+        .map_err(|err| format!("value: {}", err))?;
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn empty() -> ActionKV<Cursor<Vec<u8>>> {
+        ActionKV::from_store(Cursor::new(Vec::new())).unwrap()
+    }
+
+    #[test]
+    fn export_then_import_round_trips() {
+        let mut store = empty();
+        store.insert(b"b", b"\x00\xff binary").unwrap();
+        store.insert(b"a", b"").unwrap();
+        store.insert(b"gone", b"x").unwrap();
+        store.delete(b"gone").unwrap();
+
+        let mut dump = Vec::new();
+        store.export_jsonl(&mut dump).unwrap();
+        assert_eq!(
+            String::from_utf8(dump.clone()).unwrap(),
+            "{\"key\":\"YQ==\",\"value\":\"\"}\n{\"key\":\"Yg==\",\"value\":\"AP8gYmluYXJ5\"}\n"
+        );
+
+        let mut copy = empty();
+        assert_eq!(copy.import_jsonl(&mut &dump[..]).unwrap(), 2);
+        for key in [&b"a"[..], b"b", b"gone"] {
+            assert_eq!(copy.get(key).unwrap(), store.get(key).unwrap());
+        }
+    }
+
+    #[test]
+    fn import_names_the_malformed_line() {
+        let mut store = empty();
+        let dump = "{\"key\":\"YQ==\",\"value\":\"MQ==\"}\n\n{\"key\":\"!\",\"value\":\"\"}\n";
+
+        let err = store.import_jsonl(&mut dump.as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("line 3: key:"));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+}
@@ -0,0 +1,5908 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::iter;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::ReadBytesExt;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::checksum::Checksum;
+use crate::format::{
+    Format, FLAG_COMPRESSED, FLAG_ENCRYPTED, FLAG_EXPIRES, FLAG_FAMILY, FLAG_PADDING,
+    FLAG_TOMBSTONE, FLAG_WRITTEN_AT, KNOWN_FLAGS,
+};
+use crate::index::Index;
+use crate::read_at::ReadAt;
+
+#[cfg(feature = "async")]
+pub use crate::asynchronous::AsyncActionKV;
+pub use crate::builder::ActionKVBuilder;
+pub use crate::checksum::ChecksumAlgorithm;
+#[cfg(feature = "compression")]
+pub use crate::compression::Compression;
+#[cfg(feature = "encryption")]
+pub use crate::encryption::EncryptionKey;
+pub use crate::error::{Error, Result};
+pub use crate::family::ColumnFamily;
+pub use crate::format::Endianness;
+pub use crate::index::IndexKind;
+pub use crate::merge::ConflictPolicy;
+#[cfg(feature = "mmap")]
+pub use crate::mmap::MmapFile;
+pub use crate::options::{CorruptionPolicy, Options, SyncPolicy};
+pub use crate::segment::MergeReport;
+pub use crate::shared::SharedActionKV;
+pub use crate::stats::{CompactionEstimate, Stats};
+pub use crate::storage::Storage;
+pub use crate::stream::ValueReader;
+pub use crate::transaction::Transaction;
+pub use crate::typed::TypedActionKV;
+pub use crate::verify::VerifyReport;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod builder;
+mod checksum;
+mod compression;
+mod encryption;
+mod error;
+mod family;
+mod format;
+mod index;
+mod jsonl;
+mod merge;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod options;
+mod read_at;
+mod segment;
+mod shared;
+mod sidecar;
+mod stats;
+mod storage;
+mod stream;
+mod transaction;
+mod typed;
+mod verify;
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// Upper bound on the buffer reserved for a record before its data is read.
+const MAX_PREALLOCATION: u64 = 1 << 20;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyValuePair {
+    pub key: ByteString,
+    pub value: ByteString,
+}
+
+/// One record of the log as [`read_from`](ActionKV::read_from) yields it,
+/// with everything a follower needs to replay the write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub key: ByteString,
+    /// Empty when the record is a deletion.
+    pub value: ByteString,
+    pub deleted: bool,
+    /// See [`ActionKV::sequence`]. 0 in logs without sequence numbers.
+    pub sequence: u64,
+    /// Unix time in milliseconds at which the value expires.
+    pub expires_at: Option<u64>,
+}
+
+/// Where a value lives in the log and how it is stored there, from
+/// [`ActionKV::get_with_metadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// Position of the record, as [`ActionKV::insert_at`] returns it.
+    pub offset: u64,
+    /// The checksum saved with the record. 32-bit checksums are
+    /// zero-extended.
+    pub checksum: u64,
+    pub key_len: u64,
+    /// Length of the value as stored, which for a compressed or encrypted
+    /// value isn't the length of the value returned with it.
+    pub value_len: u64,
+}
+
+/// A decoded record, including the metadata that [`KeyValuePair`] leaves out.
+#[derive(Debug)]
+struct Record {
+    key: ByteString,
+    value: ByteString,
+    /// The checksum saved with the record.
+    checksum: u64,
+    tombstone: bool,
+    /// Whether the record is only there to align the next one.
+    padding: bool,
+    /// Position of the write in the log's history, or 0 in formats without
+    /// sequence numbers.
+    sequence: u64,
+    /// Unix time in milliseconds after which the record is gone, or 0 if it
+    /// never expires.
+    expires_at: u64,
+    /// Column family of the record, 0 for the default one.
+    family: u32,
+    /// Unix time in milliseconds when the record was written, or 0 if it
+    /// wasn't recorded.
+    written_at: u64,
+    /// The [`FLAG_COMPRESSED`] and [`FLAG_ENCRYPTED`] bits of the record,
+    /// which say how `value` is stored until
+    /// [`decode`](ActionKV::decode) has undone them.
+    encoding: u8,
+}
+
+impl Record {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    fn extras(&self) -> Extras {
+        Extras {
+            expires_at: self.expires_at,
+            family: self.family,
+            written_at: self.written_at,
+        }
+    }
+
+    fn times(&self) -> Times {
+        Times {
+            written_at: self.written_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// One record of a batch for [`ActionKV::append_batch`].
+struct BatchRecord<'a> {
+    key: &'a ByteStr,
+    value: &'a ByteStr,
+    flags: u8,
+    extras: Extras,
+}
+
+impl<'a> BatchRecord<'a> {
+    fn new(key: &'a ByteStr, value: &'a ByteStr, flags: u8) -> Self {
+        BatchRecord {
+            key,
+            value,
+            flags,
+            extras: Extras::default(),
+        }
+    }
+}
+
+/// The optional fields of a record, each written only when it isn't 0.
+#[derive(Debug, Clone, Copy, Default)]
+struct Extras {
+    /// Sets [`FLAG_EXPIRES`].
+    expires_at: u64,
+    /// Sets [`FLAG_FAMILY`].
+    family: u32,
+    /// Sets [`FLAG_WRITTEN_AT`].
+    written_at: u64,
+}
+
+/// The fixed fields at the start of a record.
+struct RecordHeader {
+    key_len: u64,
+    val_len: u64,
+    flags: u8,
+}
+
+impl RecordHeader {
+    /// Length of the whole record in bytes.
+    fn record_len(&self, format: Format) -> u64 {
+        let expiry = if self.flags & FLAG_EXPIRES != 0 { 8 } else { 0 };
+        let family = if self.flags & FLAG_FAMILY != 0 { 4 } else { 0 };
+        let written_at = if self.flags & FLAG_WRITTEN_AT != 0 {
+            8
+        } else {
+            0
+        };
+        format.record_header_len() as u64
+            + expiry
+            + family
+            + written_at
+            + self.key_len
+            + self.val_len
+    }
+}
+
+/// Why a scan of a segment's records stopped.
+enum ScanStop {
+    /// It reached the end of the segment.
+    End,
+    /// The last record is torn, and failed with this error.
+    Torn(Error),
+    /// A record failed with this error, and [`CorruptionPolicy::Truncate`]
+    /// asks for the log to be cut off there.
+    Damaged(Error),
+    /// Only zeros are left, as [`Options::ignore_zeroed_tail`] describes.
+    Zeroed,
+}
+
+/// Whether `f` holds nothing but zeros from `offset` to `len`.
+fn zeroed_to<S: Storage>(f: &S, offset: u64, len: u64) -> io::Result<bool> {
+    let mut r = ReadAt::new(f, offset).take(len - offset);
+    let mut buf = [0; 8 * 1024];
+    loop {
+        match r.read(&mut buf)? {
+            0 => return Ok(true),
+            n if buf[..n].iter().any(|byte| *byte != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// A callback registered with [`ActionKV::on_write`].
+type Watcher = Box<dyn FnMut(&ByteStr, Option<&ByteStr>) + Send + Sync>;
+
+/// The callbacks of a store, which have nothing to show for `Debug` but how
+/// many there are.
+#[derive(Default)]
+struct Watchers(Vec<Watcher>);
+
+impl std::fmt::Debug for Watchers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} watchers", self.0.len())
+    }
+}
+
+/// The storage of the active segment, which is only ever missing once
+/// [`ActionKV::into_inner`] has taken it, leaving nothing for the store to
+/// flush when it is dropped.
+#[derive(Debug)]
+struct Active<S>(Option<S>);
+
+impl<S> Deref for Active<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        self.0
+            .as_ref()
+            .expect("the storage was taken by into_inner")
+    }
+}
+
+impl<S> DerefMut for Active<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        self.0
+            .as_mut()
+            .expect("the storage was taken by into_inner")
+    }
+}
+
+/// When a record was written and when it expires, as Unix times in
+/// milliseconds, with 0 for never.
+#[derive(Debug, Clone, Copy)]
+struct Times {
+    written_at: u64,
+    expires_at: u64,
+}
+
+/// How a store compacts its own log, which only stores of files can.
+type Compactor<S> = fn(&mut ActionKV<S>) -> Result<u64>;
+
+/// Running count of the record bytes of the log, kept for
+/// [`Options::auto_compact_ratio`] so writes don't need
+/// [`stats`](ActionKV::stats) to decide whether to compact.
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    live: u64,
+    dead: u64,
+}
+
+impl Usage {
+    fn dead_ratio(&self) -> f64 {
+        let records = self.live + self.dead;
+        if records == 0 {
+            return 0.0;
+        }
+
+        self.dead as f64 / records as f64
+    }
+}
+
+impl From<Record> for KeyValuePair {
+    fn from(record: Record) -> Self {
+        KeyValuePair {
+            key: record.key,
+            value: record.value,
+        }
+    }
+}
+
+impl From<Record> for LogEntry {
+    fn from(record: Record) -> Self {
+        LogEntry {
+            key: record.key,
+            value: record.value,
+            deleted: record.tombstone,
+            sequence: record.sequence,
+            expires_at: Some(record.expires_at).filter(|at| *at != 0),
+        }
+    }
+}
+
+/// An append-only key-value store backed by a single log.
+///
+/// The log is a file unless the store was built with
+/// [`from_store`](ActionKV::from_store) over some other [`Storage`].
+///
+/// Writes made through [`insert`](ActionKV::insert),
+/// [`update`](ActionKV::update) and [`delete`](ActionKV::delete) are not
+/// durable when they return. Call [`flush`](ActionKV::flush) to hand buffered
+/// bytes to the operating system, and [`sync`](ActionKV::sync) to force them
+/// onto the disk, or set [`Options::sync_policy`] to have the store sync on
+/// its own.
+/// Dropping a store flushes it too, but can only ignore a failure.
+///
+/// Logs created by this version start with a small header recording the
+/// format version. Headerless logs written before the header existed still
+/// open and load, but [`delete`](ActionKV::delete) can only write an empty
+/// value to them.
+///
+/// With [`Options::max_segment_bytes`] set, the log is split across segment
+/// files that fill up one after another, and record positions name the
+/// segment as well as the offset within it.
+///
+/// A handle holds an advisory lock on its log until it is dropped: an
+/// exclusive one from [`open`](ActionKV::open), or a shared one from
+/// [`open_shared`](ActionKV::open_shared). Opening a log whose lock is held
+/// in a conflicting mode fails with `WouldBlock` instead of waiting. Stores
+/// opened with [`open_read_only`](ActionKV::open_read_only) or built with
+/// [`from_store`](ActionKV::from_store) take no lock.
+///
+/// A store is `Send` and `Sync` whenever its storage is, as a [`File`] and
+/// a `Cursor<Vec<u8>>` are, so it can move to another thread, and
+/// lookups, which take `&self` and read with positioned I/O, can run from
+/// several threads at once. Writes take `&mut self`, so sharing a store
+/// that is written to needs a lock around it; [`SharedActionKV`] is one.
+#[derive(Debug)]
+pub struct ActionKV<S: Storage = File> {
+    f: Active<S>,
+    path: Option<PathBuf>,
+    format: Format,
+    options: Options,
+    /// Set by the read-only constructors. Writes fail with
+    /// `PermissionDenied`.
+    read_only: bool,
+    discarded: u64,
+    /// Bytes of damage the scans since the last load passed over under
+    /// [`CorruptionPolicy::Skip`].
+    skipped: u64,
+    /// Position where the last scan of the log stopped, which
+    /// [`refresh`](ActionKV::refresh) picks up from.
+    scanned: u64,
+    sequence: u64,
+    /// Full segments before the active one in `f`, in order, with their
+    /// numbers.
+    sealed: Vec<(u32, S)>,
+    /// Number of the segment in `f`.
+    segment: u32,
+    /// Writes not yet synced under [`SyncPolicy::Batched`].
+    unsynced: usize,
+    last_sync: Instant,
+    /// Index of the default column family.
+    index: Index,
+    /// Indexes of the other column families, by id, including the catalog
+    /// of their names.
+    families: HashMap<u32, Index>,
+    /// Compacts the log once [`Options::auto_compact_ratio`] is crossed.
+    /// Only set for stores opened by path with the ratio set.
+    compactor: Option<Compactor<S>>,
+    /// Live and dead bytes for the compactor, or `None` while they aren't
+    /// known, which holds it back.
+    usage: Option<Usage>,
+    /// Times of the indexed records that carry a write time, by position,
+    /// for [`modified_since`](ActionKV::modified_since). Records indexed
+    /// from a hint or a saved index are missing.
+    write_times: HashMap<u64, Times>,
+    /// Called after every write to the default column family.
+    watchers: Watchers,
+}
+
+impl ActionKV {
+    /// Opens the store at `path` with every setting at its default,
+    /// creating it if it doesn't exist yet. [`builder`](ActionKV::builder)
+    /// opens one with others.
+    pub fn open(path: &Path) -> Result<Self> {
+        ActionKV::open_with_options(path, Options::default())
+    }
+
+    /// Opens the store at `path`, creating it with the given `options` if
+    /// it doesn't exist yet.
+    pub fn open_with_options(path: &Path, options: Options) -> Result<Self> {
+        let f = ActionKV::open_file(path)?;
+        ActionKV::open_locked(f, path, options)
+    }
+
+    /// [`open`](ActionKV::open), except that a log that doesn't exist is a
+    /// `NotFound` error rather than a new store, so a mistyped path isn't
+    /// mistaken for an empty one.
+    pub fn open_existing(path: &Path) -> Result<Self> {
+        let f = OpenOptions::new().read(true).write(true).open(path)?;
+        ActionKV::open_locked(f, path, Options::default())
+    }
+
+    /// Creates a store at `path` holding `pairs`, which are appended as one
+    /// [`insert_batch`](ActionKV::insert_batch) and indexed on the way, so
+    /// the new store needs no [`load`](ActionKV::load). Where a key comes up
+    /// more than once, the last pair wins.
+    ///
+    /// A log that already exists at `path` is an `AlreadyExists` error. The
+    /// pairs are collected before anything is written, so they have to fit
+    /// in memory.
+    pub fn create_from<I>(path: &Path, pairs: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (ByteString, ByteString)>,
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        let mut store = ActionKV::open_locked(f, path, Options::default())?;
+        let pairs: Vec<_> = pairs.into_iter().collect();
+        let records: Vec<_> = pairs
+            .iter()
+            .map(|(key, value)| BatchRecord::new(key, value, 0))
+            .collect();
+        store.append_batch(&records)?;
+        Ok(store)
+    }
+
+    /// Locks the log in `f`, which was opened from `path`, and opens the
+    /// store over it.
+    fn open_locked(mut f: File, path: &Path, options: Options) -> Result<Self> {
+        lock(&f, path, false)?;
+        let format = Format::detect(&mut f, Format::new(options.endianness, options.checksum))?;
+        let index = Index::for_options(&options);
+        let mut store = ActionKV {
+            f: Active(Some(f)),
+            path: Some(path.to_path_buf()),
+            format,
+            options,
+            read_only: false,
+            discarded: 0,
+            skipped: 0,
+            scanned: 0,
+            sequence: 0,
+            sealed: Vec::new(),
+            segment: 0,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            index,
+            families: HashMap::new(),
+            compactor: None,
+            usage: None,
+            write_times: HashMap::new(),
+            watchers: Watchers::default(),
+        };
+        if store.options.auto_compact_ratio.is_some() && store.options.build_index {
+            store.compactor = Some(ActionKV::compact_in_place);
+        }
+        store.open_segments(File::open_segment)?;
+        // With no records there is nothing to load before the count is
+        // right.
+        if store.sealed.is_empty() && store.f.byte_len()? <= store.format.data_start() {
+            store.recount()?;
+        }
+        Ok(store)
+    }
+
+    /// Opens the store at `path` with [`Options::recover_torn_tail`] set, so
+    /// [`load`](ActionKV::load) cuts off a record left half-written by a
+    /// crash instead of failing.
+    pub fn open_with_recovery(path: &Path) -> Result<Self> {
+        let options = Options {
+            recover_torn_tail: true,
+            ..Options::default()
+        };
+        ActionKV::open_with_options(path, options)
+    }
+
+    /// Opens the store at `path` with an index that has room for `capacity`
+    /// keys from the start, as [`Options::index_capacity`] describes.
+    pub fn open_with_index_capacity(path: &Path, capacity: usize) -> Result<Self> {
+        let options = Options {
+            index_capacity: capacity,
+            ..Options::default()
+        };
+        ActionKV::open_with_options(path, options)
+    }
+
+    /// Opens an existing store for reading under a shared lock, so any
+    /// number of readers can use the log at once while no writer can.
+    ///
+    /// The file is opened read-only, and every write through the handle,
+    /// including [`compact`](ActionKV::compact), fails with
+    /// `PermissionDenied`.
+    pub fn open_shared(path: &Path) -> Result<Self> {
+        ActionKV::open_read(path, true, Options::default())
+    }
+
+    /// Opens an existing store for reading without taking a lock, so it
+    /// works on read-only media and next to a writer that holds the log.
+    /// Writes fail with `PermissionDenied`, as with
+    /// [`open_shared`](ActionKV::open_shared).
+    ///
+    /// Nothing stops a writer appending meanwhile, so a load can end in the
+    /// record the writer is partway through, and fails with
+    /// [`Error::Truncated`]. Records appended after the load aren't indexed.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        ActionKV::open_read(path, false, Options::default())
+    }
+
+    fn open_read(path: &Path, shared_lock: bool, options: Options) -> Result<Self> {
+        let mut f = File::open(path)?;
+        if shared_lock {
+            lock(&f, path, true)?;
+        }
+        let format = if f.metadata()?.len() == 0 {
+            // Only a writer may write the header. There are no records to
+            // read either way.
+            Format::new(Endianness::default(), ChecksumAlgorithm::default())
+        } else {
+            Format::detect(&mut f, Format::LEGACY)?
+        };
+        let index = Index::for_options(&options);
+        let mut store = ActionKV {
+            f: Active(Some(f)),
+            path: Some(path.to_path_buf()),
+            format,
+            options,
+            read_only: true,
+            discarded: 0,
+            skipped: 0,
+            scanned: 0,
+            sequence: 0,
+            sealed: Vec::new(),
+            segment: 0,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            index,
+            families: HashMap::new(),
+            compactor: None,
+            usage: None,
+            write_times: HashMap::new(),
+            watchers: Watchers::default(),
+        };
+        store.open_segments(|path| File::open(path))?;
+        Ok(store)
+    }
+
+    pub(crate) fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Builds a store over `store`, which may already hold a log or be empty.
+    pub fn from_store(store: S) -> Result<Self> {
+        ActionKV::from_store_with_options(store, Options::default())
+    }
+
+    /// [`from_store`](ActionKV::from_store), creating the log with the given
+    /// `options` if `store` is empty.
+    pub fn from_store_with_options(mut store: S, options: Options) -> Result<Self> {
+        let format = Format::detect(
+            &mut store,
+            Format::new(options.endianness, options.checksum),
+        )?;
+        let index = Index::for_options(&options);
+        Ok(ActionKV {
+            f: Active(Some(store)),
+            path: None,
+            format,
+            options,
+            read_only: false,
+            discarded: 0,
+            skipped: 0,
+            scanned: 0,
+            sequence: 0,
+            sealed: Vec::new(),
+            segment: 0,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            index,
+            families: HashMap::new(),
+            compactor: None,
+            usage: None,
+            write_times: HashMap::new(),
+            watchers: Watchers::default(),
+        })
+    }
+
+    /// Unwraps the store, returning the storage of its active segment.
+    /// Pending writes are flushed first, on a best-effort basis, as when the
+    /// store is dropped.
+    pub fn into_inner(mut self) -> S {
+        let _ = self.flush();
+        self.f.0.take().expect("the storage is only taken here")
+    }
+
+    /// Opens the segments after the first with `open` and makes the last
+    /// one active.
+    fn open_segments(&mut self, open: impl Fn(&Path) -> io::Result<S>) -> Result<()> {
+        let base = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        for number in segment::existing(&base)? {
+            let mut f = open(&segment::path(&base, number))?;
+            if f.byte_len()? == 0 {
+                // Left by a crash while rolling over. Only a writer may
+                // write the header, and there are no records either way.
+                if !self.read_only {
+                    f.write_all(&self.format.header())?;
+                    f.flush()?;
+                }
+            } else if Format::detect(&mut f, self.format)? != self.format {
+                return Err(Error::BadFormat(format!(
+                    "segment {} isn't in the format of the first",
+                    number
+                )));
+            }
+
+            let previous = mem::replace(&mut *self.f, f);
+            self.sealed.push((self.segment, previous));
+            self.segment = number;
+        }
+        Ok(())
+    }
+
+    /// The storage holding `segment`.
+    fn segment_storage(&self, segment: u32) -> Result<&S> {
+        if segment == self.segment {
+            return Ok(&*self.f);
+        }
+
+        match self.sealed.iter().find(|(number, _)| *number == segment) {
+            Some((_, f)) => Ok(f),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the log has no segment {}", segment),
+            )
+            .into()),
+        }
+    }
+
+    /// Every segment in order, with its number.
+    fn segments(&self) -> impl Iterator<Item = (u32, &S)> {
+        self.sealed
+            .iter()
+            .map(|(number, f)| (*number, f))
+            .chain(iter::once((self.segment, &*self.f)))
+    }
+
+    /// Combined length of the segments.
+    fn log_bytes(&self) -> Result<u64> {
+        let mut len = 0;
+        for (_, f) in self.segments() {
+            len += f.byte_len()?;
+        }
+        Ok(len)
+    }
+
+    pub fn seek_to_end(&mut self) -> Result<u64> {
+        Ok(self.f.seek(SeekFrom::End(0))?)
+    }
+
+    /// Scans the log from the first record and indexes every record,
+    /// returning how many records were read. Deleted and expired keys are
+    /// left out of the index.
+    ///
+    /// Scanning stops at the first record that fails its checksum with
+    /// [`Error::Corrupt`]. The records before its offset stay indexed.
+    ///
+    /// A log whose last record is torn, because it is shorter than its header
+    /// promises or fails its checksum, is an [`Error::Truncated`] or
+    /// [`Error::Corrupt`] error too, unless the store was opened with
+    /// [`Options::recover_torn_tail`]. Then the log
+    /// is truncated to the end of the last good record and the number of bytes
+    /// cut off is reported by [`discarded_bytes`](ActionKV::discarded_bytes).
+    ///
+    /// [`Options::on_corruption`] can have damaged records skipped, or the
+    /// log cut off at the first one, instead, as [`CorruptionPolicy`]
+    /// describes.
+    ///
+    /// A segmented log is scanned one segment after another, and only the
+    /// active segment can have a torn tail; anywhere else it is damage.
+    ///
+    /// A successful load leaves the storage's cursor at the end of the log.
+    pub fn load(&mut self) -> Result<usize> {
+        self.load_segments(false)
+    }
+
+    /// [`load`](ActionKV::load), except that segments with a hint file are
+    /// indexed from it and only the records appended after it are read.
+    /// Each key a hint lists counts as one record.
+    ///
+    /// Hints are written by [`compact`](ActionKV::compact) and
+    /// [`merge_segments`](ActionKV::merge_segments). A hinted record isn't
+    /// read, so its damage goes unnoticed until a lookup reads it.
+    pub fn load_from_hints(&mut self) -> Result<usize> {
+        self.load_segments(true)
+    }
+
+    fn load_segments(&mut self, hints: bool) -> Result<usize> {
+        self.skipped = 0;
+        self.write_times.clear();
+        let segments: Vec<u32> = self.segments().map(|(number, _)| number).collect();
+        let mut records = 0;
+        for number in segments {
+            if hints {
+                if let Some(read) = self.load_hint(number)? {
+                    records += read;
+                    continue;
+                }
+            }
+            records += self.scan_from(segment::position(number, self.format.data_start()))?;
+        }
+        self.recount()?;
+        Ok(records)
+    }
+
+    /// Indexes every record from `start` to the end of its segment.
+    fn scan_from(&mut self, start: u64) -> Result<usize> {
+        let number = segment::split(start).0;
+        let (records, end, stop) = self.index_records(start)?;
+
+        let cut = match stop {
+            ScanStop::End => false,
+            ScanStop::Torn(err) => {
+                if number != self.segment || !self.options.recover_torn_tail {
+                    return Err(err);
+                }
+                true
+            }
+            ScanStop::Damaged(err) => {
+                if number != self.segment || self.read_only {
+                    return Err(err);
+                }
+                true
+            }
+            ScanStop::Zeroed => number == self.segment && !self.read_only,
+        };
+        if cut {
+            let len = self.f.byte_len()?;
+            self.f.set_len(end)?;
+            self.discarded = len - end;
+        }
+        self.scanned = segment::position(number, end);
+
+        // Leave the cursor where the next record goes, not wherever the
+        // buffered reader stopped.
+        self.f.seek(SeekFrom::End(0))?;
+        Ok(records)
+    }
+
+    /// Indexes every record from `start` to the end of its segment, and
+    /// returns how many there were, the offset the scan stopped at and why.
+    fn index_records(&mut self, start: u64) -> Result<(usize, u64, ScanStop)> {
+        let (number, start) = segment::split(start);
+        let now = now_millis();
+        let kind = self.index.kind();
+        let mut records = 0;
+        self.usage = None;
+
+        let storage = if number == self.segment {
+            &self.f
+        } else {
+            match self.sealed.iter().find(|(sealed, _)| *sealed == number) {
+                Some((_, f)) => f,
+                None => return Ok((0, start, ScanStop::End)),
+            }
+        };
+        let len = storage.byte_len()?;
+        let capacity = self.options.buffer_capacity();
+        let mut f = BufReader::with_capacity(capacity, ReadAt::new(storage, start));
+        let offset =
+            |f: &BufReader<ReadAt<'_, S>>| f.get_ref().position() - f.buffer().len() as u64;
+
+        loop {
+            let current_offset = offset(&f);
+            let current_position = segment::position(number, current_offset);
+
+            let maybe_kv = process_record(&mut f, self.format, current_position, true);
+            // Zeros either fail their checksum or, in legacy logs, read as
+            // an empty record.
+            let zeroed = match &maybe_kv {
+                Ok(kv) => kv.checksum == 0 && kv.key.is_empty() && kv.value.is_empty(),
+                Err(err) => err.is_damage(),
+            };
+            if zeroed
+                && self.options.ignore_zeroed_tail
+                && current_offset < len
+                && zeroed_to(storage, current_offset, len)?
+            {
+                return Ok((records, current_offset, ScanStop::Zeroed));
+            }
+            let kv = match maybe_kv {
+                Ok(kv) => kv,
+                Err(Error::Truncated { .. }) if current_offset >= len => {
+                    return Ok((records, current_offset, ScanStop::End));
+                }
+                Err(err)
+                    if err.is_damage() && self.options.on_corruption == CorruptionPolicy::Skip =>
+                {
+                    let base = segment::position(number, 0);
+                    let end = match err {
+                        Error::Corrupt { .. } => Some(base + offset(&f)),
+                        _ => None,
+                    };
+                    match self.resync(current_position, end, base + len)? {
+                        Some(next) => {
+                            let next = next - base;
+                            self.skipped += next - current_offset;
+                            f = BufReader::with_capacity(capacity, ReadAt::new(storage, next));
+                            continue;
+                        }
+                        None => return Ok((records, current_offset, ScanStop::Torn(err))),
+                    }
+                }
+                Err(err)
+                    if err.is_damage()
+                        && self.options.on_corruption == CorruptionPolicy::Truncate =>
+                {
+                    return Ok((records, current_offset, ScanStop::Damaged(err)));
+                }
+                Err(err @ Error::Truncated { .. }) => {
+                    return Ok((records, current_offset, ScanStop::Torn(err)));
+                }
+                Err(err) if err.is_damage() && offset(&f) >= len => {
+                    return Ok((records, current_offset, ScanStop::Torn(err)));
+                }
+                Err(err) => return Err(err),
+            };
+            if kv.padding {
+                continue;
+            }
+
+            self.sequence = self.sequence.max(kv.sequence);
+            records += 1;
+            if let Index::Disabled = self.index {
+                continue;
+            }
+            let index = family::index_mut(&mut self.index, &mut self.families, kind, kv.family);
+            let superseded = if kv.tombstone || kv.is_expired(now) {
+                index.remove(&kv.key)
+            } else {
+                if kv.written_at != 0 {
+                    self.write_times.insert(current_position, kv.times());
+                }
+                index.insert(kv.key, current_position)
+            };
+            if let Some(superseded) = superseded {
+                self.write_times.remove(&superseded);
+            }
+        }
+    }
+
+    /// Frees the room the indexes have reserved beyond their keys, such as
+    /// what is left of [`Options::index_capacity`] after a load, or what
+    /// deletions have emptied.
+    ///
+    /// Shrinking costs a rehash of every key, and the next inserts grow the
+    /// index again, so this is for after bulk loads and deletions, not
+    /// between ordinary writes.
+    pub fn shrink_index(&mut self) {
+        self.index.shrink_to_fit();
+        for index in self.families.values_mut() {
+            index.shrink_to_fit();
+        }
+    }
+
+    /// Bytes of torn record, or of zeros as
+    /// [`Options::ignore_zeroed_tail`] describes, that the last
+    /// [`load`](ActionKV::load) cut off the end of the log.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Bytes of damaged records that the last [`load`](ActionKV::load), and
+    /// any [`refresh`](ActionKV::refresh) since, passed over under
+    /// [`CorruptionPolicy::Skip`].
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Saves the index to a sidecar file at `path`, along with the length of
+    /// the log it describes, so [`load_index`](ActionKV::load_index) can skip
+    /// most of the scan on the next open.
+    ///
+    /// The sidecar only has room for the default column family, so a store
+    /// with other families refuses this with `InvalidInput`.
+    pub fn save_index(&self, path: &Path) -> Result<()> {
+        self.check_indexed()?;
+        if !self.index.has_keys() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an index of hashed keys can't be saved",
+            )
+            .into());
+        }
+        if !self.families.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the index of a store with column families can't be saved",
+            )
+            .into());
+        }
+
+        // The end of the active segment, which is where the tail scan of
+        // `load_index` starts.
+        let log_len = segment::position(self.segment, self.f.byte_len()?);
+        Ok(sidecar::save(
+            path,
+            log_len,
+            self.sequence,
+            self.index.iter(),
+        )?)
+    }
+
+    /// Restores the index from a sidecar written by
+    /// [`save_index`](ActionKV::save_index), then scans only the records
+    /// appended since.
+    ///
+    /// Falls back to a full [`load`](ActionKV::load) and returns `false` when
+    /// the sidecar is missing, malformed, or describes a longer log than the
+    /// one on disk, or one whose active segment has since filled up.
+    pub fn load_index(&mut self, path: &Path) -> Result<bool> {
+        let log_len = self.f.byte_len()?;
+
+        if let Some(snapshot) = sidecar::load(path)? {
+            let (number, saved_len) = segment::split(snapshot.log_len);
+            if number == self.segment
+                && self.options.build_index
+                && self.format.data_start() <= saved_len
+                && saved_len <= log_len
+            {
+                self.index = Index::from_entries(self.index.kind(), snapshot.index);
+                self.write_times.clear();
+                self.sequence = snapshot.sequence;
+                match self.scan_from(snapshot.log_len) {
+                    Ok(_) => {
+                        self.recount()?;
+                        return Ok(true);
+                    }
+                    Err(err) if err.is_damage() => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        self.index.clear();
+        self.families.clear();
+        self.load()?;
+        Ok(false)
+    }
+
+    /// Looks up the live value of `key`. Expired values are `None`.
+    ///
+    /// Reads use positioned I/O and never move the file's cursor, so any
+    /// number of threads can call `get` on a shared `&ActionKV`.
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        self.check_indexed()?;
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        // An index of hashed keys can point at another key's record.
+        Ok(self
+            .get_live(position)?
+            .filter(|kv| kv.key == key)
+            .map(|kv| kv.value))
+    }
+
+    /// [`get`](ActionKV::get) that also returns where the value's record
+    /// starts and what it holds as stored, checksum included, so auditing
+    /// tools can check it without parsing the log themselves.
+    pub fn get_with_metadata(&self, key: &ByteStr) -> Result<Option<(ByteString, RecordMeta)>> {
+        self.check_indexed()?;
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let record = self.stored_record_at(position, self.options.verify_checksums)?;
+        if record.is_expired(now_millis()) || record.key != key {
+            return Ok(None);
+        }
+        let meta = RecordMeta {
+            offset: position,
+            checksum: record.checksum,
+            key_len: record.key.len() as u64,
+            value_len: record.value.len() as u64,
+        };
+        Ok(Some((self.decode(record)?.value, meta)))
+    }
+
+    /// Whether `key` has a live value. Only consults the index, so a key
+    /// that expired since it was indexed still counts until the next
+    /// [`load`](ActionKV::load). With an index of hashed keys the key of the
+    /// record is read as well, and a failed read counts as no.
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.position_in(&self.index, key)
+            .is_ok_and(|position| position.is_some())
+    }
+
+    /// Length of the live value of `key`, `None` where
+    /// [`get`](ActionKV::get) would find none. A plain value's length comes
+    /// from its record without loading the value itself, while a compressed
+    /// or encrypted one has to be read and decoded to be measured.
+    pub fn value_len(&self, key: &ByteStr) -> Result<Option<u64>> {
+        let (position, prefix) = match self.live_prefix(key)? {
+            None => return Ok(None),
+            Some(found) => found,
+        };
+        if prefix.flags & (FLAG_COMPRESSED | FLAG_ENCRYPTED) == 0 {
+            return Ok(Some(prefix.val_len));
+        }
+
+        let record = self.record_at(position, self.options.verify_checksums)?;
+        Ok(Some(record.value.len() as u64))
+    }
+
+    /// Length of the live value of `key` as the log stores it, compressed
+    /// or encrypted if it is, read from its record without loading the
+    /// value. `None` where [`get`](ActionKV::get) would find none.
+    pub fn stored_len(&self, key: &ByteStr) -> Result<Option<u64>> {
+        Ok(self.live_prefix(key)?.map(|(_, prefix)| prefix.val_len))
+    }
+
+    /// The position and the fields before the key of the live record of
+    /// `key`, or `None` if it has none or it has expired.
+    fn live_prefix(&self, key: &ByteStr) -> Result<Option<(u64, RecordPrefix)>> {
+        self.check_indexed()?;
+        let position = match self.position_in(&self.index, key)? {
+            None => return Ok(None),
+            Some(position) => position,
+        };
+
+        let prefix = self.prefix_at(position)?;
+        let expires_at = prefix.expires_at(self.format)?;
+        if expires_at != 0 && expires_at <= now_millis() {
+            return Ok(None);
+        }
+        Ok(Some((position, prefix)))
+    }
+
+    /// Reads the fields before the key of the record at `position`.
+    fn prefix_at(&self, position: u64) -> Result<RecordPrefix> {
+        let (number, offset) = segment::split(position);
+        let mut f = ReadAt::new(self.segment_storage(number)?, offset);
+        RecordPrefix::read(&mut f, self.format).map_err(|err| match err {
+            Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Error::Truncated { offset: position }
+            }
+            err => err,
+        })
+    }
+
+    /// Where `index` puts the record of `key`. An index of hashed keys can
+    /// point at another key's record, so with one the key of the record is
+    /// read and compared, and another key's record counts as none.
+    pub(crate) fn position_in(&self, index: &Index, key: &ByteStr) -> Result<Option<u64>> {
+        let position = match index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+        if !index.has_keys() && self.key_at(position)? != key {
+            return Ok(None);
+        }
+        Ok(Some(position))
+    }
+
+    /// Reads the key of the record at `position`, leaving its value alone.
+    fn key_at(&self, position: u64) -> Result<ByteString> {
+        let (number, offset) = segment::split(position);
+        let mut f = ReadAt::new(self.segment_storage(number)?, offset);
+        let truncated = |err: Error| match err {
+            Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Error::Truncated { offset: position }
+            }
+            err => err,
+        };
+        let prefix = RecordPrefix::read(&mut f, self.format).map_err(truncated)?;
+
+        // Read through `take` so a damaged length can't reserve more than
+        // the log holds.
+        let mut key = Vec::new();
+        f.take(prefix.key_len).read_to_end(&mut key)?;
+        if key.len() as u64 != prefix.key_len {
+            return Err(Error::Truncated { offset: position });
+        }
+        Ok(key)
+    }
+
+    /// Reads the header of the record at `position`, leaving its data alone.
+    fn header_at(&self, position: u64) -> Result<RecordHeader> {
+        let format = self.format;
+        let (number, offset) = segment::split(position);
+        let mut f = ReadAt::new(self.segment_storage(number)?, offset);
+        let truncated = |err: io::Error| match err.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated { offset: position },
+            _ => Error::Io(err),
+        };
+        format.read_checksum(&mut f).map_err(truncated)?;
+        let key_len = format.read_len(&mut f).map_err(truncated)?;
+        let val_len = format.read_len(&mut f).map_err(truncated)?;
+        let flags = if format.has_flags() {
+            f.read_u8().map_err(truncated)?
+        } else {
+            0
+        };
+
+        Ok(RecordHeader {
+            key_len,
+            val_len,
+            flags,
+        })
+    }
+
+    /// Reports how much of the log is live, and so how much
+    /// [`compact`](ActionKV::compact) would reclaim.
+    ///
+    /// Only the headers of the indexed records are read. Everything else in
+    /// the log past its header counts as dead, which includes superseded and
+    /// deleted records.
+    pub fn stats(&self) -> Result<Stats> {
+        self.check_indexed()?;
+        let log_bytes = self.log_bytes()?;
+        let headers = self.segments().count() as u64 * self.format.data_start();
+        let mut live_bytes = 0;
+        for position in family::positions(&self.index, &self.families) {
+            let header = self.header_at(position)?;
+            live_bytes += header.record_len(self.format);
+        }
+
+        Ok(Stats {
+            live_keys: self.index.len(),
+            log_bytes,
+            live_bytes,
+            dead_bytes: log_bytes.saturating_sub(headers).saturating_sub(live_bytes),
+        })
+    }
+
+    /// Works out what [`compact`](ActionKV::compact) would reclaim without
+    /// writing anything, for deciding whether a compaction is worth its
+    /// cost.
+    ///
+    /// Unlike [`stats`](ActionKV::stats), this reads the fields before the
+    /// value of each indexed record, so records that have expired count as
+    /// dead, and records of a legacy log are sized in the format compaction
+    /// would rewrite them in. Values are taken at their stored length, so
+    /// with [`Options::compression`](crate::Options) changed since they
+    /// were written the real figures differ.
+    pub fn estimate_compaction(&self) -> Result<CompactionEstimate> {
+        self.check_indexed()?;
+        let total_bytes = self.log_bytes()?;
+        let format = Format::new(self.format.endianness, self.format.checksum);
+        let now = now_millis();
+        let mut live_keys = 0;
+        let mut live_bytes = format.data_start();
+        // In log order, as compaction writes them, for the padding to come
+        // out the same.
+        for position in family::positions(&self.index, &self.families) {
+            if let Some((len, family)) = self.compacted_len(position, format, now)? {
+                live_bytes += padding_len(format, live_bytes, self.options.record_alignment);
+                live_bytes += len;
+                live_keys += (family == 0) as usize;
+            }
+        }
+
+        Ok(CompactionEstimate {
+            live_keys,
+            total_bytes,
+            live_bytes,
+            dead_bytes: total_bytes.saturating_sub(live_bytes),
+        })
+    }
+
+    /// Length of the record at `position` once compacted into a log of
+    /// `format`, and its column family, or `None` if it will have expired by
+    /// `now` and be dropped.
+    fn compacted_len(&self, position: u64, format: Format, now: u64) -> Result<Option<(u64, u32)>> {
+        let prefix = self.prefix_at(position)?;
+        let expires_at = prefix.expires_at(self.format)?;
+        if expires_at != 0 && expires_at <= now {
+            return Ok(None);
+        }
+
+        let header = RecordHeader {
+            key_len: prefix.key_len,
+            val_len: prefix.val_len,
+            flags: prefix.flags,
+        };
+        let family = self.format.read_u32(&mut &prefix.family[..])?;
+        Ok(Some((header.record_len(format), family)))
+    }
+
+    /// Counts the records of every key of the default column family across
+    /// the whole log, deletions included. Keys written over and over are the
+    /// ones [`compact`](ActionKV::compact) reclaims the most from, and the
+    /// counts go with [`stats`](ActionKV::stats) to show where the dead
+    /// bytes come from.
+    ///
+    /// Only the fields before each value are read, and values are skipped,
+    /// so nothing is checked against its checksum. A record whose lengths
+    /// run past the end of its segment fails with [`Error::Truncated`].
+    pub fn version_counts(&self) -> Result<HashMap<ByteString, u32>> {
+        let mut counts: HashMap<ByteString, u32> = HashMap::new();
+        self.walk_prefixes(true, |prefix, key| {
+            if prefix.flags & (FLAG_FAMILY | FLAG_PADDING) == 0 {
+                let count = counts.entry(key).or_insert(0);
+                *count = count.saturating_add(1);
+            }
+        })?;
+        Ok(counts)
+    }
+
+    /// Counts every record in the log: live ones, superseded ones and
+    /// deletions, of every column family and of the catalog that names
+    /// them. Next to [`count`](ActionKV::count),
+    /// the number of live keys, this shows how much of the log
+    /// [`compact`](ActionKV::compact) would drop.
+    ///
+    /// Only the fields before each key are read, and keys and values are
+    /// skipped, as for [`version_counts`](ActionKV::version_counts), and a
+    /// record whose lengths run past the end of its segment fails the same
+    /// way.
+    pub fn total_records(&self) -> Result<usize> {
+        let mut records = 0;
+        self.walk_prefixes(false, |prefix, _| {
+            records += (prefix.flags & FLAG_PADDING == 0) as usize;
+        })?;
+        Ok(records)
+    }
+
+    /// Calls `each` with the fields before the key of every record in the
+    /// log, in order, and with its key if `keys` is set or else an empty
+    /// one. Values are skipped unread.
+    fn walk_prefixes(
+        &self,
+        keys: bool,
+        mut each: impl FnMut(&RecordPrefix, ByteString),
+    ) -> Result<()> {
+        for (number, f) in self.segments() {
+            let len = f.byte_len()?;
+            let mut offset = self.format.data_start();
+            let mut r =
+                BufReader::with_capacity(self.options.buffer_capacity(), ReadAt::new(f, offset));
+            while offset < len {
+                let position = segment::position(number, offset);
+                let truncated = || Error::Truncated { offset: position };
+                let prefix = RecordPrefix::read(&mut r, self.format).map_err(|err| match err {
+                    Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => truncated(),
+                    err => err,
+                })?;
+                let fields = RecordHeader {
+                    key_len: 0,
+                    val_len: 0,
+                    flags: prefix.flags,
+                }
+                .record_len(self.format);
+                let end = prefix
+                    .key_len
+                    .checked_add(prefix.val_len)
+                    .and_then(|data_len| data_len.checked_add(offset + fields))
+                    .filter(|end| *end <= len)
+                    .ok_or_else(truncated)?;
+
+                // The key fits in the segment, so its length is no larger
+                // than the log.
+                let mut key = Vec::new();
+                if keys {
+                    key = vec![0; prefix.key_len as usize];
+                    r.read_exact(&mut key)?;
+                    r.seek_relative(prefix.val_len as i64)?;
+                } else {
+                    r.seek_relative((prefix.key_len + prefix.val_len) as i64)?;
+                }
+                offset = end;
+
+                each(&prefix, key);
+            }
+        }
+        Ok(())
+    }
+
+    /// [`get`](ActionKV::get) behind the `&mut self` receiver it used to
+    /// require.
+    #[deprecated(note = "`get` only needs `&self` now")]
+    pub fn get_exclusive(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+        self.get(key)
+    }
+
+    /// Looks up several keys at once, returning their values in the order
+    /// of `keys`. Missing keys map to `None`.
+    ///
+    /// The records are read in ascending file order rather than the order
+    /// asked for, which keeps the reads close together on large logs.
+    pub fn get_many(&self, keys: &[&ByteStr]) -> Result<Vec<Option<ByteString>>> {
+        self.check_indexed()?;
+        let mut reads: Vec<(u64, usize)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.index.get(key).map(|position| (*position, i)))
+            .collect();
+        reads.sort_unstable();
+
+        let mut values = vec![None; keys.len()];
+        for (position, i) in reads {
+            values[i] = self
+                .get_live(position)?
+                .filter(|kv| kv.key == keys[i])
+                .map(|kv| kv.value);
+        }
+
+        Ok(values)
+    }
+
+    /// Reads the record that starts at `position`, an offset returned by
+    /// [`insert_at`](ActionKV::insert_at) or [`find`](ActionKV::find), so
+    /// callers can keep their own indexes of the log.
+    ///
+    /// Any record can be read this way, including superseded and expired
+    /// ones; a deletion reads as an empty value. A `position` outside the
+    /// records of the log is an `InvalidInput` error, and one that falls
+    /// inside a record reads garbage, which fails with one of the errors for
+    /// a damaged log.
+    pub fn get_at(&self, position: u64) -> Result<KeyValuePair> {
+        if !self.within_records(position)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {} is outside the log's records", position),
+            )
+            .into());
+        }
+
+        Ok(self
+            .record_at(position, self.options.verify_checksums)?
+            .into())
+    }
+
+    /// The value of the record that starts at `position`, read as
+    /// [`get_at`](ActionKV::get_at) reads it, for a secondary index that
+    /// keeps offsets and has no use for the key. The index isn't consulted,
+    /// so a superseded or expired value reads back as it was written.
+    pub fn value_at(&self, position: u64) -> Result<ByteString> {
+        Ok(self.get_at(position)?.value)
+    }
+
+    /// Whether `position` lies between the header and the end of its
+    /// segment, and so can start a record.
+    fn within_records(&self, position: u64) -> Result<bool> {
+        let (number, offset) = segment::split(position);
+        match self.segment_storage(number) {
+            Ok(f) => Ok(offset >= self.format.data_start() && offset < f.byte_len()?),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn record_at(&self, position: u64, verify: bool) -> Result<Record> {
+        self.decode(self.stored_record_at(position, verify)?)
+    }
+
+    /// Reads the record at `position` with its value as it is stored.
+    fn stored_record_at(&self, position: u64, verify: bool) -> Result<Record> {
+        let (number, offset) = segment::split(position);
+        let f = self.segment_storage(number)?;
+        if let Some(mut bytes) = f.mapped().and_then(|map| map.get(offset as usize..)) {
+            match process_record(&mut bytes, self.format, position, verify) {
+                // The mapping can lag behind writes that haven't been
+                // flushed yet.
+                Err(Error::Truncated { .. }) => {}
+                result => return result,
+            }
+        }
+
+        let mut f = BufReader::new(ReadAt::new(f, offset));
+        process_record(&mut f, self.format, position, verify)
+    }
+
+    /// Decrypts and then decompresses the value of a record whose checksum
+    /// has been checked. Scans that only index the log never need to.
+    fn decode(&self, mut record: Record) -> Result<Record> {
+        if record.encoding & FLAG_ENCRYPTED != 0 {
+            record.value = encryption::decrypt(&self.options, &record.key, &record.value)?;
+        }
+        if record.encoding & FLAG_COMPRESSED != 0 {
+            record.value = compression::decode(&record.value)?;
+        }
+        record.encoding = 0;
+        Ok(record)
+    }
+
+    /// Reads the record at `position`, or `None` if it has expired.
+    fn get_live(&self, position: u64) -> Result<Option<KeyValuePair>> {
+        let record = self.record_at(position, self.options.verify_checksums)?;
+        if record.is_expired(now_millis()) {
+            return Ok(None);
+        }
+
+        Ok(Some(record.into()))
+    }
+
+    /// Iterates over the live value of every key, in no particular order.
+    ///
+    /// Each value is read from the log as the iterator advances, so a failed
+    /// read shows up as an `Err` item.
+    pub fn iter(&self) -> impl Iterator<Item = Result<KeyValuePair>> + '_ {
+        let positions: Vec<u64> = self.index.values().copied().collect();
+        positions
+            .into_iter()
+            .filter_map(move |position| self.get_live(position).transpose())
+    }
+
+    /// Iterates over the live value of every key, in no particular order,
+    /// reading each from the log as [`iter`](ActionKV::iter) does.
+    pub fn values(&self) -> impl Iterator<Item = Result<ByteString>> + '_ {
+        let positions: Vec<u64> = self.index.values().copied().collect();
+        positions.into_iter().filter_map(move |position| {
+            self.get_live(position)
+                .map(|kv| kv.map(|kv| kv.value))
+                .transpose()
+        })
+    }
+
+    /// Reads every live pair into a `HashMap`.
+    ///
+    /// The whole store ends up in memory at once, keys and values, so this
+    /// is only for stores known to be small. The first failed read fails the
+    /// whole call.
+    pub fn to_hashmap(&self) -> Result<HashMap<ByteString, ByteString>> {
+        let mut map = HashMap::with_capacity(self.index.len());
+        for position in self.index.values() {
+            if let Some(kv) = self.get_live(*position)? {
+                map.insert(kv.key, kv.value);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Iterates over every live key without touching the log.
+    pub fn keys(&self) -> impl Iterator<Item = &ByteStr> {
+        self.index.keys().map(|key| key.as_slice())
+    }
+
+    /// Iterates over the live records with keys in `[start, end)`, in key
+    /// order.
+    ///
+    /// With [`IndexKind::Sorted`] this walks only the matching part of the
+    /// index. A hash index has to find and sort the matching keys first, so
+    /// open with the sorted kind to run range queries often.
+    pub fn range(
+        &self,
+        start: &ByteStr,
+        end: &ByteStr,
+    ) -> impl Iterator<Item = Result<KeyValuePair>> + '_ {
+        self.index
+            .range(start, end)
+            .filter_map(move |(_, position)| self.get_live(position).transpose())
+    }
+
+    /// Iterates over the live records whose keys start with `prefix`, in key
+    /// order.
+    ///
+    /// With [`IndexKind::Sorted`] the scan stops at the first key past the
+    /// prefix. A hash index has to find and sort the matching keys first.
+    pub fn scan_prefix<'a>(
+        &'a self,
+        prefix: &'a ByteStr,
+    ) -> impl Iterator<Item = Result<KeyValuePair>> + 'a {
+        self.index
+            .prefix(prefix)
+            .filter_map(move |(_, position)| self.get_live(position).transpose())
+    }
+
+    /// Iterates over every record of the log in the order it was written,
+    /// superseded versions and deletions included, with the offset each
+    /// starts at. This is [`read_from`](ActionKV::read_from) from the
+    /// start, for looking into the history of a log rather than its live
+    /// state. Each record comes as a [`LogEntry`], which unlike a
+    /// [`KeyValuePair`] can tell a deletion from an empty value.
+    pub fn raw_records(&self) -> impl Iterator<Item = Result<(u64, LogEntry)>> + '_ {
+        self.read_from(0)
+    }
+
+    /// Iterates over every record from `offset` to the end of the log,
+    /// yielding each with the offset it starts at. Offsets before the first
+    /// record start at the first record. A segmented log is read one
+    /// segment after another, and the offsets are positions as
+    /// [`insert_at`](ActionKV::insert_at) returns them.
+    ///
+    /// Unlike [`iter`](ActionKV::iter) this yields superseded records,
+    /// deletions and expired records too, in the order they were written,
+    /// so a follower can replay them. It can store the offset after the
+    /// last entry it applied and resume from there. Only records of the
+    /// default column family are yielded.
+    ///
+    /// The iterator ends at the end of the log. A damaged record, or an
+    /// `offset` that isn't the start of a record, is yielded as an `Err`
+    /// and ends it.
+    pub fn read_from(&self, offset: u64) -> impl Iterator<Item = Result<(u64, LogEntry)>> + '_ {
+        let data_start = self.format.data_start();
+        let capacity = self.options.buffer_capacity();
+        let (first, offset) = segment::split(offset);
+        let mut segments = self
+            .segments()
+            .skip_while(move |(number, _)| *number < first);
+        let mut current = segments.next().map(|(number, f)| {
+            let offset = if number == first {
+                offset.max(data_start)
+            } else {
+                data_start
+            };
+            (
+                number,
+                f,
+                offset,
+                BufReader::with_capacity(capacity, ReadAt::new(f, offset)),
+            )
+        });
+
+        iter::from_fn(move || loop {
+            let (number, f, offset, reader) = current.as_mut()?;
+            let position = segment::position(*number, *offset);
+
+            match process_record(reader, self.format, position, true) {
+                Ok(record) => {
+                    *offset = reader.get_ref().position() - reader.buffer().len() as u64;
+                    if record.family != 0 || record.padding {
+                        continue;
+                    }
+                    let record = self.decode(record);
+                    if record.is_err() {
+                        current = None;
+                    }
+                    return Some(record.map(|record| (position, record.into())));
+                }
+                Err(err) => match (&err, f.byte_len()) {
+                    (Error::Truncated { .. }, Ok(len)) if *offset >= len => {
+                        current = segments.next().map(|(number, f)| {
+                            let r = ReadAt::new(f, data_start);
+                            (number, f, data_start, BufReader::with_capacity(capacity, r))
+                        });
+                    }
+                    _ => {
+                        current = None;
+                        return Some(Err(err));
+                    }
+                },
+            }
+        })
+    }
+
+    /// Iterates over every record that starts at a position from `start`
+    /// up to but not including `end`, in the order they were written, with
+    /// the position of each. The records are read one after another through
+    /// a single buffer, as [`read_from`](ActionKV::read_from) reads them,
+    /// which for bulk reads in storage order beats a lookup and a seek per
+    /// key.
+    ///
+    /// A record that starts before `end` is yielded whole even if it runs
+    /// past it, and the iterator ends at the first record that starts at or
+    /// after `end`, so an `end` inside a record needs no care. Superseded
+    /// and expired records are yielded too, and a deletion comes as an
+    /// empty value; use `read_from` to tell them apart. A `start` that isn't
+    /// where a record starts, or a damaged record in the range, is yielded
+    /// as an `Err` and ends the iterator.
+    pub fn scan(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> impl Iterator<Item = Result<(u64, KeyValuePair)>> + '_ {
+        self.read_from(start)
+            .take_while(move |entry| match entry {
+                Ok((position, _)) => *position < end,
+                Err(Error::Corrupt { offset, .. }) | Err(Error::Truncated { offset }) => {
+                    *offset < end
+                }
+                Err(_) => true,
+            })
+            .map(|entry| {
+                entry.map(|(position, entry)| {
+                    let kv = KeyValuePair {
+                        key: entry.key,
+                        value: entry.value,
+                    };
+                    (position, kv)
+                })
+            })
+    }
+
+    /// Number of live keys.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Number of live keys, the same as [`len`](ActionKV::len), to set
+    /// against [`total_records`](ActionKV::total_records).
+    pub fn count(&self) -> usize {
+        self.len()
+    }
+
+    /// Scans the whole log for the first record whose value equals `target`,
+    /// returning its offset and key. Deletions and expired records never
+    /// match, and a damaged record before the match fails the search.
+    ///
+    /// The record found may since have been overwritten: this searches the
+    /// log's history, not the store's contents.
+    /// [`find_by_value`](ActionKV::find_by_value) searches the live values.
+    pub fn find(&self, target: &ByteStr) -> Result<Option<(u64, ByteString)>> {
+        let now = now_millis();
+        for entry in self.read_from(0) {
+            let (position, entry) = entry?;
+            let expired = entry.expires_at.is_some_and(|at| at <= now);
+            if !entry.deleted && !expired && entry.value == target {
+                return Ok(Some((position, entry.key)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Every key whose live value equals `target`, in key order: a reverse
+    /// lookup from value to keys.
+    ///
+    /// Every live value is read from the log and compared, so this takes
+    /// time in proportion to the whole store. For frequent reverse lookups,
+    /// keep an index of your own from value to key, updated alongside
+    /// writes, with the offsets [`insert_at`](ActionKV::insert_at) returns.
+    pub fn find_by_value(&self, target: &ByteStr) -> Result<Vec<ByteString>> {
+        self.check_indexed()?;
+        let mut keys = Vec::new();
+        for position in self.index.values() {
+            if let Some(kv) = self.get_live(*position)? {
+                if kv.value == target {
+                    keys.push(kv.key);
+                }
+            }
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Every key in the index that satisfies `pred`, in key order. Only the
+    /// index is consulted, so nothing is read from the log, and a key whose
+    /// value has expired but not yet been dropped can turn up.
+    ///
+    /// An index of hashed keys has no keys to filter, so it fails with
+    /// `Unsupported`.
+    pub fn filter_keys<F: Fn(&ByteStr) -> bool>(&self, pred: F) -> Result<Vec<ByteString>> {
+        if !self.index.has_keys() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an index of hashed keys has no keys to filter",
+            )
+            .into());
+        }
+        let mut keys: Vec<ByteString> = self
+            .keys()
+            .filter(|key| pred(key))
+            .map(<[u8]>::to_vec)
+            .collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Every live pair that satisfies `pred`, in key order. Unlike
+    /// [`filter_keys`](ActionKV::filter_keys) this reads every live value
+    /// from the log, one at a time, so it takes time in proportion to the
+    /// whole store; only the matches are kept in memory.
+    pub fn filter<F: Fn(&ByteStr, &ByteStr) -> bool>(&self, pred: F) -> Result<Vec<KeyValuePair>> {
+        self.check_indexed()?;
+        let mut pairs = Vec::new();
+        for position in self.index.values() {
+            if let Some(kv) = self.get_live(*position)? {
+                if pred(&kv.key, &kv.value) {
+                    pairs.push(kv);
+                }
+            }
+        }
+        pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(pairs)
+    }
+
+    /// Every live key last written at or after `since`, a Unix time in
+    /// milliseconds, in key order, for incremental syncs and audits.
+    ///
+    /// Only records written with [`Options::record_write_times`] set carry
+    /// a time; the others count as written at 0. The times are kept in
+    /// memory next to the index as [`load`](ActionKV::load) reads the
+    /// records and as they are written. Records without one, or indexed from
+    /// a hint or a saved index, have the fields before their key read from
+    /// the log instead.
+    pub fn modified_since(&self, since: u64) -> Result<Vec<ByteString>> {
+        self.check_indexed()?;
+        let now = now_millis();
+        let mut keys = Vec::new();
+        if self.index.has_keys() {
+            for (key, position) in self.index.iter() {
+                if self.written_since(*position, since, now)? {
+                    keys.push(key.clone());
+                }
+            }
+        } else {
+            for position in self.index.values() {
+                if self.written_since(*position, since, now)? {
+                    keys.push(self.key_at(*position)?);
+                }
+            }
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Whether the record at `position` was written at or after `since`
+    /// and is still live at `now`.
+    fn written_since(&self, position: u64, since: u64, now: u64) -> Result<bool> {
+        let times = match self.write_times.get(&position) {
+            Some(times) => *times,
+            None => {
+                let prefix = self.prefix_at(position)?;
+                Times {
+                    written_at: self.format.read_u64(&mut &prefix.written_at[..])?,
+                    expires_at: prefix.expires_at(self.format)?,
+                }
+            }
+        };
+        let expired = times.expires_at != 0 && times.expires_at <= now;
+        Ok(!expired && times.written_at >= since)
+    }
+
+    /// Appends a record for `key` and points the index at it.
+    ///
+    /// The record is not durable until [`flush`](ActionKV::flush) or
+    /// [`sync`](ActionKV::sync) is called.
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.insert_at(key, value)?;
+        Ok(())
+    }
+
+    /// [`insert`](ActionKV::insert) that returns the offset of the new
+    /// record, for use with [`get_at`](ActionKV::get_at). The index is
+    /// updated just as `insert` does, so this is how to keep an index of
+    /// your own, say by a field inside the value, without scanning the log
+    /// for the offsets.
+    ///
+    /// An offset stays good until the record moves:
+    /// [`compact`](ActionKV::compact) and [`clear`](ActionKV::clear)
+    /// invalidate every offset, and
+    /// [`merge_segments`](ActionKV::merge_segments) those in sealed
+    /// segments, so an external index has to be rebuilt after them.
+    ///
+    /// With [`Options::skip_identical_writes`] set, a write of the value the
+    /// key already holds appends nothing and returns the existing record's
+    /// offset.
+    pub fn insert_at(&mut self, key: &ByteStr, value: &ByteStr) -> Result<u64> {
+        if self.options.skip_identical_writes {
+            self.check_writable()?;
+            if let Some(position) = self.identical_record(key, value)? {
+                return Ok(position);
+            }
+        }
+
+        let position = self.insert_but_ignore_index(key, value)?;
+
+        self.index.insert(key.to_vec(), position);
+        self.notify(key, Some(value));
+        Ok(position)
+    }
+
+    /// Position of the live record of `key` if it holds `value` and never
+    /// expires. A record that is damaged counts as different, so the write
+    /// goes ahead and replaces it.
+    fn identical_record(&self, key: &ByteStr, value: &ByteStr) -> Result<Option<u64>> {
+        let position = match self.position_in(&self.index, key) {
+            Ok(None) => return Ok(None),
+            Ok(Some(position)) => position,
+            Err(err) if err.is_damage() => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        match self.record_at(position, self.options.verify_checksums) {
+            Ok(record) if record.expires_at == 0 && record.value == value => Ok(Some(position)),
+            Ok(_) => Ok(None),
+            Err(err) if err.is_damage() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Appends every pair through a single buffered writer and flushes once.
+    ///
+    /// If a write fails partway, the log is cut back to the last record that
+    /// reached it in full, the index covers exactly those records, and the
+    /// error is returned.
+    pub fn insert_batch(&mut self, pairs: &[(&ByteStr, &ByteStr)]) -> Result<()> {
+        let records: Vec<_> = pairs
+            .iter()
+            .map(|(key, value)| BatchRecord::new(key, value, 0))
+            .collect();
+        self.append_batch(&records)
+    }
+
+    /// Appends every record, and indexes the ones that land, as
+    /// [`insert_batch`](ActionKV::insert_batch) describes. Tombstones remove
+    /// their keys from the index instead.
+    fn append_batch(&mut self, records: &[BatchRecord<'_>]) -> Result<()> {
+        self.check_writable()?;
+        for record in records {
+            self.check_key(record.key)?;
+            self.check_value_len(record.value.len() as u64)?;
+        }
+        self.check_alignment()?;
+        self.check_write_times()?;
+        self.compact_if_due()?;
+        self.check_room()?;
+
+        let format = self.format;
+        let options = &self.options;
+        let written_at = self.write_time();
+        let start = self.f.seek(SeekFrom::End(0))?;
+        let first_sequence = self.next_sequence();
+        let mut written = Vec::with_capacity(records.len());
+
+        let result = {
+            let mut f = BufWriter::with_capacity(options.buffer_capacity(), &mut *self.f);
+            let mut position = start;
+            records
+                .iter()
+                .try_for_each(|record| {
+                    let (value, encoding) =
+                        encode_value(options, format, record.key, record.value)?;
+                    let padding = padding_len(format, position, options.record_alignment);
+                    write_padding(&mut f, format, padding)?;
+                    position += padding;
+                    let sequence = first_sequence + written.len() as u64;
+                    let len = write_record(
+                        &mut f,
+                        format,
+                        record.key,
+                        &value,
+                        record.flags | encoding,
+                        sequence,
+                        Extras {
+                            written_at,
+                            ..record.extras
+                        },
+                    )?;
+                    written.push((position, position + len));
+                    position += len;
+                    Ok(())
+                })
+                .and_then(|_| f.flush())
+        };
+
+        let durable = match result {
+            Ok(()) => written.len(),
+            Err(_) => {
+                let len = self.f.byte_len()?;
+                let durable = written.iter().take_while(|(_, end)| *end <= len).count();
+                let boundary = durable.checked_sub(1).map_or(start, |last| written[last].1);
+                self.f.set_len(boundary)?;
+                durable
+            }
+        };
+
+        let mut previous_end = start;
+        for (record, (offset, end)) in records.iter().zip(&written).take(durable) {
+            if let Some(usage) = &mut self.usage {
+                usage.dead += offset - previous_end;
+            }
+            previous_end = *end;
+            let tombstone = record.flags & FLAG_TOMBSTONE != 0;
+            let position = segment::position(self.segment, *offset);
+            let superseded = if tombstone {
+                self.index.remove(record.key)
+            } else {
+                if written_at != 0 {
+                    let times = Times {
+                        written_at,
+                        expires_at: record.extras.expires_at,
+                    };
+                    self.write_times.insert(position, times);
+                }
+                self.index.insert(record.key.to_vec(), position)
+            };
+            if let Some(superseded) = superseded {
+                self.write_times.remove(&superseded);
+            }
+            self.account(superseded, end - offset, tombstone)?;
+        }
+        if durable > 0 && format.has_sequence() {
+            self.sequence = first_sequence + durable as u64 - 1;
+        }
+
+        // The records that landed before a failed write are indexed, so they
+        // are committed and reported too, as a write of their own would be.
+        let committed = self.commit(durable);
+        if committed.is_ok() {
+            for record in &records[..durable] {
+                let value = Some(record.value).filter(|_| record.flags & FLAG_TOMBSTONE == 0);
+                self.notify(record.key, value);
+            }
+        }
+
+        result?;
+        committed?;
+        self.roll_if_full()
+    }
+
+    /// Inserts `key` with a value that disappears once `ttl` has passed:
+    /// `get` returns `None` for it, `load` leaves it out of the index, and
+    /// [`compact`](ActionKV::compact) drops it.
+    ///
+    /// Expiry needs a record flags byte, so legacy logs refuse this with
+    /// `InvalidInput`.
+    pub fn insert_with_ttl(&mut self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> Result<()> {
+        if !self.format.has_flags() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "legacy logs cannot store expiry times",
+            )
+            .into());
+        }
+
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = now_millis().saturating_add(ttl);
+        let extras = Extras {
+            expires_at,
+            ..Extras::default()
+        };
+        let position = self.append(key, value, 0, extras)?;
+
+        self.index.insert(key.to_vec(), position);
+        self.notify(key, Some(value));
+        Ok(())
+    }
+
+    fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> Result<u64> {
+        self.append(key, value, 0, Extras::default())
+    }
+
+    fn append(&mut self, key: &ByteStr, value: &ByteStr, flags: u8, extras: Extras) -> Result<u64> {
+        self.check_writable()?;
+        self.check_value_len(value.len() as u64)?;
+        let (value, encoding) = encode_value(&self.options, self.format, key, value)?;
+        let value_len = value.len() as u64;
+        self.append_with(key, &mut &*value, value_len, flags | encoding, extras)
+    }
+
+    /// [`append`](ActionKV::append) for a `value` of `value_len` bytes,
+    /// already encoded as `flags` says, that may not be in memory. A write
+    /// that fails partway is cut off the log again.
+    fn append_with(
+        &mut self,
+        key: &ByteStr,
+        value: &mut dyn RecordValue,
+        value_len: u64,
+        flags: u8,
+        extras: Extras,
+    ) -> Result<u64> {
+        self.check_writable()?;
+        self.check_key(key)?;
+        self.check_alignment()?;
+        self.check_write_times()?;
+        self.compact_if_due()?;
+        self.check_room()?;
+        let extras = Extras {
+            written_at: self.write_time(),
+            ..extras
+        };
+        // Whatever the key held is superseded once the caller indexes the
+        // record, or removes the key for a tombstone.
+        let superseded = match extras.family {
+            0 => self.index.get(key),
+            family => self.families.get(&family).and_then(|index| index.get(key)),
+        }
+        .copied();
+        let format = self.format;
+        let sequence = self.next_sequence();
+        let mut f = BufWriter::new(&mut *self.f);
+
+        // Reads leave the cursor wherever they stopped, so the record's
+        // position has to come from the seek to the end.
+        let end = f.seek(SeekFrom::End(0))?;
+        let padding = padding_len(format, end, self.options.record_alignment);
+        let fields = RecordFields {
+            key,
+            value_len,
+            flags,
+            sequence,
+            extras,
+        };
+        let written = write_padding(&mut f, format, padding)
+            .and_then(|()| write_record_with(&mut f, format, fields, value))
+            .and_then(|written| f.flush().map(|()| written));
+        drop(f);
+        let written = written.and_then(|(len, checksum)| {
+            if let Some(checksum) = checksum {
+                let mut field = Vec::with_capacity(8);
+                format.write_checksum(&mut field, checksum)?;
+                self.f.write_at(&field, end + padding)?;
+            }
+            Ok(len)
+        });
+        let len = match written {
+            Ok(len) => len,
+            Err(err) => {
+                if self.f.byte_len()? > end {
+                    self.f.set_len(end)?;
+                }
+                return Err(err.into());
+            }
+        };
+
+        if format.has_sequence() {
+            self.sequence = sequence;
+        }
+        self.account(superseded, len, flags & FLAG_TOMBSTONE != 0)?;
+        if let Some(usage) = &mut self.usage {
+            usage.dead += padding;
+        }
+        let position = segment::position(self.segment, end + padding);
+        if let Some(superseded) = superseded {
+            self.write_times.remove(&superseded);
+        }
+        if extras.written_at != 0 && flags & FLAG_TOMBSTONE == 0 {
+            let times = Times {
+                written_at: extras.written_at,
+                expires_at: extras.expires_at,
+            };
+            self.write_times.insert(position, times);
+        }
+        self.commit(1)?;
+        self.roll_if_full()?;
+        Ok(position)
+    }
+
+    /// Seals the active segment and starts the next one once the active
+    /// segment has reached [`Options::max_segment_bytes`].
+    fn roll_if_full(&mut self) -> Result<()> {
+        let (max, base) = match (self.options.max_segment_bytes, &self.path) {
+            (Some(max), Some(base)) => (max, base),
+            _ => return Ok(()),
+        };
+        if self.f.byte_len()? < max {
+            return Ok(());
+        }
+
+        let number = match self
+            .segment
+            .checked_add(1)
+            .filter(|n| *n <= segment::MAX_SEGMENT)
+        {
+            Some(number) => number,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    "the log has run out of segment numbers",
+                )
+                .into())
+            }
+        };
+        let mut f = S::open_segment(&segment::path(base, number))?;
+        f.write_all(&self.format.header())?;
+        f.flush()?;
+
+        // A sealed segment is never written again, so make it durable now.
+        self.sync()?;
+        let sealed = mem::replace(&mut *self.f, f);
+        self.sealed.push((self.segment, sealed));
+        self.segment = number;
+        Ok(())
+    }
+
+    /// Applies [`Options::sync_policy`] after `writes` records were
+    /// appended.
+    fn commit(&mut self, writes: usize) -> Result<()> {
+        match self.options.sync_policy {
+            SyncPolicy::Manual => Ok(()),
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Batched {
+                writes: limit,
+                interval,
+            } => {
+                self.unsynced += writes;
+                if self.unsynced >= limit || self.last_sync.elapsed() >= interval {
+                    self.sync()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts a new record of `len` bytes in [`Usage`], as dead for a
+    /// tombstone, along with the live record at `superseded` turning dead.
+    fn account(&mut self, superseded: Option<u64>, len: u64, tombstone: bool) -> Result<()> {
+        if self.usage.is_none() {
+            return Ok(());
+        }
+
+        let superseded = match superseded {
+            Some(position) => self.header_at(position)?.record_len(self.format),
+            None => 0,
+        };
+        if let Some(usage) = &mut self.usage {
+            usage.live = usage.live.saturating_sub(superseded);
+            usage.dead += superseded;
+            if tombstone {
+                usage.dead += len;
+            } else {
+                usage.live += len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the live and dead bytes of the log afresh with
+    /// [`stats`](ActionKV::stats), for a store that compacts on its own, once
+    /// the index covers the whole log.
+    fn recount(&mut self) -> Result<()> {
+        self.usage = None;
+        if self.compactor.is_some() {
+            let stats = self.stats()?;
+            self.usage = Some(Usage {
+                live: stats.live_bytes,
+                dead: stats.dead_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Compacts the log if its dead bytes have passed
+    /// [`Options::auto_compact_ratio`].
+    fn compact_if_due(&mut self) -> Result<()> {
+        let (compact, usage, ratio) =
+            match (self.compactor, self.usage, self.options.auto_compact_ratio) {
+                (Some(compact), Some(usage), Some(ratio)) => (compact, usage, ratio),
+                _ => return Ok(()),
+            };
+        if usage.dead_ratio() > ratio {
+            compact(self)?;
+        }
+        Ok(())
+    }
+
+    /// Registers `f` to be called after every write to the default column
+    /// family that reaches the log, with the key and its new value, or
+    /// `None` for a deletion, so caches and other listeners can follow the
+    /// store without polling it. Any number of callbacks can be registered,
+    /// and they are called in the order they were.
+    ///
+    /// Callbacks run synchronously on the writing thread, inside the write
+    /// call and under whatever lock it holds, so they should be quick and
+    /// must not write to the store themselves. Each record of a batch gets
+    /// a call of its own, once the batch has been committed under the
+    /// [`SyncPolicy`], as a single write is. A write that appends nothing, such as one
+    /// [`Options::skip_identical_writes`] skips, calls nothing, and neither
+    /// do [`clear`](ActionKV::clear), compaction and
+    /// [`insert_stream`](ActionKV::insert_stream). Deleting from a legacy
+    /// log stores an empty value, and is reported as one.
+    pub fn on_write<F>(&mut self, f: F)
+    where
+        F: FnMut(&ByteStr, Option<&ByteStr>) + Send + Sync + 'static,
+    {
+        self.watchers.0.push(Box::new(f));
+    }
+
+    fn notify(&mut self, key: &ByteStr, value: Option<&ByteStr>) {
+        for watcher in &mut self.watchers.0 {
+            watcher(key, value);
+        }
+    }
+
+    /// Refuses to write to a store opened read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the store was opened read-only",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Refuses what needs the index on a store opened with
+    /// [`Options::build_index`] off.
+    fn check_indexed(&self) -> Result<()> {
+        if let Index::Disabled = self.index {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the store has no index; scan it with read_from or find",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Refuses to write to a legacy log with [`Options::record_alignment`]
+    /// set, as it has no flags byte to mark padding with.
+    fn check_alignment(&self) -> Result<()> {
+        let aligned = self.options.record_alignment.is_some_and(|at| at > 1);
+        if aligned && !self.format.has_flags() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "legacy logs cannot hold padding",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Refuses [`Options::record_write_times`] for a legacy log.
+    fn check_write_times(&self) -> Result<()> {
+        if self.options.record_write_times && !self.format.has_flags() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "legacy logs cannot store write times",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The write time to give a new record, 0 for none unless
+    /// [`Options::record_write_times`] is set.
+    fn write_time(&self) -> u64 {
+        if self.options.record_write_times {
+            now_millis()
+        } else {
+            0
+        }
+    }
+
+    /// Refuses an empty key when [`Options::reject_empty_keys`] is set.
+    fn check_key(&self, key: &ByteStr) -> Result<()> {
+        if key.is_empty() && self.options.reject_empty_keys {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "empty keys are rejected").into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Refuses a value longer than [`Options::max_value_bytes`].
+    fn check_value_len(&self, len: u64) -> Result<()> {
+        match self.options.max_value_bytes {
+            Some(max) if len > max => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a value of {} bytes is over the limit of {}", len, max),
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuses to write once the log has reached
+    /// [`Options::max_total_bytes`].
+    fn check_room(&self) -> Result<()> {
+        if let Some(max) = self.options.max_total_bytes {
+            let len = self.log_bytes()?;
+            if len >= max {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!("the log has reached its limit of {} bytes", max),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sequence number for the next record, or 0 if the format has none.
+    fn next_sequence(&self) -> u64 {
+        if self.format.has_sequence() {
+            self.sequence + 1
+        } else {
+            0
+        }
+    }
+
+    /// Highest sequence number written to or read from the log, 0 if there
+    /// is none yet.
+    ///
+    /// Every record appended to a log in the current format gets the next
+    /// number, so consumers can order writes and spot gaps. Records in logs
+    /// created before sequence numbers existed have none, and neither do
+    /// records appended to those logs.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Sets `key` to `new` only if its current value is `expected`, where
+    /// `None` means the key must be absent. Returns whether the swap happened.
+    ///
+    /// The comparison and the append both happen under `&mut self`, and a
+    /// store opened by path holds an exclusive lock on its log, so no other
+    /// handle or process can write between them. Stores built with
+    /// [`from_store`](ActionKV::from_store) have no such lock, and callers
+    /// sharing their storage must provide one.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &ByteStr,
+        expected: Option<&ByteStr>,
+        new: &ByteStr,
+    ) -> Result<bool> {
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        self.insert(key, new)?;
+        Ok(true)
+    }
+
+    /// [`insert`](ActionKV::insert) that returns the value `key` held
+    /// before, like `HashMap::insert`.
+    pub fn insert_returning(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> Result<Option<ByteString>> {
+        let previous = self.get(key)?;
+        self.insert(key, value)?;
+        Ok(previous)
+    }
+
+    /// [`update`](ActionKV::update) that returns the value `key` held before.
+    pub fn update_returning(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> Result<Option<ByteString>> {
+        self.insert_returning(key, value)
+    }
+
+    /// [`delete`](ActionKV::delete) that returns the value `key` held before.
+    pub fn delete_returning(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+        let previous = self.get(key)?;
+        self.delete(key)?;
+        Ok(previous)
+    }
+
+    /// Replaces the value of `key` with `f` applied to its current value, or
+    /// to `None` when the key is absent, and appends the result.
+    ///
+    /// The read and the write happen under `&mut self`, so nothing else
+    /// holding this handle can interleave with them. Another handle or process
+    /// writing the same log can, unless the callers lock it themselves.
+    pub fn merge<F>(&mut self, key: &ByteStr, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&ByteStr>) -> ByteString,
+    {
+        let current = self.get(key)?;
+        let value = f(current.as_deref());
+        self.insert(key, &value)
+    }
+
+    /// Inserts `key` only if it has no live value, like
+    /// `HashMap::try_insert`, and returns whether it did. A key that is
+    /// present writes nothing. One whose value has expired counts as absent.
+    pub fn insert_new(&mut self, key: &ByteStr, value: &ByteStr) -> Result<bool> {
+        if self.get(key)?.is_some() {
+            return Ok(false);
+        }
+
+        self.insert(key, value)?;
+        Ok(true)
+    }
+
+    /// Returns the live value of `key`, or, when it has none, appends the
+    /// value `f` returns and returns that. `f` is only called when the key
+    /// is missing or expired.
+    pub fn get_or_insert_with<F>(&mut self, key: &ByteStr, f: F) -> Result<ByteString>
+    where
+        F: FnOnce() -> ByteString,
+    {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+
+        let value = f();
+        self.insert(key, &value)?;
+        Ok(value)
+    }
+
+    /// Same as [`insert`](ActionKV::insert), including its durability caveat.
+    #[inline]
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.insert(key, value)
+    }
+
+    /// Appends a tombstone for `key` and drops it from the index, so `get`
+    /// returns `None`. Not durable until [`flush`](ActionKV::flush) or
+    /// [`sync`](ActionKV::sync) is called.
+    ///
+    /// Legacy logs have no room for a tombstone; deleting from one stores an
+    /// empty value instead.
+    pub fn delete(&mut self, key: &ByteStr) -> Result<()> {
+        if !self.format.has_flags() {
+            return self.insert(key, b"");
+        }
+
+        self.append(key, b"", FLAG_TOMBSTONE, Extras::default())?;
+        self.index.remove(key);
+        self.notify(key, None);
+        Ok(())
+    }
+
+    /// Deletes every key in `keys` through a single buffered writer and
+    /// flushes once. Keys that aren't present get a tombstone all the same.
+    ///
+    /// A failed write is handled as in
+    /// [`insert_batch`](ActionKV::insert_batch): the deletions that reached
+    /// the log take effect and the rest don't. Legacy logs store empty
+    /// values instead of tombstones, as [`delete`](ActionKV::delete) does.
+    pub fn delete_batch(&mut self, keys: &[&ByteStr]) -> Result<()> {
+        let flags = if self.format.has_flags() {
+            FLAG_TOMBSTONE
+        } else {
+            0
+        };
+        let records: Vec<_> = keys
+            .iter()
+            .map(|key| BatchRecord::new(key, b"", flags))
+            .collect();
+        self.append_batch(&records)
+    }
+
+    /// Deletes every key that starts with `prefix`, as one
+    /// [`delete_batch`](ActionKV::delete_batch), and returns how many keys
+    /// that was. A key that only contains `prefix` further in is left
+    /// alone, and so are keys in other column families.
+    ///
+    /// Finding the keys is a range of a sorted index, but a hash index is
+    /// walked in full. An index of hashed keys can't be searched by prefix,
+    /// so it fails with `Unsupported` and deletes nothing.
+    pub fn delete_prefix(&mut self, prefix: &ByteStr) -> Result<usize> {
+        if !self.index.has_keys() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an index of hashed keys can't be searched by prefix",
+            )
+            .into());
+        }
+        let keys: Vec<ByteString> = self
+            .index
+            .prefix(prefix)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let keys: Vec<&ByteStr> = keys.iter().map(Vec::as_slice).collect();
+        self.delete_batch(&keys)?;
+        Ok(keys.len())
+    }
+
+    /// Moves the live value of `old`, and its expiry time if it has one, to
+    /// `new`, and returns whether `old` had a value to move. Whatever `new`
+    /// held before is replaced.
+    ///
+    /// The record for `new` and the deletion of `old` are written through
+    /// one buffered writer and flushed together, and nothing else holding
+    /// this handle can come between the read of `old` and the writes. The
+    /// two records still land one after the other: a write that fails
+    /// between them, or a crash that tears the second, leaves the value
+    /// under both keys, but never under neither.
+    pub fn rename(&mut self, old: &ByteStr, new: &ByteStr) -> Result<bool> {
+        let position = match self.position_in(&self.index, old)? {
+            None => return Ok(false),
+            Some(position) => position,
+        };
+        let record = self.record_at(position, self.options.verify_checksums)?;
+        if record.is_expired(now_millis()) {
+            return Ok(false);
+        }
+        if old == new {
+            return Ok(true);
+        }
+
+        let flags = if self.format.has_flags() {
+            FLAG_TOMBSTONE
+        } else {
+            0
+        };
+        let mut moved = BatchRecord::new(new, &record.value, 0);
+        moved.extras.expires_at = record.expires_at;
+        self.append_batch(&[moved, BatchRecord::new(old, b"", flags)])?;
+        Ok(true)
+    }
+
+    /// Removes every record by truncating the log back to its header, and
+    /// empties the index. The next record is written right after the
+    /// header. A segmented log goes back to its first segment, and the files
+    /// of the others are deleted.
+    pub fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if !self.sealed.is_empty() {
+            let (_, first) = self.sealed.remove(0);
+            drop(mem::replace(&mut *self.f, first));
+            let mut numbers: Vec<u32> = self.sealed.drain(..).map(|(number, _)| number).collect();
+            numbers.push(mem::replace(&mut self.segment, 0));
+            if let Some(base) = &self.path {
+                for number in numbers {
+                    fs::remove_file(segment::path(base, number))?;
+                    segment::remove_hint(base, number)?;
+                }
+            }
+        }
+        if let Some(base) = &self.path {
+            segment::remove_hint(base, 0)?;
+        }
+
+        self.f.set_len(0)?;
+        self.f.seek(SeekFrom::Start(0))?;
+        self.f.write_all(&self.format.header())?;
+        self.f.flush()?;
+        self.scanned = self.format.data_start();
+        self.index.clear();
+        self.families.clear();
+        self.write_times.clear();
+        self.recount()
+    }
+
+    /// Cuts the log back to `position`, an offset returned by
+    /// [`insert_at`](ActionKV::insert_at) or [`find`](ActionKV::find), so
+    /// the record there and every one after it are gone, and rebuilds the
+    /// indexes from what is left. This rolls the store back to a checkpoint
+    /// taken with [`seek_to_end`](ActionKV::seek_to_end) or a returned
+    /// offset.
+    ///
+    /// `position` has to be in the active segment and be where a record
+    /// starts or where the segment ends, which is checked by reading the
+    /// records before it; anything else is an `InvalidInput` error and
+    /// leaves the log alone.
+    pub fn truncate_at(&mut self, position: u64) -> Result<()> {
+        self.check_writable()?;
+        let (number, offset) = segment::split(position);
+        if number != self.segment || !self.record_boundary(offset)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "offset {} is not a record boundary in the active segment",
+                    position
+                ),
+            )
+            .into());
+        }
+
+        self.f.flush()?;
+        self.f.set_len(offset)?;
+        self.f.seek(SeekFrom::End(0))?;
+        // A hint of the active segment can list records that are gone now.
+        if let Some(base) = &self.path {
+            segment::remove_hint(base, self.segment)?;
+        }
+
+        self.index.clear();
+        self.families.clear();
+        self.sequence = 0;
+        self.load()?;
+        Ok(())
+    }
+
+    /// Whether a record of the active segment starts at `offset`, or the
+    /// segment ends there.
+    fn record_boundary(&self, offset: u64) -> Result<bool> {
+        let start = self.format.data_start();
+        if offset < start || offset > self.f.byte_len()? {
+            return Ok(false);
+        }
+
+        let capacity = self.options.buffer_capacity();
+        let mut f = BufReader::with_capacity(capacity, ReadAt::new(&*self.f, start));
+        let mut current = start;
+        while current < offset {
+            let position = segment::position(self.segment, current);
+            process_record(&mut f, self.format, position, false)?;
+            current = f.get_ref().position() - f.buffer().len() as u64;
+        }
+        Ok(current == offset)
+    }
+
+    /// Writes a point-in-time copy of the log to `dest`, which opens and
+    /// loads as a standalone store. Returns the number of bytes copied.
+    ///
+    /// Pending writes are flushed first, then exactly the bytes up to the
+    /// current end of the log are copied. The length is read and the copy
+    /// made under `&mut self`, and only the exclusive lock that
+    /// [`open`](ActionKV::open) takes keeps other processes from appending in
+    /// between; without it a concurrent append can end up in the copy.
+    ///
+    /// Each segment of a segmented log is copied to the file `dest` names
+    /// for it, so the copy is segmented the same way.
+    pub fn snapshot(&mut self, dest: &Path) -> Result<u64> {
+        self.flush()?;
+
+        let mut copied = 0;
+        for (number, f) in self.segments() {
+            let len = f.byte_len()?;
+            let mut out = File::create(segment::path(dest, number))?;
+            let n = io::copy(&mut ReadAt::new(f, 0).take(len), &mut out)?;
+            if n < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "log shrank while it was being copied",
+                )
+                .into());
+            }
+            out.sync_all()?;
+            copied += n;
+        }
+
+        Ok(copied)
+    }
+
+    /// Hands any buffered writes to the operating system.
+    ///
+    /// Flushed writes survive the process exiting, but not a power loss.
+    /// Under [`SyncPolicy::Batched`] this also syncs any pending writes.
+    pub fn flush(&mut self) -> Result<()> {
+        self.f.flush()?;
+        if self.unsynced > 0 {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes buffered writes and forces them onto the disk with
+    /// [`Storage::sync`], which is [`File::sync_all`] for files.
+    pub fn sync(&mut self) -> Result<()> {
+        self.f.flush()?;
+        self.f.sync()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+/// Flushes the store, ignoring errors, and syncs any writes
+/// [`SyncPolicy::Batched`] still owes the disk. Call
+/// [`flush`](ActionKV::flush) or [`sync`](ActionKV::sync) before dropping a
+/// store to find out whether its writes made it.
+impl<S: Storage> Drop for ActionKV<S> {
+    fn drop(&mut self) {
+        if self.f.0.is_none() {
+            return;
+        }
+        let _ = self.flush();
+    }
+}
+
+impl ActionKV {
+    /// Indexes the records appended to the log since the last
+    /// [`load`](ActionKV::load) or refresh, and returns how many were read.
+    /// Segments the writer has started since are opened and read too.
+    ///
+    /// This is how a handle from [`open_read_only`](ActionKV::open_read_only)
+    /// follows a writer in another process without loading the whole log
+    /// again. A record the writer is partway through isn't an error: the
+    /// refresh stops before it, and the next one reads it once it is whole.
+    /// Damage anywhere before the end still fails with the error
+    /// [`load`](ActionKV::load) would return.
+    ///
+    /// A writer that compacts the log replaces its file, which a refresh
+    /// can't follow; open the log again instead.
+    pub fn refresh(&mut self) -> Result<usize> {
+        let data_start = self.format.data_start();
+        let mut records = 0;
+        loop {
+            let (number, offset) = segment::split(self.scanned);
+            let start = segment::position(number, offset.max(data_start));
+            let (read, end, stop) = self.index_records(start)?;
+            records += read;
+            self.scanned = segment::position(number, end);
+            if !matches!(stop, ScanStop::End) {
+                break;
+            }
+
+            match self.segment_after(number)? {
+                Some(next) => self.scanned = segment::position(next, data_start),
+                None => break,
+            }
+        }
+
+        self.recount()?;
+        Ok(records)
+    }
+
+    /// The number of the segment after `number`, opening it if the writer
+    /// has started it since the store was opened. `None` if there is none
+    /// yet, or it doesn't have its header yet.
+    fn segment_after(&mut self, number: u32) -> Result<Option<u32>> {
+        if number != self.segment {
+            let mut numbers = self.segments().map(|(number, _)| number);
+            return Ok(numbers.find(|n| *n > number));
+        }
+
+        let path = match (&self.path, number.checked_add(1)) {
+            (Some(base), Some(next)) => segment::path(base, next),
+            _ => return Ok(None),
+        };
+        let mut f = match File::open(&path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if f.byte_len()? < self.format.data_start() {
+            return Ok(None);
+        }
+        if Format::detect(&mut f, self.format)? != self.format {
+            return Err(Error::BadFormat(format!(
+                "segment {} isn't in the format of the first",
+                number + 1
+            )));
+        }
+        if !self.read_only {
+            f = ActionKV::open_file(&path)?;
+        }
+
+        let previous = mem::replace(&mut *self.f, f);
+        self.sealed.push((self.segment, previous));
+        self.segment = number + 1;
+        Ok(Some(self.segment))
+    }
+
+    /// Rewrites the log into `out` keeping only the live record of each key,
+    /// then renames `out` over the store's file and switches to it. Returns
+    /// the number of bytes reclaimed. A segmented log is compacted into its
+    /// first segment, and the files of the others are deleted. The new log
+    /// gets a hint file for [`load_from_hints`](ActionKV::load_from_hints).
+    ///
+    /// The live records keep the order they had in the log, whatever order
+    /// the index holds them in, so the same log always compacts to the same
+    /// bytes.
+    ///
+    /// Deleted and expired keys are dropped entirely, and legacy logs come out in the
+    /// current format, keeping their byte order and checksum. Values are
+    /// rewritten with this handle's compression setting. `out` must be on the
+    /// same file system as the store, which must have been opened by path.
+    pub fn compact(&mut self, out: &Path) -> Result<u64> {
+        self.check_writable()?;
+        self.check_indexed()?;
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot compact a store built from a bare file",
+                )
+                .into())
+            }
+        };
+
+        let old_len = self.log_bytes()?;
+        let format = Format::new(self.format.endianness, self.format.checksum);
+        let positions = family::positions(&self.index, &self.families);
+        let kind = self.index.kind();
+        let mut index = Index::new(kind);
+        let mut families = HashMap::new();
+        let mut write_times = HashMap::new();
+
+        {
+            let mut w =
+                BufWriter::with_capacity(self.options.buffer_capacity(), File::create(out)?);
+            w.write_all(&format.header())?;
+            let mut position = format.data_start();
+            let now = now_millis();
+            for old_position in positions {
+                // Never give a corrupt record a fresh checksum.
+                let kv = self.record_at(old_position, true)?;
+                if kv.is_expired(now) {
+                    continue;
+                }
+                let padding = padding_len(format, position, self.options.record_alignment);
+                write_padding(&mut w, format, padding)?;
+                position += padding;
+                let (value, flags) = encode_value(&self.options, format, &kv.key, &kv.value)?;
+                let len = write_record(
+                    &mut w,
+                    format,
+                    &kv.key,
+                    &value,
+                    flags,
+                    kv.sequence,
+                    kv.extras(),
+                )?;
+                if kv.written_at != 0 {
+                    write_times.insert(position, kv.times());
+                }
+                family::index_mut(&mut index, &mut families, kind, kv.family)
+                    .insert(kv.key, position);
+                position += len;
+            }
+            w.into_inner().map_err(io::Error::from)?.sync_all()?;
+        }
+
+        // Lock the new log before it takes the old one's place, so no other
+        // process can open it unlocked in between.
+        let f = ActionKV::open_file(out)?;
+        lock(&f, out, false)?;
+        fs::rename(out, &path)?;
+        *self.f = f;
+        self.format = format;
+        self.index = index;
+        self.families = families;
+        self.write_times = write_times;
+
+        // Everything now lives in the first segment.
+        let mut numbers: Vec<u32> = self.sealed.drain(..).map(|(number, _)| number).collect();
+        numbers.push(mem::replace(&mut self.segment, 0));
+        for number in numbers {
+            if number != 0 {
+                fs::remove_file(segment::path(&path, number))?;
+            }
+            segment::remove_hint(&path, number)?;
+        }
+        let new_len = self.f.metadata()?.len();
+        self.scanned = new_len;
+        // Hints, like index sidecars, only have room for the default family.
+        if self.families.is_empty() && self.index.has_keys() {
+            sidecar::save(
+                &segment::hint_path(&path, 0),
+                new_len,
+                self.sequence,
+                self.index.iter(),
+            )?;
+        }
+        self.recount()?;
+
+        Ok(old_len.saturating_sub(new_len))
+    }
+
+    /// [`compact`](ActionKV::compact) into a file next to the log, named
+    /// after it with `.compact` appended, as
+    /// [`Options::auto_compact_ratio`] does. Returns the number of bytes
+    /// reclaimed.
+    ///
+    /// The handle stays open throughout and carries on over the compacted
+    /// log, with its index pointing at the records' new offsets, so there
+    /// is nothing to reopen or reload afterwards.
+    pub fn compact_in_place(&mut self) -> Result<u64> {
+        let out = match &self.path {
+            Some(path) => {
+                let mut out = path.clone().into_os_string();
+                out.push(".compact");
+                PathBuf::from(out)
+            }
+            // Refused by `compact` before anything is written.
+            None => PathBuf::new(),
+        };
+        self.compact(&out)
+    }
+}
+
+/// Reads the record at the current position of `f`, which is `offset` in
+/// the log, and checks it against its checksum if `verify` is set.
+///
+/// A record cut short by the end of `f` is [`Error::Truncated`], whichever
+/// field it ends in.
+fn process_record<R: Read>(f: &mut R, format: Format, offset: u64, verify: bool) -> Result<Record> {
+    read_record(f, format, offset, verify).map_err(|err| match err {
+        Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => Error::Truncated { offset },
+        err => err,
+    })
+}
+
+fn read_record<R: Read>(f: &mut R, format: Format, offset: u64, verify: bool) -> Result<Record> {
+    let prefix = RecordPrefix::read(f, format)?;
+    let data_len = prefix
+        .key_len
+        .checked_add(prefix.val_len)
+        .filter(|len| usize::try_from(*len).is_ok())
+        .ok_or_else(|| {
+            Error::BadFormat(format!(
+                "record lengths {} + {} are too large",
+                prefix.key_len, prefix.val_len
+            ))
+        })?;
+
+    // The lengths aren't checksummed yet, so don't trust them with the
+    // whole allocation up front.
+    let mut data = ByteString::with_capacity(data_len.min(MAX_PREALLOCATION) as usize);
+
+    {
+        f.by_ref().take(data_len).read_to_end(&mut data)?;
+    }
+    if (data.len() as u64) < data_len {
+        return Err(Error::Truncated { offset });
+    }
+
+    if verify {
+        let mut hasher = prefix.hasher(format);
+        hasher.update(&data);
+        prefix.check(hasher.finish(), offset)?;
+    }
+
+    let value = data.split_off(prefix.key_len as usize);
+    let flags = prefix.flags;
+    Ok(Record {
+        key: data,
+        value,
+        checksum: prefix.checksum,
+        tombstone: flags & FLAG_TOMBSTONE != 0,
+        padding: flags & FLAG_PADDING != 0,
+        sequence: format.read_u64(&mut &prefix.sequence[..])?,
+        expires_at: prefix.expires_at(format)?,
+        family: format.read_u32(&mut &prefix.family[..])?,
+        written_at: format.read_u64(&mut &prefix.written_at[..])?,
+        encoding: flags & (FLAG_COMPRESSED | FLAG_ENCRYPTED),
+    })
+}
+
+/// Every field of a record before its key, as stored.
+struct RecordPrefix {
+    checksum: u64,
+    key_len: u64,
+    val_len: u64,
+    flags: u8,
+    sequence: [u8; 8],
+    expiry: [u8; 8],
+    family: [u8; 4],
+    written_at: [u8; 8],
+}
+
+impl RecordPrefix {
+    fn read<R: Read>(f: &mut R, format: Format) -> Result<RecordPrefix> {
+        let checksum = format.read_checksum(f)?;
+        let key_len = format.read_len(f)?;
+        let val_len = format.read_len(f)?;
+        let flags = if format.has_flags() { f.read_u8()? } else { 0 };
+
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::BadFormat(format!(
+                "unknown record flags {:02x}",
+                flags
+            )));
+        }
+
+        let mut prefix = RecordPrefix {
+            checksum,
+            key_len,
+            val_len,
+            flags,
+            sequence: [0; 8],
+            expiry: [0; 8],
+            family: [0; 4],
+            written_at: [0; 8],
+        };
+        if format.has_sequence() {
+            f.read_exact(&mut prefix.sequence)?;
+        }
+        if flags & FLAG_EXPIRES != 0 {
+            f.read_exact(&mut prefix.expiry)?;
+        }
+        if flags & FLAG_FAMILY != 0 {
+            f.read_exact(&mut prefix.family)?;
+        }
+        if flags & FLAG_WRITTEN_AT != 0 {
+            f.read_exact(&mut prefix.written_at)?;
+        }
+        Ok(prefix)
+    }
+
+    fn expires_at(&self, format: Format) -> io::Result<u64> {
+        format.read_u64(&mut &self.expiry[..])
+    }
+
+    /// A checksum of the record that has taken in every field before the
+    /// key, ready for the key and value.
+    fn hasher(&self, format: Format) -> Box<dyn Checksum> {
+        let mut hasher = format.checksum.hasher();
+        if format.has_flags() {
+            hasher.update(&[self.flags]);
+        }
+        if format.has_sequence() {
+            hasher.update(&self.sequence);
+        }
+        if self.flags & FLAG_EXPIRES != 0 {
+            hasher.update(&self.expiry);
+        }
+        if self.flags & FLAG_FAMILY != 0 {
+            hasher.update(&self.family);
+        }
+        if self.flags & FLAG_WRITTEN_AT != 0 {
+            hasher.update(&self.written_at);
+        }
+        hasher
+    }
+
+    /// Compares the checksum of the record at `offset` with the saved one.
+    fn check(&self, checksum: u64, offset: u64) -> Result<()> {
+        if checksum != self.checksum {
+            return Err(Error::Corrupt {
+                offset,
+                expected: self.checksum,
+                actual: checksum,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the bytes to store for the value of `key` and the record flags
+/// describing them: compressed and then encrypted, as `options` ask.
+fn encode_value<'v>(
+    options: &Options,
+    format: Format,
+    key: &ByteStr,
+    value: &'v ByteStr,
+) -> io::Result<(Cow<'v, ByteStr>, u8)> {
+    let (value, flags) = compression::encode(options, format, value)?;
+    encryption::encrypt(options, format, key, value, flags)
+}
+
+/// Writes one record and returns its length in bytes. `sequence` is only
+/// written in formats that have one. Each of the `extras` other than 0 adds
+/// its field and sets its flag.
+///
+/// Fails with `InvalidInput`, before writing anything, when the key or
+/// value is longer than the format can record.
+fn write_record<W: Write>(
+    f: &mut W,
+    format: Format,
+    key: &ByteStr,
+    value: &ByteStr,
+    flags: u8,
+    sequence: u64,
+    extras: Extras,
+) -> io::Result<u64> {
+    let fields = RecordFields {
+        key,
+        value_len: value.len() as u64,
+        flags,
+        sequence,
+        extras,
+    };
+    write_record_with(f, format, fields, &mut &*value).map(|(len, _)| len)
+}
+
+/// Everything [`write_record`] needs of a record but its value.
+struct RecordFields<'a> {
+    key: &'a ByteStr,
+    value_len: u64,
+    flags: u8,
+    sequence: u64,
+    extras: Extras,
+}
+
+/// The value of a record being written, which needn't be in memory.
+trait RecordValue {
+    /// Feeds the value to the record's checksum, returning `false` for a
+    /// value that can only be read once, which is fed to the checksum by
+    /// [`write_to`](RecordValue::write_to) instead.
+    fn hash(&mut self, hasher: &mut dyn Checksum) -> io::Result<bool>;
+
+    /// Writes the value, after [`hash`](RecordValue::hash) has been
+    /// called, feeding it to `hasher` as well if `hash` returned `false`.
+    fn write_to(&mut self, f: &mut dyn Write, hasher: &mut dyn Checksum) -> io::Result<()>;
+}
+
+impl RecordValue for &ByteStr {
+    fn hash(&mut self, hasher: &mut dyn Checksum) -> io::Result<bool> {
+        hasher.update(self);
+        Ok(true)
+    }
+
+    fn write_to(&mut self, f: &mut dyn Write, _: &mut dyn Checksum) -> io::Result<()> {
+        f.write_all(self)
+    }
+}
+
+/// A value of zeros, for padding records.
+struct Zeros(u64);
+
+impl RecordValue for Zeros {
+    fn hash(&mut self, hasher: &mut dyn Checksum) -> io::Result<bool> {
+        let zeros = [0; 512];
+        let mut remaining = self.0;
+        while remaining > 0 {
+            let len = remaining.min(zeros.len() as u64);
+            hasher.update(&zeros[..len as usize]);
+            remaining -= len;
+        }
+        Ok(true)
+    }
+
+    fn write_to(&mut self, f: &mut dyn Write, _: &mut dyn Checksum) -> io::Result<()> {
+        io::copy(&mut io::repeat(0).take(self.0), f)?;
+        Ok(())
+    }
+}
+
+/// Length of the padding record that has to go at `offset` for the record
+/// after it to start on a multiple of `alignment`, or 0 if none is needed.
+/// Formats without flags are never padded.
+fn padding_len(format: Format, offset: u64, alignment: Option<u64>) -> u64 {
+    let alignment = match alignment {
+        Some(alignment) if alignment > 1 && format.has_flags() => alignment,
+        _ => return 0,
+    };
+    let min = RecordHeader {
+        key_len: 0,
+        val_len: 0,
+        flags: FLAG_PADDING,
+    }
+    .record_len(format);
+    let mut len = (alignment - offset % alignment) % alignment;
+    while len != 0 && len < min {
+        len += alignment;
+    }
+    len
+}
+
+/// Writes a padding record `len` bytes long, as [`padding_len`] sized it.
+fn write_padding<W: Write>(f: &mut W, format: Format, len: u64) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let value_len = len
+        - RecordHeader {
+            key_len: 0,
+            val_len: 0,
+            flags: FLAG_PADDING,
+        }
+        .record_len(format);
+    let fields = RecordFields {
+        key: b"",
+        value_len,
+        flags: FLAG_PADDING,
+        sequence: 0,
+        extras: Extras::default(),
+    };
+    write_record_with(f, format, fields, &mut Zeros(value_len))?;
+    Ok(())
+}
+
+/// [`write_record`] for a `value` of `value_len` bytes that may not be in
+/// memory. A value that can only be read once leaves zeros where the
+/// checksum goes, and its checksum is returned after the length, to be
+/// written over them.
+fn write_record_with<W: Write>(
+    f: &mut W,
+    format: Format,
+    fields: RecordFields<'_>,
+    value: &mut dyn RecordValue,
+) -> io::Result<(u64, Option<u64>)> {
+    let RecordFields {
+        key,
+        value_len,
+        mut flags,
+        sequence,
+        extras,
+    } = fields;
+    let mut lengths = Vec::with_capacity(16);
+    format.write_len(&mut lengths, key.len() as u64)?;
+    format.write_len(&mut lengths, value_len)?;
+
+    let mut sequence_field = Vec::with_capacity(8);
+    if format.has_sequence() {
+        format.write_u64(&mut sequence_field, sequence)?;
+    }
+
+    let mut expiry = Vec::with_capacity(8);
+    if extras.expires_at != 0 {
+        flags |= FLAG_EXPIRES;
+        format.write_u64(&mut expiry, extras.expires_at)?;
+    }
+
+    let mut family = Vec::with_capacity(4);
+    if extras.family != 0 {
+        flags |= FLAG_FAMILY;
+        format.write_u32(&mut family, extras.family)?;
+    }
+
+    let mut written_at = Vec::with_capacity(8);
+    if extras.written_at != 0 {
+        flags |= FLAG_WRITTEN_AT;
+        format.write_u64(&mut written_at, extras.written_at)?;
+    }
+
+    let mut hasher = format.checksum.hasher();
+    if format.has_flags() {
+        hasher.update(&[flags]);
+    }
+    hasher.update(&sequence_field);
+    hasher.update(&expiry);
+    hasher.update(&family);
+    hasher.update(&written_at);
+    hasher.update(key);
+    let hashed = value.hash(&mut *hasher)?;
+    let checksum = if hashed { hasher.finish() } else { 0 };
+
+    format.write_checksum(f, checksum)?;
+    f.write_all(&lengths)?;
+    if format.has_flags() {
+        f.write_all(&[flags])?;
+    }
+    f.write_all(&sequence_field)?;
+    f.write_all(&expiry)?;
+    f.write_all(&family)?;
+    f.write_all(&written_at)?;
+    f.write_all(key)?;
+    value.write_to(f, &mut *hasher)?;
+
+    let extras_len = expiry.len() + family.len() + written_at.len();
+    let len = (format.record_header_len() + extras_len + key.len()) as u64 + value_len;
+    Ok((len, (!hashed).then(|| hasher.finish())))
+}
+
+/// The current Unix time in milliseconds, the clock record expiry uses.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Takes an advisory lock on `f` without waiting for it.
+fn lock(f: &File, path: &Path, shared: bool) -> io::Result<()> {
+    let result = if shared {
+        f.try_lock_shared()
+    } else {
+        f.try_lock()
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(fs::TryLockError::WouldBlock) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is locked by another handle", path.display()),
+        )),
+        Err(fs::TryLockError::Error(err)) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::Cursor;
+
+    use tempfile::TempDir;
+
+    fn store() -> (TempDir, ActionKV) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        (dir, store)
+    }
+
+    fn reopen(dir: &TempDir) -> ActionKV {
+        ActionKV::open(&dir.path().join("store.akv")).unwrap()
+    }
+
+    fn corrupt_last_byte(dir: &TempDir) {
+        let path = dir.path().join("store.akv");
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+    }
+
+    #[test]
+    fn process_record_reports_checksum_mismatch() {
+        let (dir, mut store) = store();
+        store.insert(b"key", b"value").unwrap();
+        drop(store);
+        corrupt_last_byte(&dir);
+
+        let bytes = fs::read(dir.path().join("store.akv")).unwrap();
+        let mut f = Cursor::new(bytes);
+        f.set_position(Format::CURRENT.data_start());
+        let err = process_record(&mut f, Format::CURRENT, 6, true).unwrap_err();
+
+        match err {
+            Error::Corrupt {
+                offset,
+                expected,
+                actual,
+            } => {
+                assert_eq!(offset, 6);
+                assert_ne!(expected, actual);
+            }
+            err => panic!("expected a checksum mismatch, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn process_record_rejects_overflowing_lengths() {
+        let format = Format::CURRENT;
+        let mut record = Vec::new();
+        format.write_checksum(&mut record, 0).unwrap();
+        format.write_u64(&mut record, u64::MAX).unwrap();
+        format.write_u64(&mut record, 1).unwrap();
+        record.push(0);
+        format.write_u64(&mut record, 1).unwrap();
+
+        let err = process_record(&mut Cursor::new(record), format, 0, true).unwrap_err();
+        assert!(matches!(err, Error::BadFormat(_)));
+    }
+
+    #[test]
+    fn process_record_reports_every_short_read_as_truncated() {
+        for format in [Format::CURRENT, Format::LEGACY] {
+            let mut record = Vec::new();
+            // Legacy records have nowhere to put an expiry or write time.
+            let extras = Extras {
+                expires_at: format.has_flags() as u64,
+                written_at: format.has_flags() as u64,
+                ..Extras::default()
+            };
+            write_record(&mut record, format, b"key", b"value", 0, 1, extras).unwrap();
+
+            for cut in 0..record.len() {
+                let mut f = Cursor::new(&record[..cut]);
+                match process_record(&mut f, format, 6, true) {
+                    Err(Error::Truncated { offset: 6 }) => {}
+                    result => panic!("cut at {}: expected truncation, got {:?}", cut, result),
+                }
+            }
+            assert!(process_record(&mut Cursor::new(&record), format, 6, true).is_ok());
+        }
+    }
+
+    #[test]
+    fn load_stops_at_first_corrupt_record() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        drop(store);
+        corrupt_last_byte(&dir);
+
+        let mut store = reopen(&dir);
+        let err = store.load().unwrap_err();
+
+        assert!(matches!(err, Error::Corrupt { offset: 37, .. }));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn resync_skips_damage_that_shifts_the_records_after_it() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        // Drops a byte from the header of the record at 37, so the one after
+        // it starts at 67 instead of 68.
+        let mut bytes = store.f.get_ref().clone();
+        bytes.remove(40);
+
+        let mut store = ActionKV::from_store(Cursor::new(bytes.clone())).unwrap();
+        assert!(store.load().unwrap_err().is_damage());
+
+        let options = Options {
+            on_corruption: CorruptionPolicy::Skip,
+            ..Options::default()
+        };
+        let mut store = ActionKV::from_store_with_options(Cursor::new(bytes), options).unwrap();
+        assert_eq!(store.load().unwrap(), 2);
+        assert_eq!(store.skipped_bytes(), 30);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn each_corruption_policy_handles_a_damaged_record() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        // Last byte of the value of the record at 37.
+        let mut bytes = store.f.get_ref().clone();
+        bytes[67] ^= 0xff;
+        let open = |on_corruption| {
+            let options = Options {
+                on_corruption,
+                ..Options::default()
+            };
+            ActionKV::from_store_with_options(Cursor::new(bytes.clone()), options).unwrap()
+        };
+
+        let mut store = open(CorruptionPolicy::Abort);
+        let err = store.load().unwrap_err();
+        assert!(matches!(err, Error::Corrupt { offset: 37, .. }));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+
+        let mut store = open(CorruptionPolicy::Skip);
+        assert_eq!(store.load().unwrap(), 2);
+        assert_eq!(store.skipped_bytes(), 31);
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.f.get_ref().len(), 99);
+
+        let mut store = open(CorruptionPolicy::Truncate);
+        assert_eq!(store.load().unwrap(), 1);
+        assert_eq!(store.discarded_bytes(), 62);
+        assert_eq!(store.f.get_ref().len(), 37);
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(store.insert_at(b"d", b"4").unwrap(), 37);
+    }
+
+    #[test]
+    fn skip_leaves_a_torn_tail_to_recover_torn_tail() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        // Tears the record at 68.
+        let mut bytes = store.f.get_ref().clone();
+        bytes.truncate(95);
+        let open = |recover_torn_tail| {
+            let options = Options {
+                on_corruption: CorruptionPolicy::Skip,
+                recover_torn_tail,
+                ..Options::default()
+            };
+            ActionKV::from_store_with_options(Cursor::new(bytes.clone()), options).unwrap()
+        };
+
+        let mut store = open(false);
+        assert!(matches!(store.load().unwrap_err(), Error::Truncated { .. }));
+        assert_eq!(store.f.get_ref().len(), 95);
+
+        let mut store = open(true);
+        assert_eq!(store.load().unwrap(), 2);
+        assert_eq!(store.discarded_bytes(), 27);
+        assert_eq!(store.f.get_ref().len(), 68);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    fn io_kind(err: Error) -> io::ErrorKind {
+        match err {
+            Error::Io(err) => err.kind(),
+            err => panic!("expected an I/O error, got {:?}", err),
+        }
+    }
+
+    /// Accepts at most `capacity` bytes, then fails like a full disk.
+    struct LimitedWriter {
+        buf: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let room = self.capacity - self.buf.len();
+            if room == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "no space left"));
+            }
+            let n = room.min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_record_surfaces_short_write() {
+        let mut f = LimitedWriter {
+            buf: Vec::new(),
+            capacity: 14,
+        };
+        let err = write_record(
+            &mut f,
+            Format::CURRENT,
+            b"key",
+            b"value",
+            0,
+            0,
+            Extras::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+        let mut f = BufWriter::new(LimitedWriter {
+            buf: Vec::new(),
+            capacity: 14,
+        });
+        write_record(
+            &mut f,
+            Format::CURRENT,
+            b"key",
+            b"value",
+            0,
+            0,
+            Extras::default(),
+        )
+        .unwrap();
+        assert!(f.flush().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn insert_surfaces_write_failure() {
+        let result =
+            ActionKV::open(Path::new("/dev/full")).and_then(|mut s| s.insert(b"key", b"value"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn flush_and_sync_persist_writes() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.flush().unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.sync().unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    /// In-memory storage that counts its syncs.
+    #[derive(Default)]
+    struct SyncCounter {
+        bytes: Cursor<Vec<u8>>,
+        syncs: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Read for SyncCounter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.bytes.read(buf)
+        }
+    }
+
+    impl Write for SyncCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.bytes.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SyncCounter {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.bytes.seek(pos)
+        }
+    }
+
+    impl Storage for SyncCounter {
+        fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+            self.bytes.read_at(buf, position)
+        }
+
+        fn byte_len(&self) -> io::Result<u64> {
+            self.bytes.byte_len()
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.bytes.set_len(len)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.syncs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dropping_a_store_syncs_what_its_policy_owes() {
+        let batched = Options {
+            sync_policy: SyncPolicy::Batched {
+                writes: 100,
+                interval: Duration::from_secs(3600),
+            },
+            ..Options::default()
+        };
+        let f = SyncCounter::default();
+        let syncs = f.syncs.clone();
+        let mut store = ActionKV::from_store_with_options(f, batched.clone()).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        assert_eq!(syncs.load(std::sync::atomic::Ordering::SeqCst), 0);
+        drop(store);
+        assert_eq!(syncs.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let f = SyncCounter::default();
+        let syncs = f.syncs.clone();
+        let mut store = ActionKV::from_store_with_options(f, batched).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let f = store.into_inner();
+        assert_eq!(syncs.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let mut store = ActionKV::from_store(f).unwrap();
+        assert_eq!(store.load().unwrap(), 1);
+        drop(store);
+        assert_eq!(syncs.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn into_inner_hands_back_a_store_with_families() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.cf("f").unwrap().insert(b"b", b"2").unwrap();
+        store.cf("g").unwrap().insert(b"c", b"3").unwrap();
+        assert_eq!(store.families.len(), 3);
+
+        let f = store.into_inner();
+        let mut store = ActionKV::from_store(f).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(
+            store.cf("f").unwrap().get(b"b").unwrap(),
+            Some(b"2".to_vec())
+        );
+        assert_eq!(
+            store.cf("g").unwrap().get(b"c").unwrap(),
+            Some(b"3".to_vec())
+        );
+    }
+
+    #[test]
+    fn delete_hides_key() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"").unwrap();
+        store.delete(b"a").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(Vec::new()));
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 3);
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn legacy_log_still_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut legacy = Vec::new();
+        write_record(
+            &mut legacy,
+            Format::LEGACY,
+            b"a",
+            b"1",
+            0,
+            0,
+            Extras::default(),
+        )
+        .unwrap();
+        write_record(
+            &mut legacy,
+            Format::LEGACY,
+            b"b",
+            b"2",
+            0,
+            0,
+            Extras::default(),
+        )
+        .unwrap();
+        fs::write(dir.path().join("store.akv"), legacy).unwrap();
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 2);
+        store.delete(b"b").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn a_zeroed_tail_is_cut_off_rather_than_loaded() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        drop(store);
+        let path = dir.path().join("store.akv");
+        let len = fs::metadata(&path).unwrap().len();
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[0; 4096]).unwrap();
+        drop(f);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 2);
+        assert_eq!(store.discarded_bytes(), 4096);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        store.insert(b"c", b"3").unwrap();
+        drop(store);
+        assert_eq!(reopen(&dir).load().unwrap(), 3);
+
+        // Zeros read as empty legacy records unless they are taken for a
+        // zeroed tail.
+        let mut legacy = Vec::new();
+        write_record(
+            &mut legacy,
+            Format::LEGACY,
+            b"a",
+            b"1",
+            0,
+            0,
+            Extras::default(),
+        )
+        .unwrap();
+        legacy.extend_from_slice(&[0; 24]);
+        fs::write(&path, &legacy).unwrap();
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 1);
+        assert!(!store.contains_key(b""));
+        drop(store);
+
+        fs::write(&path, &legacy).unwrap();
+        let options = Options {
+            ignore_zeroed_tail: false,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        assert_eq!(store.load().unwrap(), 3);
+        assert!(store.contains_key(b""));
+    }
+
+    #[test]
+    fn load_index_scans_only_the_tail() {
+        let (dir, mut store) = store();
+        let sidecar = dir.path().join("store.idx");
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.save_index(&sidecar).unwrap();
+        store.insert(b"c", b"3").unwrap();
+        store.delete(b"a").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert!(store.load_index(&sidecar).unwrap());
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn load_index_falls_back_to_full_scan() {
+        let (dir, mut store) = store();
+        let sidecar = dir.path().join("store.idx");
+        assert!(!store.load_index(&sidecar).unwrap());
+
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.save_index(&sidecar).unwrap();
+        drop(store);
+
+        let path = dir.path().join("store.akv");
+        let len = fs::metadata(&path).unwrap().len();
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(len - 31).unwrap();
+        drop(f);
+
+        let mut store = reopen(&dir);
+        assert!(!store.load_index(&sidecar).unwrap());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn load_index_rescans_when_the_sidecar_is_damaged() {
+        let (dir, mut store) = store();
+        let sidecar = dir.path().join("store.idx");
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.save_index(&sidecar).unwrap();
+        drop(store);
+
+        // Swap the positions of the two keys, which both still point at
+        // records.
+        let mut bytes = fs::read(&sidecar).unwrap();
+        let positions: Vec<usize> = bytes
+            .windows(8)
+            .enumerate()
+            .filter(|(_, window)| *window == 6u64.to_le_bytes() || *window == 37u64.to_le_bytes())
+            .map(|(at, _)| at)
+            .collect();
+        assert_eq!(positions.len(), 2);
+        bytes[positions[0]] ^= 6 ^ 37;
+        bytes[positions[1]] ^= 6 ^ 37;
+        fs::write(&sidecar, bytes).unwrap();
+
+        let mut store = reopen(&dir);
+        assert!(!store.load_index(&sidecar).unwrap());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn compact_drops_stale_records() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"22").unwrap();
+        store.insert(b"b", b"3").unwrap();
+        store.delete(b"b").unwrap();
+        store.insert(b"c", b"4").unwrap();
+
+        let reclaimed = store.compact(&dir.path().join("store.compact")).unwrap();
+        assert_eq!(reclaimed, 31 + 31 + 30);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"22".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        store.insert(b"d", b"5").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 3);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"22".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"5".to_vec()));
+        assert!(!dir.path().join("store.compact").exists());
+    }
+
+    #[test]
+    fn compaction_output_is_byte_identical_for_the_same_log() {
+        let (dir, mut store) = store();
+        for i in 0..200u32 {
+            store
+                .insert(&(i % 50).to_le_bytes(), &i.to_be_bytes())
+                .unwrap();
+        }
+        store.delete(&7u32.to_le_bytes()).unwrap();
+        let copy = dir.path().join("copy.akv");
+        store.snapshot(&copy).unwrap();
+        drop(store);
+
+        // Each load builds its index with its own hash order.
+        let mut outputs = Vec::new();
+        for path in [dir.path().join("store.akv"), copy] {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.load().unwrap();
+            store.compact(&dir.path().join("store.compact")).unwrap();
+            outputs.push(fs::read(&path).unwrap());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    fn segmented(dir: &TempDir) -> ActionKV {
+        let options = Options {
+            max_segment_bytes: Some(64),
+            ..Options::default()
+        };
+        ActionKV::open_with_options(&dir.path().join("store.akv"), options).unwrap()
+    }
+
+    #[test]
+    fn full_segments_roll_over_to_new_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = segmented(&dir);
+        let mut positions = Vec::new();
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            positions.push(store.insert_at(key, b"1").unwrap());
+        }
+        let second = segment::position(1, 6);
+        assert_eq!(
+            positions,
+            [6, 37, second, second + 31, segment::position(2, 6)]
+        );
+        assert_eq!(store.get_at(positions[3]).unwrap().key, b"d");
+        store.delete(b"a").unwrap();
+        drop(store);
+
+        assert!(dir.path().join("store.akv.3").exists());
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 6);
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"1".to_vec()));
+        let read: Vec<u64> = store.read_from(37).map(|entry| entry.unwrap().0).collect();
+        assert_eq!(read[..3], positions[1..4]);
+        assert_eq!(store.stats().unwrap().log_bytes, 4 * 6 + 5 * 31 + 30);
+        assert!(store.verify().unwrap().is_clean());
+    }
+
+    #[test]
+    fn compact_and_clear_merge_segments_into_the_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = segmented(&dir);
+        for key in [b"a", b"b", b"c", b"a"] {
+            store.insert(key, b"1").unwrap();
+        }
+        assert!(dir.path().join("store.akv.1").exists());
+
+        store.compact(&dir.path().join("store.compact")).unwrap();
+        assert!(!dir.path().join("store.akv.1").exists());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        for key in [b"d", b"e", b"f"] {
+            store.insert(key, b"1").unwrap();
+        }
+        assert!(dir.path().join("store.akv.1").exists());
+
+        store.clear().unwrap();
+        assert!(!dir.path().join("store.akv.1").exists());
+        store.insert(b"g", b"1").unwrap();
+        drop(store);
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 1);
+    }
+
+    #[test]
+    fn load_from_hints_reads_only_what_followed_the_hint() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.compact(&dir.path().join("store.compact")).unwrap();
+        store.delete(b"b").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        drop(store);
+
+        // Damage the hinted record of `b`, which only a full load reads.
+        let path = dir.path().join("store.akv");
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[36] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        assert!(matches!(
+            reopen(&dir).load(),
+            Err(Error::Corrupt { offset: 6, .. })
+        ));
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load_from_hints().unwrap(), 4);
+        assert_eq!(store.keys().count(), 2);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.sequence(), 5);
+    }
+
+    #[test]
+    fn iter_yields_live_values() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"a", b"3").unwrap();
+        store.insert(b"c", b"4").unwrap();
+        store.delete(b"c").unwrap();
+
+        let mut pairs: Vec<_> = store
+            .iter()
+            .map(|kv| {
+                let kv = kv.unwrap();
+                (kv.key, kv.value)
+            })
+            .collect();
+        pairs.sort();
+
+        assert_eq!(
+            pairs,
+            vec![
+                (b"a".to_vec(), b"3".to_vec()),
+                (b"b".to_vec(), b"2".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn values_and_to_hashmap_skip_dead_records() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.insert(b"b", b"3").unwrap();
+        store.insert(b"c", b"4").unwrap();
+        store.delete(b"c").unwrap();
+        store
+            .insert_with_ttl(b"d", b"5", Duration::from_millis(0))
+            .unwrap();
+
+        let mut values: Vec<ByteString> = store.values().collect::<Result<_>>().unwrap();
+        values.sort();
+        assert_eq!(values, [b"2".to_vec(), b"3".to_vec()]);
+
+        let map = store.to_hashmap().unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&b"a".to_vec()], b"2");
+    }
+
+    #[test]
+    fn keys_and_len_follow_the_index() {
+        let (_dir, mut store) = store();
+        assert!(store.is_empty());
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"a", b"3").unwrap();
+        store.delete(b"b").unwrap();
+        store.insert(b"c", b"4").unwrap();
+
+        let mut keys: Vec<_> = store.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec![&b"a"[..], &b"c"[..]]);
+        assert_eq!(store.len(), 2);
+        assert!(!store.is_empty());
+    }
+
+    #[test]
+    fn find_scans_past_non_matching_records() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        store.insert(b"d", b"2").unwrap();
+
+        assert_eq!(store.find(b"2").unwrap(), Some((37, b"b".to_vec())));
+        assert_eq!(store.find(b"3").unwrap(), Some((68, b"c".to_vec())));
+        assert_eq!(store.find(b"9").unwrap(), None);
+    }
+
+    #[test]
+    fn find_by_value_returns_every_key_that_holds_it_now() {
+        let (_dir, mut store) = store();
+        store.insert(b"c", b"2").unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+
+        assert_eq!(
+            store.find_by_value(b"2").unwrap(),
+            [b"a".to_vec(), b"b".to_vec()]
+        );
+        assert!(store.find_by_value(b"1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn filters_pick_out_keys_and_pairs() {
+        let (_dir, mut store) = store();
+        store.insert(b"user:2", b"bob").unwrap();
+        store.insert(b"user:1", b"alice").unwrap();
+        store.insert(b"team:1", b"ops").unwrap();
+        store.insert(b"user:3", b"carol").unwrap();
+        store.delete(b"user:3").unwrap();
+
+        assert_eq!(
+            store.filter_keys(|key| key.starts_with(b"user:")).unwrap(),
+            [b"user:1".to_vec(), b"user:2".to_vec()]
+        );
+        let pairs = store
+            .filter(|key, value| key.starts_with(b"user:") && value.contains(&b'o'))
+            .unwrap();
+        let keys: Vec<&[u8]> = pairs.iter().map(|kv| kv.key.as_slice()).collect();
+        assert_eq!(keys, [b"user:2"]);
+        assert_eq!(pairs[0].value, b"bob");
+        assert!(store.filter(|_, _| false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn modified_since_finds_keys_by_their_write_time() {
+        let (dir, mut store) = store();
+        store.insert(b"untimed", b"0").unwrap();
+        drop(store);
+
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            record_write_times: true,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options.clone()).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let since = now_millis();
+        store.insert(b"d", b"4").unwrap();
+        store.update(b"a", b"5").unwrap();
+        store.delete(b"c").unwrap();
+
+        let recent = [b"a".to_vec(), b"d".to_vec()];
+        assert_eq!(store.modified_since(since).unwrap(), recent);
+        assert_eq!(store.modified_since(1).unwrap().len(), 3);
+        assert_eq!(store.modified_since(0).unwrap().len(), 4);
+        // Only the live records that carry a time are kept.
+        assert_eq!(store.write_times.len(), 3);
+
+        store.compact_in_place().unwrap();
+        assert_eq!(store.write_times.len(), 3);
+        assert_eq!(store.modified_since(since).unwrap(), recent);
+        drop(store);
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.write_times.len(), 3);
+        assert_eq!(store.modified_since(since).unwrap(), recent);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"5".to_vec()));
+    }
+
+    #[test]
+    fn find_matches_the_last_record() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"end").unwrap();
+
+        assert_eq!(store.find(b"end").unwrap(), Some((68, b"c".to_vec())));
+    }
+
+    #[test]
+    fn insert_after_a_read_indexes_the_appended_record() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", &[1; 20_000]).unwrap();
+        store.insert(b"b", &[2; 20_000]).unwrap();
+        store.find(&[1; 20_000]).unwrap();
+        store.insert(b"c", b"3").unwrap();
+        store.get(b"a").unwrap();
+        store.insert(b"d", b"4").unwrap();
+
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn get_reports_corruption_at_the_record_offset() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        *store.f.get_mut().last_mut().unwrap() ^= 0xff;
+
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        let err = store.get(b"b").unwrap_err();
+        assert!(matches!(err, Error::Corrupt { offset: 37, .. }));
+        assert!(err.to_string().starts_with("corrupt record at offset 37 "));
+    }
+
+    #[test]
+    fn open_in_missing_directory_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = ActionKV::open(&dir.path().join("missing").join("store.akv")).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn big_endian_log_is_detected_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            endianness: Endianness::Big,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        store.insert(b"key", b"value").unwrap();
+        drop(store);
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(&bytes[..6], b"AKV\x05\x01\x00");
+        assert_eq!(&bytes[10..18], &[0, 0, 0, 0, 0, 0, 0, 3]);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"key").unwrap(), Some(b"value".to_vec()));
+        store.compact(&dir.path().join("store.compact")).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn each_checksum_algorithm_round_trips() {
+        for checksum in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::XxHash64,
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("store.akv");
+            let options = Options {
+                checksum,
+                ..Options::default()
+            };
+            let mut store = ActionKV::open_with_options(&path, options).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            drop(store);
+
+            let mut store = ActionKV::open(&path).unwrap();
+            assert_eq!(store.load().unwrap(), 2);
+            assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+            drop(store);
+
+            corrupt_last_byte(&dir);
+            let mut store = ActionKV::open(&path).unwrap();
+            let err = store.load().unwrap_err();
+            assert!(matches!(err, Error::Corrupt { .. }));
+        }
+    }
+
+    #[test]
+    fn insert_batch_indexes_every_pair() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"0").unwrap();
+        store
+            .insert_batch(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")])
+            .unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+        store.insert(b"d", b"4").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 5);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn delete_batch_tombstones_every_key() {
+        let (dir, mut store) = store();
+        store
+            .insert_batch(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")])
+            .unwrap();
+        store.delete_batch(&[b"a", b"c", b"missing"]).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.len(), 1);
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 6);
+        assert_eq!(store.keys().collect::<Vec<_>>(), [&b"b"[..]]);
+        assert_eq!(sequences(&mut store), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn on_write_reports_every_write_in_order() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::new(Mutex::new(0));
+        let (_dir, mut store) = store();
+        let sink = events.clone();
+        store.on_write(move |key, value| {
+            let event = (key.to_vec(), value.map(<[u8]>::to_vec));
+            sink.lock().unwrap().push(event);
+        });
+        let counter = calls.clone();
+        store.on_write(move |_, _| *counter.lock().unwrap() += 1);
+
+        store.insert(b"a", b"1").unwrap();
+        store.delete(b"a").unwrap();
+        store.insert_batch(&[(b"b", b"2"), (b"c", b"3")]).unwrap();
+        store.cf("f").unwrap().insert(b"d", b"4").unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (b"a".to_vec(), None),
+                (b"b".to_vec(), Some(b"2".to_vec())),
+                (b"c".to_vec(), Some(b"3".to_vec())),
+            ]
+        );
+        assert_eq!(*calls.lock().unwrap(), 4);
+    }
+
+    #[test]
+    fn on_write_hears_of_a_batch_once_it_is_synced() {
+        use std::sync::atomic::Ordering;
+        use std::sync::{Arc, Mutex};
+
+        let options = Options {
+            sync_policy: SyncPolicy::Always,
+            ..Options::default()
+        };
+        let f = SyncCounter::default();
+        let syncs = f.syncs.clone();
+        let mut store = ActionKV::from_store_with_options(f, options).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        store.on_write(move |_, _| sink.lock().unwrap().push(syncs.load(Ordering::SeqCst)));
+
+        store.insert_batch(&[(b"a", b"1"), (b"b", b"2")]).unwrap();
+        assert_eq!(*seen.lock().unwrap(), [1, 1]);
+    }
+
+    #[test]
+    fn insert_new_never_overwrites() {
+        let (_dir, mut store) = store();
+        assert!(store.insert_new(b"a", b"1").unwrap());
+        let len = store.stats().unwrap().log_bytes;
+
+        assert!(!store.insert_new(b"a", b"2").unwrap());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.stats().unwrap().log_bytes, len);
+
+        store.delete(b"a").unwrap();
+        assert!(store.insert_new(b"a", b"3").unwrap());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn total_records_counts_dead_records_too() {
+        let mut store = ActionKV::from_store(io::Cursor::new(Vec::new())).unwrap();
+        assert_eq!(store.total_records().unwrap(), 0);
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", &[7; 10_000]).unwrap();
+        store.insert(b"b", b"1").unwrap();
+        store.delete(b"b").unwrap();
+        store.cf("f").unwrap().insert(b"a", b"1").unwrap();
+
+        // Creating the family wrote a record of its own to the catalog.
+        assert_eq!(store.count(), 1);
+        assert_eq!(store.total_records().unwrap(), 6);
+    }
+
+    #[test]
+    fn version_counts_count_every_record_of_a_key() {
+        let mut store = ActionKV::from_store(io::Cursor::new(Vec::new())).unwrap();
+        for value in [&b"1"[..], &[7; 10_000], b"3"] {
+            store.insert(b"hot", value).unwrap();
+        }
+        store.insert(b"cold", b"1").unwrap();
+        store.delete(b"cold").unwrap();
+        store.cf("f").unwrap().insert(b"hot", b"1").unwrap();
+
+        let counts = store.version_counts().unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!((counts[&b"hot"[..]], counts[&b"cold"[..]]), (3, 2));
+
+        let len = store.f.get_ref().len();
+        store.f.get_mut().truncate(len - 1);
+        assert!(matches!(
+            store.version_counts().unwrap_err(),
+            Error::Truncated { .. }
+        ));
+    }
+
+    #[test]
+    fn rename_moves_the_value_and_its_expiry() {
+        let (dir, mut store) = store();
+        store.insert(b"old", b"1").unwrap();
+        store.insert(b"taken", b"2").unwrap();
+        store
+            .insert_with_ttl(b"session", b"3", Duration::from_secs(60))
+            .unwrap();
+
+        assert!(store.rename(b"old", b"taken").unwrap());
+        assert!(store.rename(b"session", b"session:2").unwrap());
+        assert!(store.rename(b"taken", b"taken").unwrap());
+        assert!(!store.rename(b"old", b"new").unwrap());
+        assert_eq!(store.get(b"taken").unwrap(), Some(b"1".to_vec()));
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(b"old").unwrap(), None);
+        assert_eq!(store.get(b"taken").unwrap(), Some(b"1".to_vec()));
+        let entries: Vec<_> = store.read_from(0).map(|entry| entry.unwrap().1).collect();
+        let moved = entries
+            .iter()
+            .find(|entry| entry.key == b"session:2")
+            .unwrap();
+        assert!(moved.expires_at.is_some());
+    }
+
+    #[test]
+    fn open_existing_refuses_a_missing_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let err = ActionKV::open_existing(&path).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::NotFound);
+        assert!(!path.exists());
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        drop(store);
+        let mut store = ActionKV::open_existing(&path).unwrap();
+        assert_eq!(store.load().unwrap(), 1);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn create_from_writes_and_indexes_every_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let pairs: HashMap<ByteString, ByteString> = (0..300u32)
+            .map(|i| (i.to_be_bytes().to_vec(), i.to_string().into_bytes()))
+            .collect();
+
+        let store = ActionKV::create_from(&path, pairs.clone()).unwrap();
+        assert_eq!(store.to_hashmap().unwrap(), pairs);
+        drop(store);
+        let err = ActionKV::create_from(&path, Vec::new()).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::AlreadyExists);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 300);
+        assert_eq!(store.to_hashmap().unwrap(), pairs);
+        assert_eq!(sequences(&mut store).last(), Some(&300));
+    }
+
+    #[test]
+    fn delete_prefix_only_matches_keys_that_start_with_it() {
+        for kind in [IndexKind::Hash, IndexKind::Sorted] {
+            let options = Options {
+                index: kind,
+                ..Options::default()
+            };
+            let mut store =
+                ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap();
+            for key in [
+                &b"user:1"[..],
+                b"user:12",
+                b"user:2",
+                b"order:user:1",
+                b"user",
+            ] {
+                store.insert(key, b"x").unwrap();
+            }
+
+            assert_eq!(store.delete_prefix(b"user:1").unwrap(), 2);
+            let mut keys: Vec<_> = store.keys().collect();
+            keys.sort();
+            assert_eq!(keys, [&b"order:user:1"[..], b"user", b"user:2"]);
+            assert_eq!(store.delete_prefix(b"user:1").unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn get_many_keeps_the_order_of_the_keys() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        store.insert(b"a", b"4").unwrap();
+
+        let values = store
+            .get_many(&[b"c", b"missing", b"a", b"b", b"c"])
+            .unwrap();
+        assert_eq!(
+            values,
+            [
+                Some(b"3".to_vec()),
+                None,
+                Some(b"4".to_vec()),
+                Some(b"2".to_vec()),
+                Some(b"3".to_vec()),
+            ]
+        );
+        assert!(store.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge_combines_with_the_current_value() {
+        let (dir, mut store) = store();
+        let increment = |current: Option<&ByteStr>| {
+            let n = current.map_or(0, |bytes| bytes[0]);
+            vec![n + 1]
+        };
+
+        store.merge(b"counter", increment).unwrap();
+        store.merge(b"counter", increment).unwrap();
+        store.merge(b"counter", increment).unwrap();
+        assert_eq!(store.get(b"counter").unwrap(), Some(vec![3]));
+
+        store.delete(b"counter").unwrap();
+        store.merge(b"counter", increment).unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.get(b"counter").unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn open_locks_the_log_until_drop() {
+        let (dir, store) = store();
+        let path = dir.path().join("store.akv");
+
+        let err = ActionKV::open(&path).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::WouldBlock);
+        let err = ActionKV::open_shared(&path).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::WouldBlock);
+
+        drop(store);
+        ActionKV::open(&path).unwrap();
+    }
+
+    #[test]
+    fn shared_handles_read_together_and_keep_writers_out() {
+        let (dir, mut store) = store();
+        let path = dir.path().join("store.akv");
+        store.insert(b"a", b"1").unwrap();
+        drop(store);
+
+        let mut first = ActionKV::open_shared(&path).unwrap();
+        let mut second = ActionKV::open_shared(&path).unwrap();
+        first.load().unwrap();
+        second.load().unwrap();
+        assert_eq!(first.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(second.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        let err = ActionKV::open(&path).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::WouldBlock);
+        assert!(first.insert(b"b", b"2").is_err());
+        let err = first
+            .compact(&dir.path().join("store.compact"))
+            .unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn read_only_handles_read_beside_a_writer() {
+        let (dir, mut writer) = store();
+        writer.insert(b"a", b"1").unwrap();
+
+        let mut reader = ActionKV::open_read_only(&dir.path().join("store.akv")).unwrap();
+        assert_eq!(reader.load().unwrap(), 1);
+        assert_eq!(reader.get(b"a").unwrap(), Some(b"1".to_vec()));
+        for err in [
+            reader.insert(b"b", b"2").unwrap_err(),
+            reader.delete(b"a").unwrap_err(),
+            reader.clear().unwrap_err(),
+        ] {
+            assert_eq!(io_kind(err), io::ErrorKind::PermissionDenied);
+        }
+        assert_eq!(reader.get(b"a").unwrap(), Some(b"1".to_vec()));
+        writer.insert(b"b", b"2").unwrap();
+    }
+
+    #[test]
+    fn refresh_follows_a_writer_up_to_a_torn_record() {
+        let (dir, mut writer) = store();
+        writer.insert(b"a", b"1").unwrap();
+        let mut reader = ActionKV::open_read_only(&dir.path().join("store.akv")).unwrap();
+        assert_eq!(reader.load().unwrap(), 1);
+        assert_eq!(reader.refresh().unwrap(), 0);
+
+        writer.insert(b"b", b"2").unwrap();
+        writer.delete(b"a").unwrap();
+        assert_eq!(reader.refresh().unwrap(), 2);
+        assert_eq!(reader.get(b"a").unwrap(), None);
+        assert_eq!(reader.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        // Land the next record in two halves, as a writer caught partway
+        // would.
+        let mut record = Vec::new();
+        let format = Format::CURRENT;
+        write_record(&mut record, format, b"c", b"3", 0, 4, Extras::default()).unwrap();
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("store.akv"))
+            .unwrap();
+        f.write_all(&record[..20]).unwrap();
+        assert_eq!(reader.refresh().unwrap(), 0);
+        f.write_all(&record[20..]).unwrap();
+        assert_eq!(reader.refresh().unwrap(), 1);
+        assert_eq!(reader.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(reader.sequence(), 4);
+    }
+
+    #[test]
+    fn refresh_opens_segments_started_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = segmented(&dir);
+        writer.insert(b"a", b"1").unwrap();
+        let mut reader = ActionKV::open_read_only(&dir.path().join("store.akv")).unwrap();
+        reader.load().unwrap();
+
+        for key in [b"b", b"c", b"d", b"e"] {
+            writer.insert(key, b"1").unwrap();
+        }
+        assert!(dir.path().join("store.akv.2").exists());
+        assert_eq!(reader.refresh().unwrap(), 4);
+        assert_eq!(reader.len(), 5);
+        assert_eq!(reader.get(b"e").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn compact_keeps_the_log_locked() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.compact(&dir.path().join("store.compact")).unwrap();
+
+        let err = ActionKV::open(&dir.path().join("store.akv")).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn range_yields_live_records_in_key_order() {
+        for kind in [IndexKind::Hash, IndexKind::Sorted] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("store.akv");
+            let options = Options {
+                index: kind,
+                ..Options::default()
+            };
+            let mut store = ActionKV::open_with_options(&path, options.clone()).unwrap();
+            for key in [&b"d"[..], b"a", b"c", b"b", b"e"] {
+                store.insert(key, key).unwrap();
+            }
+            store.insert(b"b", b"updated").unwrap();
+            store.delete(b"c").unwrap();
+            drop(store);
+
+            let mut store = ActionKV::open_with_options(&path, options).unwrap();
+            store.load().unwrap();
+            let pairs: Vec<(ByteString, ByteString)> = store
+                .range(b"b", b"e")
+                .map(|kv| kv.map(|kv| (kv.key, kv.value)))
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(
+                pairs,
+                [
+                    (b"b".to_vec(), b"updated".to_vec()),
+                    (b"d".to_vec(), b"d".to_vec()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn scan_prefix_separates_overlapping_prefixes() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            index: IndexKind::Sorted,
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::open_with_options(&dir.path().join("store.akv"), options).unwrap();
+        for key in [&b"abc:2"[..], b"ab:1", b"abc:1", b"ab:2", b"b:1"] {
+            store.insert(key, b"").unwrap();
+        }
+        store.delete(b"ab:2").unwrap();
+
+        let keys = |prefix: &ByteStr| -> Vec<ByteString> {
+            store
+                .scan_prefix(prefix)
+                .map(|kv| kv.unwrap().key)
+                .collect()
+        };
+        assert_eq!(keys(b"ab"), [&b"ab:1"[..], b"abc:1", b"abc:2"]);
+        assert_eq!(keys(b"ab:"), [&b"ab:1"[..]]);
+        assert_eq!(keys(b"abc"), [&b"abc:1"[..], b"abc:2"]);
+    }
+
+    #[test]
+    fn store_runs_entirely_in_memory() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert_batch(&[(b"b", b"2"), (b"c", b"3")]).unwrap();
+        store.delete(b"c").unwrap();
+        store.sync().unwrap();
+
+        let bytes = store.into_inner().into_inner();
+        assert_eq!(&bytes[..4], b"AKV\x05");
+
+        let mut store = ActionKV::from_store(Cursor::new(bytes)).unwrap();
+        assert_eq!(store.load().unwrap(), 4);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(
+            store.find(b"2").unwrap().map(|(_, key)| key),
+            Some(b"b".to_vec())
+        );
+    }
+
+    #[test]
+    fn compact_needs_a_path() {
+        let f = tempfile::tempfile().unwrap();
+        let mut store = ActionKV::from_store(f).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = store.compact(&dir.path().join("out")).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+    }
+
+    fn truncate_by(dir: &TempDir, n: u64) {
+        let path = dir.path().join("store.akv");
+        let len = fs::metadata(&path).unwrap().len();
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(len - n).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_fails_load_without_recovery() {
+        for cut in [3, 20] {
+            let (dir, mut store) = store();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            drop(store);
+            truncate_by(&dir, cut);
+
+            let err = reopen(&dir).load().unwrap_err();
+            assert!(matches!(err, Error::Truncated { offset: 37 }));
+        }
+    }
+
+    #[test]
+    fn recovery_truncates_a_torn_tail() {
+        // Cut into the value, into the header, and flip the last byte.
+        for (cut, corrupt) in [(3, false), (20, false), (0, true)] {
+            let (dir, mut store) = store();
+            let path = dir.path().join("store.akv");
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            drop(store);
+            let good_len = fs::metadata(&path).unwrap().len() - 31;
+            truncate_by(&dir, cut);
+            if corrupt {
+                corrupt_last_byte(&dir);
+            }
+
+            let mut store = ActionKV::open_with_recovery(&path).unwrap();
+            assert_eq!(store.load().unwrap(), 1);
+            assert_eq!(store.discarded_bytes(), 31 - cut);
+            assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+
+            store.insert(b"c", b"3").unwrap();
+            drop(store);
+            let mut store = reopen(&dir);
+            assert_eq!(store.load().unwrap(), 2);
+            assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(store.discarded_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn recovery_leaves_corruption_before_the_tail_alone() {
+        let (dir, mut store) = store();
+        let path = dir.path().join("store.akv");
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        drop(store);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[6 + 22] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let err = ActionKV::open_with_recovery(&path)
+            .unwrap()
+            .load()
+            .unwrap_err();
+        assert!(matches!(err, Error::Corrupt { .. }));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn returning_variants_yield_the_previous_value() {
+        let (_dir, mut store) = store();
+
+        assert_eq!(store.insert_returning(b"a", b"1").unwrap(), None);
+        assert_eq!(
+            store.update_returning(b"a", b"2").unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(
+            store.insert_returning(b"a", b"3").unwrap(),
+            Some(b"2".to_vec())
+        );
+        assert_eq!(store.delete_returning(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.delete_returning(b"a").unwrap(), None);
+        assert_eq!(store.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn expired_values_disappear() {
+        let (dir, mut store) = store();
+        store.insert(b"kept", b"1").unwrap();
+        store
+            .insert_with_ttl(b"expired", b"2", Duration::ZERO)
+            .unwrap();
+        store
+            .insert_with_ttl(b"fresh", b"3", Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(store.get(b"expired").unwrap(), None);
+        assert_eq!(store.get(b"fresh").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.iter().count(), 2);
+        assert_eq!(store.find(b"2").unwrap(), None);
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 3);
+        assert!(!store.contains_key(b"expired"));
+        assert_eq!(store.len(), 2);
+
+        store.compact(&dir.path().join("store.compact")).unwrap();
+        drop(store);
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 2);
+        let fresh = store
+            .record_at(*store.index.get(b"fresh").unwrap(), true)
+            .unwrap();
+        assert_ne!(fresh.expires_at, 0);
+    }
+
+    #[test]
+    fn expiry_before_the_previous_value_hides_the_key() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert_with_ttl(b"a", b"2", Duration::ZERO).unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn legacy_log_refuses_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut legacy = Vec::new();
+        write_record(
+            &mut legacy,
+            Format::LEGACY,
+            b"a",
+            b"1",
+            0,
+            0,
+            Extras::default(),
+        )
+        .unwrap();
+        fs::write(dir.path().join("store.akv"), legacy).unwrap();
+
+        let err = reopen(&dir)
+            .insert_with_ttl(b"a", b"2", Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn skip_identical_writes_appends_only_changes() {
+        let mut store = ActionKV::from_store_with_options(
+            Cursor::new(Vec::new()),
+            Options {
+                skip_identical_writes: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let first = store.insert_at(b"a", b"1").unwrap();
+        store.update(b"a", b"1").unwrap();
+        assert_eq!(store.insert_at(b"a", b"1").unwrap(), first);
+        assert_eq!(store.stats().unwrap().log_bytes, 6 + 31);
+
+        store.update(b"a", b"2").unwrap();
+        store
+            .insert_with_ttl(b"b", b"1", Duration::from_secs(60))
+            .unwrap();
+        store.insert(b"b", b"1").unwrap();
+        assert_eq!(store.stats().unwrap().log_bytes, 6 + 31 + 31 + 39 + 31);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn stats_count_superseded_and_deleted_records_as_dead() {
+        let (_dir, mut store) = store();
+        let empty = store.stats().unwrap();
+        assert_eq!(empty.log_bytes, 6);
+        assert_eq!(empty.dead_ratio(), 0.0);
+
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"22").unwrap();
+        store.insert(b"b", b"3").unwrap();
+        store.delete(b"b").unwrap();
+        store
+            .insert_with_ttl(b"c", b"4", Duration::from_secs(3600))
+            .unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.live_keys, 2);
+        assert_eq!(stats.live_bytes, 32 + 39);
+        assert_eq!(stats.dead_bytes, 31 + 31 + 30);
+        assert_eq!(stats.log_bytes, 6 + stats.live_bytes + stats.dead_bytes);
+        assert!((stats.dead_ratio() - 92.0 / 163.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_store_without_an_index_still_logs_and_scans() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            build_index: false,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options.clone()).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert_batch(&[(b"b", b"2"), (b"a", b"3")]).unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        assert_eq!(store.load().unwrap(), 3);
+        assert_eq!(store.len(), 0);
+        let unsupported =
+            |err: Error| matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::Unsupported);
+        assert!(unsupported(store.get(b"a").unwrap_err()));
+        assert!(unsupported(
+            store.compact(&dir.path().join("out")).unwrap_err()
+        ));
+        assert!(unsupported(store.cf("other").err().unwrap()));
+
+        let entries: Vec<_> = store
+            .read_from(0)
+            .map(|entry| entry.unwrap().1.key)
+            .collect();
+        assert_eq!(entries, [b"a".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+        assert!(store.find(b"3").unwrap().is_some());
+    }
+
+    #[test]
+    fn records_start_on_the_alignment_boundary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            record_alignment: Some(64),
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options.clone()).unwrap();
+        let a = store.insert_at(b"a", &[1; 10]).unwrap();
+        // A gap too small for a padding record takes another boundary.
+        let b = store.insert_at(b"b", &[2; 20]).unwrap();
+        store.insert_batch(&[(b"c", b"3"), (b"a", b"4")]).unwrap();
+        assert_eq!((a, b), (64, 192));
+        for (_, position) in store.index.iter() {
+            assert_eq!(position % 64, 0);
+        }
+        drop(store);
+
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        assert_eq!(store.load().unwrap(), 4);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(store.iter().count(), 3);
+        assert_eq!(store.verify().unwrap().valid_records, 4);
+
+        let estimate = store.estimate_compaction().unwrap();
+        let reclaimed = store.compact(&dir.path().join("compacted")).unwrap();
+        assert_eq!(estimate.dead_bytes, reclaimed);
+        for (_, position) in store.index.iter() {
+            assert_eq!(position % 64, 0);
+        }
+        assert_eq!(store.get(b"b").unwrap(), Some(vec![2; 20]));
+    }
+
+    #[test]
+    fn compact_in_place_keeps_the_handle_usable() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.insert(b"b", b"3").unwrap();
+
+        assert!(store.compact_in_place().unwrap() > 0);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"3".to_vec()));
+        store.insert(b"c", b"4").unwrap();
+        assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+        assert!(!dir.path().join("store.akv.compact").exists());
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 3);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+        let mut bare = ActionKV::from_store(tempfile::tempfile().unwrap()).unwrap();
+        assert!(bare.compact_in_place().is_err());
+    }
+
+    #[test]
+    fn estimate_compaction_matches_what_compact_reclaims() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"22").unwrap();
+        store.insert(b"b", b"3").unwrap();
+        store.delete(b"b").unwrap();
+        store.insert_with_ttl(b"c", b"4", Duration::ZERO).unwrap();
+        store.cf("other").unwrap().insert(b"d", b"5").unwrap();
+
+        let estimate = store.estimate_compaction().unwrap();
+        assert_eq!(estimate.total_bytes, store.stats().unwrap().log_bytes);
+        assert_eq!(estimate.live_keys, 1);
+        let reclaimed = store.compact(&dir.path().join("compacted")).unwrap();
+        assert_eq!(estimate.dead_bytes, reclaimed);
+        assert_eq!(estimate.live_bytes, store.stats().unwrap().log_bytes);
+    }
+
+    #[test]
+    fn writes_compact_once_the_dead_ratio_is_crossed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            auto_compact_ratio: Some(0.5),
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options.clone()).unwrap();
+        for value in [b"1", b"2", b"3"] {
+            store.insert(b"a", value).unwrap();
+        }
+        assert_eq!(store.stats().unwrap().dead_bytes, 62);
+        store.insert(b"b", b"4").unwrap();
+        assert_eq!(store.stats().unwrap().dead_bytes, 0);
+        store.delete(b"b").unwrap();
+        drop(store);
+
+        // Until a load, the store can't tell dead records from live ones.
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        for value in [b"5", b"6", b"7"] {
+            store.insert(b"c", value).unwrap();
+        }
+        assert_eq!(store.load().unwrap(), 6);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        store.insert(b"a", b"8").unwrap();
+        assert_eq!(store.stats().unwrap().dead_bytes, 31);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"7".to_vec()));
+        assert!(!dir.path().join("store.akv.compact").exists());
+    }
+
+    #[test]
+    fn compare_and_swap_only_writes_on_a_match() {
+        let (_dir, mut store) = store();
+
+        assert!(store.compare_and_swap(b"lock", None, b"owner-1").unwrap());
+        assert!(!store.compare_and_swap(b"lock", None, b"owner-2").unwrap());
+        assert!(!store
+            .compare_and_swap(b"lock", Some(b"owner-2"), b"owner-3")
+            .unwrap());
+        assert!(store
+            .compare_and_swap(b"lock", Some(b"owner-1"), b"owner-2")
+            .unwrap());
+        assert_eq!(store.get(b"lock").unwrap(), Some(b"owner-2".to_vec()));
+        assert_eq!(store.stats().unwrap().dead_bytes, 29 + 4 + 7);
+    }
+
+    #[test]
+    fn snapshot_excludes_later_writes() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.delete(b"a").unwrap();
+        store.insert(b"b", b"2").unwrap();
+
+        let dest = dir.path().join("snapshot.akv");
+        let copied = store.snapshot(&dest).unwrap();
+        store.insert(b"c", b"3").unwrap();
+        assert_eq!(copied, fs::metadata(&dest).unwrap().len());
+        assert_eq!(copied, 6 + 31 + 30 + 31);
+
+        let mut snapshot = ActionKV::open(&dest).unwrap();
+        assert_eq!(snapshot.load().unwrap(), 3);
+        assert_eq!(snapshot.get(b"a").unwrap(), None);
+        assert_eq!(snapshot.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(snapshot.get(b"c").unwrap(), None);
+    }
+
+    #[test]
+    fn get_at_reads_records_by_offset() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        let first = store.insert_at(b"a", b"1").unwrap();
+        let second = store.insert_at(b"a", b"2").unwrap();
+        assert_eq!((first, second), (6, 37));
+
+        assert_eq!(store.get_at(first).unwrap().value, b"1");
+        assert_eq!(store.get_at(second).unwrap().value, b"2");
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+        for position in [0, 5, 68, 1000] {
+            let err = store.get_at(position).unwrap_err();
+            assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+        }
+        assert!(store.get_at(7).unwrap_err().is_damage());
+    }
+
+    #[test]
+    fn value_at_reads_any_record_without_the_index() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        let first = store.insert_at(b"a", b"1").unwrap();
+        let second = store.insert_at(b"a", b"2").unwrap();
+        let deleted = store.f.get_ref().len() as u64;
+        store.delete(b"a").unwrap();
+        let expired = store.f.get_ref().len() as u64;
+        store.insert_with_ttl(b"b", b"3", Duration::ZERO).unwrap();
+        let end = store.f.get_ref().len() as u64;
+
+        assert_eq!(store.value_at(first).unwrap(), b"1");
+        assert_eq!(store.value_at(second).unwrap(), b"2");
+        assert!(store.get_at(deleted).unwrap().value.is_empty());
+        assert!(store.value_at(deleted).unwrap().is_empty());
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.value_at(expired).unwrap(), b"3");
+
+        let err = store.value_at(end).unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+        store.f.get_mut()[second as usize + 30] ^= 0xff;
+        assert!(matches!(
+            store.value_at(second),
+            Err(Error::Corrupt { offset: 37, .. })
+        ));
+    }
+
+    #[test]
+    fn load_leaves_the_cursor_at_the_end() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.f.stream_position().unwrap(), 6 + 31 + 31);
+        store.insert(b"c", b"3").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 3);
+        for (key, value) in [(b"a", b"1"), (b"b", b"2"), (b"c", b"3")] {
+            assert_eq!(store.get(key).unwrap(), Some(value.to_vec()));
+        }
+        assert_eq!(store.f.stream_position().unwrap(), 6 + 3 * 31);
+    }
+
+    #[test]
+    fn interleaved_loads_reads_and_writes_keep_their_offsets() {
+        let (dir, mut store) = store();
+        let mut model = HashMap::new();
+        for i in 0..300u32 {
+            let key = (i % 17).to_be_bytes();
+            // Values of every size up to well past the read buffer, so the
+            // readers stop at all kinds of places.
+            let value = vec![i as u8; (i as usize * 97) % 12_000];
+            let end = store.f.byte_len().unwrap();
+            assert_eq!(store.insert_at(&key, &value).unwrap(), end);
+            model.insert(key.to_vec(), value);
+
+            if i % 7 == 0 {
+                store.load().unwrap();
+            }
+            let earlier = (i * 5 % 17).to_be_bytes();
+            assert_eq!(
+                store.get(&earlier).unwrap().as_ref(),
+                model.get(&earlier[..])
+            );
+            if i % 11 == 0 {
+                store.find(&[0xff]).unwrap();
+            }
+        }
+        assert_eq!(store.to_hashmap().unwrap(), model);
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 300);
+        assert_eq!(store.to_hashmap().unwrap(), model);
+        assert!(store.verify().unwrap().is_clean());
+    }
+
+    #[test]
+    fn bulk_paths_work_with_any_buffer_capacity() {
+        for capacity in [1, 7, 1 << 20] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("store.akv");
+            let options = Options {
+                buffer_capacity: Some(capacity),
+                ..Options::default()
+            };
+            let mut store = ActionKV::open_with_options(&path, options.clone()).unwrap();
+            store
+                .insert_batch(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")])
+                .unwrap();
+            drop(store);
+
+            let mut store = ActionKV::open_with_options(&path, options).unwrap();
+            assert_eq!(store.load().unwrap(), 3);
+            assert_eq!(store.find(b"3").unwrap(), Some((68, b"c".to_vec())));
+        }
+    }
+
+    /// Sequence numbers of every record, in file order.
+    fn sequences(store: &mut ActionKV) -> Vec<u64> {
+        let format = store.format;
+        let mut f = BufReader::new(&mut *store.f);
+        f.seek(SeekFrom::Start(format.data_start())).unwrap();
+        let mut sequences = Vec::new();
+        loop {
+            let offset = f.stream_position().unwrap();
+            match process_record(&mut f, format, offset, true) {
+                Ok(record) => sequences.push(record.sequence),
+                Err(Error::Truncated { .. }) => return sequences,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_increase_through_the_log() {
+        let (dir, mut store) = store();
+        assert_eq!(store.sequence(), 0);
+        store.insert(b"a", b"1").unwrap();
+        store.insert_batch(&[(b"b", b"2"), (b"c", b"3")]).unwrap();
+        store.delete(b"a").unwrap();
+        store
+            .insert_with_ttl(b"d", b"4", Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(store.sequence(), 5);
+        assert_eq!(sequences(&mut store), [1, 2, 3, 4, 5]);
+
+        let sidecar = dir.path().join("store.idx");
+        store.save_index(&sidecar).unwrap();
+        store.insert(b"e", b"5").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.sequence(), 6);
+        drop(store);
+        let mut store = reopen(&dir);
+        assert!(store.load_index(&sidecar).unwrap());
+        assert_eq!(store.sequence(), 6);
+
+        store.compact(&dir.path().join("store.compact")).unwrap();
+        assert_eq!(sequences(&mut store), [2, 3, 5, 6]);
+        store.insert(b"f", b"6").unwrap();
+        assert_eq!(store.sequence(), 7);
+    }
+
+    #[test]
+    fn logs_without_sequence_numbers_stay_at_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut v4 = Format::CURRENT;
+        v4.version = 4;
+        let mut log = v4.header();
+        write_record(&mut log, v4, b"a", b"1", 0, 0, Extras::default()).unwrap();
+        fs::write(&path, log).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"b", b"2").unwrap();
+        assert_eq!(store.sequence(), 0);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn raw_records_yields_the_whole_history_in_order() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"a", b"3").unwrap();
+        store.delete(b"b").unwrap();
+
+        let entry = |key: &[u8], value: &[u8], deleted, sequence| LogEntry {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            deleted,
+            sequence,
+            expires_at: None,
+        };
+        let records: Vec<(u64, LogEntry)> = store.raw_records().map(Result::unwrap).collect();
+        assert_eq!(
+            records,
+            [
+                (6, entry(b"a", b"1", false, 1)),
+                (37, entry(b"b", b"2", false, 2)),
+                (68, entry(b"a", b"3", false, 3)),
+                (99, entry(b"b", b"", true, 4)),
+            ]
+        );
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn read_from_replays_every_write_and_resumes() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.delete(b"a").unwrap();
+        store
+            .insert_with_ttl(b"c", b"3", Duration::from_secs(60))
+            .unwrap();
+
+        let entries: Vec<(u64, LogEntry)> = store.read_from(0).map(Result::unwrap).collect();
+        let offsets: Vec<u64> = entries.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, [6, 37, 68, 98]);
+        assert!(entries[2].1.deleted);
+        assert_eq!(entries[2].1.key, b"a");
+        assert_eq!(entries[3].1.sequence, 4);
+        assert!(entries[3].1.expires_at.is_some());
+        assert_eq!(entries[1].1.expires_at, None);
+
+        let resumed: Vec<u64> = store.read_from(68).map(|e| e.unwrap().0).collect();
+        assert_eq!(resumed, [68, 98]);
+        let end = store.f.get_ref().len() as u64;
+        assert_eq!(store.read_from(end).count(), 0);
+
+        let mut misaligned = store.read_from(7);
+        assert!(misaligned.next().unwrap().unwrap_err().is_damage());
+        assert!(misaligned.next().is_none());
+        drop(misaligned);
+
+        *store.f.get_mut().last_mut().unwrap() ^= 0xff;
+        let results: Vec<_> = store.read_from(0).collect();
+        assert_eq!(results.len(), 4);
+        assert!(matches!(results[3], Err(Error::Corrupt { offset: 98, .. })));
+    }
+
+    #[test]
+    fn scan_reads_the_records_that_start_in_a_range() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"a", b"3").unwrap();
+        store.delete(b"b").unwrap();
+
+        fn scanned(
+            store: &ActionKV<Cursor<Vec<u8>>>,
+            start: u64,
+            end: u64,
+        ) -> Vec<(u64, ByteString, ByteString)> {
+            store
+                .scan(start, end)
+                .map(|entry| entry.map(|(position, kv)| (position, kv.key, kv.value)))
+                .collect::<Result<_>>()
+                .unwrap()
+        }
+        assert_eq!(
+            scanned(&store, 37, 99),
+            [
+                (37, b"b".to_vec(), b"2".to_vec()),
+                (68, b"a".to_vec(), b"3".to_vec())
+            ]
+        );
+        // Ending partway through the record at 68 still yields it whole.
+        assert_eq!(scanned(&store, 6, 69).len(), 3);
+        assert_eq!(scanned(&store, 6, 68).len(), 2);
+        assert_eq!(
+            scanned(&store, 99, u64::MAX),
+            [(99, b"b".to_vec(), Vec::new())]
+        );
+        assert!(scanned(&store, 37, 37).is_empty());
+
+        // Damage after the range doesn't reach the caller.
+        *store.f.get_mut().last_mut().unwrap() ^= 0xff;
+        assert_eq!(scanned(&store, 6, 99).len(), 3);
+        let results: Vec<_> = store.scan(6, u64::MAX).collect();
+        assert!(matches!(results[3], Err(Error::Corrupt { offset: 99, .. })));
+    }
+
+    #[test]
+    fn empty_keys_can_be_rejected() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"", b"allowed").unwrap();
+        assert_eq!(store.get(b"").unwrap(), Some(b"allowed".to_vec()));
+
+        let options = Options {
+            reject_empty_keys: true,
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap();
+        let err = store.insert(b"", b"1").unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+        let err = store
+            .insert_batch(&[(b"a", b"1"), (b"", b"2")])
+            .unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+        assert_eq!(store.stats().unwrap().log_bytes, 6);
+
+        store.insert(b"a", b"").unwrap();
+        let bytes = store.into_inner().into_inner();
+        let mut store = ActionKV::from_store(Cursor::new(bytes)).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn values_over_the_limit_are_refused_before_writing() {
+        let options = Options {
+            max_value_bytes: Some(4),
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap();
+        store.insert(b"a", b"1234").unwrap();
+
+        let err = store.insert(b"b", b"12345").unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+        let err = store
+            .insert_batch(&[(b"c", b"1"), (b"d", b"12345")])
+            .unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::InvalidInput);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.stats().unwrap().log_bytes, 6 + 34);
+    }
+
+    #[test]
+    fn writes_stop_once_the_log_reaches_its_limit() {
+        for (max, inserts) in [(37, 1), (38, 2)] {
+            let options = Options {
+                max_total_bytes: Some(max),
+                ..Options::default()
+            };
+            let mut store =
+                ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap();
+            // Each record is 31 bytes, after a 6 byte header.
+            store.insert(b"a", b"1").unwrap();
+            if inserts == 2 {
+                store.insert(b"b", b"2").unwrap();
+            }
+
+            let err = store.insert(b"c", b"3").unwrap_err();
+            assert_eq!(io_kind(err), io::ErrorKind::StorageFull);
+            let err = store.delete(b"a").unwrap_err();
+            assert_eq!(io_kind(err), io::ErrorKind::StorageFull);
+            assert_eq!(store.len(), inserts);
+        }
+    }
+
+    #[test]
+    fn batched_sync_policy_commits_in_groups() {
+        let options = Options {
+            sync_policy: SyncPolicy::Batched {
+                writes: 3,
+                interval: Duration::from_secs(3600),
+            },
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap();
+
+        store.insert(b"a", b"1").unwrap();
+        store.delete(b"a").unwrap();
+        assert_eq!(store.unsynced, 2);
+        store.insert(b"b", b"2").unwrap();
+        assert_eq!(store.unsynced, 0);
+
+        store.insert_batch(&[(b"c", b"3"), (b"d", b"4")]).unwrap();
+        assert_eq!(store.unsynced, 2);
+        store.flush().unwrap();
+        assert_eq!(store.unsynced, 0);
+
+        store.options.sync_policy = SyncPolicy::Batched {
+            writes: 100,
+            interval: Duration::ZERO,
+        };
+        store.insert(b"e", b"5").unwrap();
+        assert_eq!(store.unsynced, 0);
+    }
+
+    #[test]
+    fn sync_policy_always_leaves_nothing_pending() {
+        let options = Options {
+            sync_policy: SyncPolicy::Always,
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap();
+        let before = store.last_sync;
+        store.insert(b"a", b"1").unwrap();
+        assert!(store.last_sync > before);
+        assert_eq!(store.unsynced, 0);
+    }
+
+    #[test]
+    fn sync_policy_always_puts_each_write_in_the_file_before_returning() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            sync_policy: SyncPolicy::Always,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+
+        store.insert(b"a", b"1").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 6 + 31);
+        store.update(b"a", b"2").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 6 + 31 * 2);
+        store.delete(b"a").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 6 + 31 * 2 + 30);
+        assert_eq!(store.unsynced, 0);
+    }
+
+    #[test]
+    fn sync_policy_always_syncs_once_per_write() {
+        let options = Options {
+            sync_policy: SyncPolicy::Always,
+            ..Options::default()
+        };
+        let f = SyncCounter::default();
+        let syncs = f.syncs.clone();
+        let mut store = ActionKV::from_store_with_options(f, options).unwrap();
+        let syncs = || syncs.load(std::sync::atomic::Ordering::SeqCst);
+
+        store.insert(b"a", b"1").unwrap();
+        assert_eq!(syncs(), 1);
+        store.update(b"a", b"2").unwrap();
+        assert_eq!(syncs(), 2);
+        store.delete(b"a").unwrap();
+        assert_eq!(syncs(), 3);
+        drop(store);
+        assert_eq!(syncs(), 3);
+    }
+
+    #[test]
+    fn get_or_insert_with_only_computes_missing_values() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store
+            .insert_with_ttl(b"b", b"stale", Duration::ZERO)
+            .unwrap();
+
+        let value = store
+            .get_or_insert_with(b"a", || panic!("a exists"))
+            .unwrap();
+        assert_eq!(value, b"1");
+        assert_eq!(
+            store.get_or_insert_with(b"b", || b"2".to_vec()).unwrap(),
+            b"2"
+        );
+        assert_eq!(
+            store.get_or_insert_with(b"c", || b"3".to_vec()).unwrap(),
+            b"3"
+        );
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.sequence(), 4);
+    }
+
+    #[test]
+    fn lookups_can_skip_checksums_but_compaction_cannot() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        drop(store);
+        corrupt_last_byte(&dir);
+
+        let options = Options {
+            verify_checksums: false,
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::open_with_options(&dir.path().join("store.akv"), options).unwrap();
+        // Scans verify whatever the option says.
+        assert!(matches!(
+            store.load(),
+            Err(Error::Corrupt { offset: 6, .. })
+        ));
+
+        store.index.insert(b"a".to_vec(), 6);
+        assert_eq!(store.get(b"a").unwrap(), Some(vec![b'1' ^ 0xff]));
+        let err = store
+            .compact(&dir.path().join("store.compact"))
+            .unwrap_err();
+        assert!(matches!(err, Error::Corrupt { offset: 6, .. }));
+    }
+
+    #[test]
+    fn clear_empties_the_log_and_the_index() {
+        let (dir, mut store) = store();
+        let path = dir.path().join("store.akv");
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+
+        store.clear().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert!(store.is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 6);
+
+        store.insert(b"c", b"3").unwrap();
+        assert_eq!(store.index.get(b"c"), Some(&6));
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 1);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn truncate_at_rolls_back_to_a_record_boundary() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        let checkpoint = store.insert_at(b"c", b"3").unwrap();
+        store.insert(b"a", b"4").unwrap();
+
+        let err = store.truncate_at(checkpoint + 1).unwrap_err();
+        assert!(matches!(err, Error::Io(ref err) if err.kind() == io::ErrorKind::InvalidInput));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        store.truncate_at(checkpoint).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(store.insert_at(b"d", b"5").unwrap(), checkpoint);
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 3);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"5".to_vec()));
+    }
+
+    #[test]
+    fn get_works_through_a_shared_reference() {
+        let (_dir, mut store) = store();
+        for i in 0..100u32 {
+            store.insert(&i.to_be_bytes(), &[i as u8; 300]).unwrap();
+        }
+
+        let store = &store;
+        std::thread::scope(|s| {
+            for t in 0..4u32 {
+                s.spawn(move || {
+                    for i in (t..100).step_by(4) {
+                        let value = store.get(&i.to_be_bytes()).unwrap();
+                        assert_eq!(value, Some(vec![i as u8; 300]));
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn get_exclusive_still_works() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        assert_eq!(store.get_exclusive(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn contains_key_and_value_len() {
+        let (_dir, mut store) = store();
+        store.insert(b"a", b"12345").unwrap();
+        store.insert(b"b", b"").unwrap();
+        store.insert(b"c", b"1").unwrap();
+        store.delete(b"c").unwrap();
+
+        assert!(store.contains_key(b"a"));
+        assert!(store.contains_key(b"b"));
+        assert!(!store.contains_key(b"c"));
+        assert_eq!(store.value_len(b"a").unwrap(), Some(5));
+        assert_eq!(store.value_len(b"b").unwrap(), Some(0));
+        assert_eq!(store.value_len(b"c").unwrap(), None);
+        assert_eq!(store.stored_len(b"a").unwrap(), Some(5));
+
+        store.insert_with_ttl(b"d", b"1", Duration::ZERO).unwrap();
+        assert_eq!(store.get(b"d").unwrap(), None);
+        assert_eq!(store.value_len(b"d").unwrap(), None);
+        assert_eq!(store.stored_len(b"d").unwrap(), None);
+    }
+
+    #[test]
+    fn get_with_metadata_reports_the_stored_record() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"bb", b"value").unwrap();
+        store.delete(b"a").unwrap();
+
+        let (value, meta) = store.get_with_metadata(b"bb").unwrap().unwrap();
+        assert_eq!(value, b"value");
+        let saved = Format::CURRENT
+            .read_checksum(&mut &store.f.get_ref()[37..])
+            .unwrap();
+        assert_eq!(
+            meta,
+            RecordMeta {
+                offset: 37,
+                checksum: saved,
+                key_len: 2,
+                value_len: 5,
+            }
+        );
+        assert!(store.get_with_metadata(b"a").unwrap().is_none());
+    }
+
+    #[test]
+    fn load_counts_records() {
+        let (dir, mut store) = store();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        drop(store);
+
+        let mut store = reopen(&dir);
+        assert_eq!(store.load().unwrap(), 2);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn an_index_of_hashed_keys_finds_every_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            index: IndexKind::HashedKeys,
+            ..Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&path, options.clone()).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"a", b"3").unwrap();
+        store.delete(b"b").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.iter().next().unwrap().unwrap().key, b"a");
+        assert_eq!(store.keys().count(), 0);
+        let err = store.save_index(&dir.path().join("index")).unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::Unsupported));
+        let err = store.filter_keys(|_| true).unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::Unsupported));
+        let err = store.export_jsonl(&mut Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::Unsupported));
+        let err = store.delete_prefix(b"a").unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::Unsupported));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn an_index_of_hashed_keys_never_hands_out_another_keys_record() {
+        let options = Options {
+            index: IndexKind::HashedKeys,
+            skip_identical_writes: true,
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap();
+        let position = store.insert_at(b"a", b"1").unwrap();
+        // Points "b" at the record of "a", as colliding hashes would.
+        store.index.insert(b"b".to_vec(), position);
+
+        assert!(store.contains_key(b"a"));
+        assert!(!store.contains_key(b"b"));
+        assert_eq!(store.value_len(b"a").unwrap(), Some(1));
+        assert_eq!(store.value_len(b"b").unwrap(), None);
+        assert!(!store.rename(b"b", b"c").unwrap());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+
+        let len = store.f.get_ref().len();
+        store.insert(b"b", b"1").unwrap();
+        assert!(store.f.get_ref().len() > len);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn index_capacity_is_reserved_and_shrunk_on_request() {
+        fn capacity(store: &ActionKV) -> usize {
+            match &store.index {
+                Index::Hash(map) => map.capacity(),
+                _ => unreachable!(),
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open_with_index_capacity(&path, 10_000).unwrap();
+        assert!(capacity(&store) >= 10_000);
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.load().unwrap();
+        assert!(capacity(&store) >= 10_000);
+
+        store.shrink_index();
+        assert!(capacity(&store) < 10_000);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_values_read_back_without_compression_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let options = Options {
+            compression: Some(Compression::default()),
+            ..Options::default()
+        };
+        let value = b"{\"name\": \"actionkv\"} ".repeat(50);
+
+        let mut store = ActionKV::open_with_options(&path, options).unwrap();
+        store.insert(b"json", &value).unwrap();
+        store.insert(b"tiny", b"1").unwrap();
+        assert_eq!(store.value_len(b"json").unwrap(), Some(value.len() as u64));
+        assert!(store.stored_len(b"json").unwrap().unwrap() < value.len() as u64);
+        drop(store);
+
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(store.get(b"json").unwrap(), Some(value));
+        assert_eq!(store.get(b"tiny").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn checksum_covers_the_compressed_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            compression: Some(Compression::default()),
+            ..Options::default()
+        };
+        let mut store =
+            ActionKV::open_with_options(&dir.path().join("store.akv"), options).unwrap();
+        store.insert(b"key", &b"abc".repeat(100)).unwrap();
+        drop(store);
+        corrupt_last_byte(&dir);
+
+        let err = reopen(&dir).load().unwrap_err();
+        assert!(matches!(err, Error::Corrupt { .. }));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_values_need_their_key_to_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let with_key = |key| Options {
+            encryption: Some(EncryptionKey::new([key; 32])),
+            ..Options::default()
+        };
+
+        let mut store = ActionKV::open_with_options(&path, with_key(1)).unwrap();
+        store.insert(b"card", b"4111 1111 1111 1111").unwrap();
+        store.insert(b"gone", b"x").unwrap();
+        store.delete(b"gone").unwrap();
+        store.compact(&dir.path().join("store.compact")).unwrap();
+        drop(store);
+        let bytes = fs::read(&path).unwrap();
+        assert!(!bytes.windows(4).any(|window| window == b"4111"));
+
+        let mut store = ActionKV::open_with_options(&path, with_key(1)).unwrap();
+        assert_eq!(store.load().unwrap(), 1);
+        assert_eq!(
+            store.get(b"card").unwrap(),
+            Some(b"4111 1111 1111 1111".to_vec())
+        );
+        drop(store);
+
+        let mut store = ActionKV::open_with_options(&path, with_key(2)).unwrap();
+        store.load().unwrap();
+        assert_eq!(
+            io_kind(store.get(b"card").unwrap_err()),
+            io::ErrorKind::InvalidData
+        );
+        drop(store);
+        let mut store = reopen(&dir);
+        store.load().unwrap();
+        assert_eq!(
+            io_kind(store.get(b"card").unwrap_err()),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(store.verify().unwrap().is_clean());
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compressed_record_needs_the_feature() {
+        let mut log = Format::CURRENT.header();
+        write_record(
+            &mut log,
+            Format::CURRENT,
+            b"k",
+            b"v",
+            FLAG_COMPRESSED,
+            0,
+            Extras::default(),
+        )
+        .unwrap();
+
+        let mut store = ActionKV::from_store(Cursor::new(log)).unwrap();
+        store.load().unwrap();
+        let err = store.get(b"k").unwrap_err();
+        assert_eq!(io_kind(err), io::ErrorKind::Unsupported);
+    }
+}
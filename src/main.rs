@@ -0,0 +1,101 @@
+use std::env;
+use std::path::Path;
+use std::process;
+
+use libactionkv::ActionKV;
+
+const USAGE: &str = "
+Usage:
+    actionkv FILE get KEY
+    actionkv FILE delete KEY
+    actionkv FILE insert KEY VALUE
+    actionkv FILE update KEY VALUE
+    actionkv FILE list
+    actionkv FILE verify
+";
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let fname = args.get(1).expect(USAGE);
+    let action = args.get(2).expect(USAGE).as_ref();
+    let maybe_key = args.get(3);
+    let maybe_value = args.get(4);
+
+    let key = || maybe_key.expect(USAGE).as_bytes();
+    let value = || maybe_value.expect(USAGE).as_bytes();
+
+    let result = ActionKV::open(Path::new(fname)).and_then(|mut store| {
+        // Verifying has to read past damage that would fail the load.
+        if action != "verify" {
+            store.load()?;
+        }
+
+        match action {
+            "get" => match store.get(key())? {
+                None => {
+                    eprintln!("{} not found", display(key()));
+                    process::exit(1);
+                }
+                Some(value) => println!("{}", display(&value)),
+            },
+            "delete" => store.delete(key())?,
+            "insert" => store.insert(key(), value())?,
+            "update" => store.update(key(), value())?,
+            "list" => {
+                for kv in store.iter() {
+                    let kv = kv?;
+                    println!("{}\t{}", display(&kv.key), display(&kv.value));
+                }
+            }
+            "verify" => {
+                let report = store.verify()?;
+                for damage in &report.damage {
+                    println!("{}", damage);
+                }
+                println!(
+                    "{} valid records, {} damaged bytes",
+                    report.valid_records, report.damaged_bytes
+                );
+                if !report.is_clean() {
+                    process::exit(1);
+                }
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+
+        store.flush()
+    });
+
+    if let Err(err) = result {
+        eprintln!("actionkv: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Renders bytes as UTF-8, writing any bytes that aren't valid UTF-8 as
+/// `\xNN` escapes.
+fn display(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        out.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_escapes_invalid_utf8() {
+        assert_eq!(display(b"plain"), "plain");
+        assert_eq!(display("café".as_bytes()), "café");
+        assert_eq!(display(b"a\xffb\xc3"), "a\\xffb\\xc3");
+    }
+}
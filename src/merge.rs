@@ -0,0 +1,226 @@
+//! Folding the live contents of one store into another.
+
+use std::io;
+
+use crate::{now_millis, ActionKV, Extras, Record, Result, Storage};
+
+/// What [`merge_from`](ActionKV::merge_from) does with a key that both
+/// stores hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Leaves the value already in the store alone.
+    KeepExisting,
+    /// Replaces it with the value from the other store.
+    Overwrite,
+    /// Keeps whichever record was written later, the existing one on a
+    /// tie. Where both records carry the time they were written at, as
+    /// [`Options::record_write_times`](crate::Options::record_write_times)
+    /// has them do, those times are compared.
+    ///
+    /// Otherwise it falls back to sequence numbers, and each store counts
+    /// its own from 1, so those only say which record is newer when the two
+    /// logs share a history, such as a copy of the store merged back into
+    /// it. Between unrelated stores, the one that has taken more writes
+    /// wins, however long ago they were. Records of formats without
+    /// sequence numbers count as 0, so against those the existing value
+    /// always stays.
+    NewerSequence,
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Inserts every live pair of the default column family of `other`,
+    /// deciding by `policy` for keys this store already holds, and returns
+    /// how many were written. Pairs that expire keep their expiry time.
+    ///
+    /// The pairs are written one at a time, so an error leaves the ones
+    /// before it in the store.
+    pub fn merge_from<T: Storage>(
+        &mut self,
+        other: &ActionKV<T>,
+        policy: ConflictPolicy,
+    ) -> Result<usize> {
+        self.check_indexed()?;
+        other.check_indexed()?;
+
+        let now = now_millis();
+        let mut written = 0;
+        for position in other.index.values() {
+            let record = other.record_at(*position, other.options.verify_checksums)?;
+            if record.is_expired(now) || !self.takes(&record, policy, now)? {
+                continue;
+            }
+
+            if record.expires_at != 0 && !self.format.has_flags() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "legacy logs cannot store expiry times",
+                )
+                .into());
+            }
+            let extras = Extras {
+                expires_at: record.expires_at,
+                ..Extras::default()
+            };
+            let position = self.append(&record.key, &record.value, 0, extras)?;
+
+            self.index.insert(record.key.clone(), position);
+            self.notify(&record.key, Some(&record.value));
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Whether `incoming` should replace what this store holds for its key
+    /// under `policy`. A key with no live record always takes it.
+    fn takes(&self, incoming: &Record, policy: ConflictPolicy, now: u64) -> Result<bool> {
+        let position = match self.index.get(&incoming.key) {
+            None => return Ok(true),
+            Some(position) => *position,
+        };
+        let existing = self.stored_record_at(position, self.options.verify_checksums)?;
+        // An index of hashed keys can point at another key's record.
+        if existing.is_expired(now) || existing.key != incoming.key {
+            return Ok(true);
+        }
+
+        Ok(match policy {
+            ConflictPolicy::KeepExisting => false,
+            ConflictPolicy::Overwrite => true,
+            ConflictPolicy::NewerSequence => {
+                if incoming.written_at != 0 && existing.written_at != 0 {
+                    incoming.written_at > existing.written_at
+                } else {
+                    incoming.sequence > existing.sequence
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{ByteStr, Options};
+
+    fn empty() -> ActionKV<Cursor<Vec<u8>>> {
+        ActionKV::from_store(Cursor::new(Vec::new())).unwrap()
+    }
+
+    fn with(pairs: &[(&ByteStr, &ByteStr)]) -> ActionKV<Cursor<Vec<u8>>> {
+        let mut store = empty();
+        for (key, value) in pairs {
+            store.insert(key, value).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn keys_only_in_the_other_store_are_always_written() {
+        for policy in [
+            ConflictPolicy::KeepExisting,
+            ConflictPolicy::Overwrite,
+            ConflictPolicy::NewerSequence,
+        ] {
+            let mut store = with(&[(b"a", b"1")]);
+            let mut other = with(&[(b"b", b"2"), (b"c", b"3")]);
+            other.delete(b"c").unwrap();
+
+            assert_eq!(store.merge_from(&other, policy).unwrap(), 1);
+            assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(store.get(b"c").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn keep_existing_leaves_shared_keys_alone() {
+        let mut store = with(&[(b"a", b"mine")]);
+        let other = with(&[(b"a", b"theirs"), (b"b", b"2")]);
+
+        assert_eq!(
+            store
+                .merge_from(&other, ConflictPolicy::KeepExisting)
+                .unwrap(),
+            1
+        );
+        assert_eq!(store.get(b"a").unwrap(), Some(b"mine".to_vec()));
+    }
+
+    #[test]
+    fn overwrite_replaces_shared_keys() {
+        let mut store = with(&[(b"a", b"mine")]);
+        let other = with(&[(b"a", b"theirs"), (b"b", b"2")]);
+
+        assert_eq!(
+            store.merge_from(&other, ConflictPolicy::Overwrite).unwrap(),
+            2
+        );
+        assert_eq!(store.get(b"a").unwrap(), Some(b"theirs".to_vec()));
+    }
+
+    #[test]
+    fn newer_sequence_keeps_the_later_write() {
+        let mut store = with(&[(b"a", b"mine"), (b"b", b"mine")]);
+        let other = with(&[
+            (b"x", b"0"),
+            (b"y", b"0"),
+            (b"a", b"theirs"),
+            (b"b", b"theirs"),
+        ]);
+        // "a" here stays at sequence 1, behind the 3 of "a" there, while "b"
+        // moves on to 4, level with "b" there, and a tie keeps this one.
+        store.insert(b"b", b"mine again").unwrap();
+        store.insert(b"b", b"newest").unwrap();
+
+        assert_eq!(
+            store
+                .merge_from(&other, ConflictPolicy::NewerSequence)
+                .unwrap(),
+            3
+        );
+        assert_eq!(store.get(b"a").unwrap(), Some(b"theirs".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"newest".to_vec()));
+    }
+
+    #[test]
+    fn newer_sequence_compares_write_times_where_both_records_have_them() {
+        let timed = || {
+            let options = Options {
+                record_write_times: true,
+                ..Options::default()
+            };
+            ActionKV::from_store_with_options(Cursor::new(Vec::new()), options).unwrap()
+        };
+        // Far ahead in sequence numbers, but written first.
+        let mut other = timed();
+        for _ in 0..5 {
+            other.insert(b"a", b"older").unwrap();
+        }
+        thread::sleep(Duration::from_millis(5));
+        let mut store = timed();
+        store.insert(b"a", b"newer").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let mut later = timed();
+        later.insert(b"a", b"newest").unwrap();
+
+        assert_eq!(
+            store
+                .merge_from(&other, ConflictPolicy::NewerSequence)
+                .unwrap(),
+            0
+        );
+        assert_eq!(store.get(b"a").unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(
+            store
+                .merge_from(&later, ConflictPolicy::NewerSequence)
+                .unwrap(),
+            1
+        );
+        assert_eq!(store.get(b"a").unwrap(), Some(b"newest".to_vec()));
+    }
+}
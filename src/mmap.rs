@@ -0,0 +1,174 @@
+//! Memory-mapped reads, behind the `mmap` feature.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::{lock, ActionKV, Options, Result, Storage};
+
+/// A log file whose reads are served from a memory mapping.
+///
+/// Writes go to the file as usual. The mapping is refreshed whenever the file
+/// is flushed or its length is set, and reads past the end of a stale
+/// mapping fall back to the file.
+///
+/// Mapping a file is only sound while nothing else changes it, which the
+/// exclusive lock taken by [`ActionKV::open_mmap`] ensures for cooperating
+/// processes. Modifying a mapped log by other means is undefined behavior.
+#[derive(Debug)]
+pub struct MmapFile {
+    f: File,
+    map: Option<Mmap>,
+}
+
+impl MmapFile {
+    fn new(f: File) -> io::Result<Self> {
+        let map = Some(map(&f)?);
+        Ok(MmapFile { f, map })
+    }
+
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = Some(map(&self.f)?);
+        Ok(())
+    }
+}
+
+fn map(f: &File) -> io::Result<Mmap> {
+    // Safety: see the type's documentation. The store holds an exclusive
+    // lock on the file, and only shrinks it after dropping the mapping.
+    unsafe { Mmap::map(f) }
+}
+
+impl Read for MmapFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.f.read(buf)
+    }
+}
+
+impl Write for MmapFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.f.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.f.flush()?;
+        if self.f.metadata()?.len() != self.mapped().map_or(0, |map| map.len()) as u64 {
+            self.remap()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for MmapFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.f.seek(pos)
+    }
+}
+
+impl Storage for MmapFile {
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        match self.mapped().and_then(|map| map.get(position as usize..)) {
+            Some(rest) if rest.len() >= buf.len() => {
+                buf.copy_from_slice(&rest[..buf.len()]);
+                Ok(buf.len())
+            }
+            _ => self.f.read_at(buf, position),
+        }
+    }
+
+    fn mapped(&self) -> Option<&[u8]> {
+        self.map.as_deref()
+    }
+
+    fn byte_len(&self) -> io::Result<u64> {
+        self.f.byte_len()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        // Touching a mapping past the end of its file is a fault, so the
+        // mapping goes before the file shrinks.
+        self.map = None;
+        File::set_len(&self.f, len)?;
+        self.remap()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.f.sync_all()
+    }
+
+    fn open_segment(path: &Path) -> io::Result<Self> {
+        MmapFile::new(ActionKV::open_file(path)?)
+    }
+}
+
+impl ActionKV<MmapFile> {
+    /// Opens the store at `path` like [`open`](ActionKV::open), serving
+    /// reads from a memory mapping of the log.
+    pub fn open_mmap(path: &Path) -> Result<Self> {
+        ActionKV::open_mmap_with_options(path, Options::default())
+    }
+
+    pub fn open_mmap_with_options(path: &Path, options: Options) -> Result<Self> {
+        let f = ActionKV::open_file(path)?;
+        lock(&f, path, false)?;
+        let mut store = ActionKV::from_store_with_options(MmapFile::new(f)?, options)?;
+        store.path = Some(path.to_path_buf());
+        store.open_segments(MmapFile::open_segment)?;
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn reads_follow_the_growing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open_mmap(&path).unwrap();
+
+        for i in 0..100u32 {
+            store.insert(&i.to_le_bytes(), &[i as u8; 300]).unwrap();
+            assert_eq!(
+                store.f.mapped().unwrap().len() as u64,
+                fs::metadata(&path).unwrap().len()
+            );
+            assert_eq!(
+                store.get(&i.to_le_bytes()).unwrap(),
+                Some(vec![i as u8; 300])
+            );
+        }
+        store.delete(&7u32.to_le_bytes()).unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open_mmap(&path).unwrap();
+        assert_eq!(store.load().unwrap(), 101);
+        assert_eq!(store.get(&7u32.to_le_bytes()).unwrap(), None);
+        assert_eq!(
+            store.get(&99u32.to_le_bytes()).unwrap(),
+            Some(vec![99; 300])
+        );
+    }
+
+    #[test]
+    fn truncation_remaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = MmapFile::new(ActionKV::open_file(&dir.path().join("log")).unwrap()).unwrap();
+        f.write_all(b"hello world").unwrap();
+        f.flush().unwrap();
+        assert_eq!(f.mapped(), Some(&b"hello world"[..]));
+
+        Storage::set_len(&mut f, 5).unwrap();
+        assert_eq!(f.mapped(), Some(&b"hello"[..]));
+        let mut buf = [0; 5];
+        assert_eq!(f.read_at(&mut buf, 0).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -0,0 +1,270 @@
+//! Settings for opening a store.
+
+use std::time::Duration;
+
+use crate::checksum::ChecksumAlgorithm;
+#[cfg(feature = "compression")]
+use crate::compression::Compression;
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
+use crate::format::Endianness;
+use crate::index::IndexKind;
+
+/// Settings for [`ActionKV::open_with_options`](crate::ActionKV::open_with_options).
+///
+/// Settings describing the layout of the log only apply when the log is
+/// created. An existing log keeps the layout recorded in its header.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Byte order of a new log. Defaults to little endian.
+    pub endianness: Endianness,
+    /// Record checksum of a new log. Defaults to CRC-32.
+    pub checksum: ChecksumAlgorithm,
+    /// How the in-memory index is organised. Defaults to a hash map.
+    pub index: IndexKind,
+    /// Number of keys the index of the default column family has room for
+    /// before it first grows. Defaults to 0. Sizing a hash index for the
+    /// keys a [`load`](crate::ActionKV::load) will find saves rehashing it
+    /// over and over as it grows, but the room is taken up front whether or
+    /// not the keys arrive; [`shrink_index`](crate::ActionKV::shrink_index)
+    /// gives back what went unused. A sorted index grows node by node and
+    /// ignores this.
+    pub index_capacity: usize,
+    /// Whether the store keeps an index of its keys. Defaults to on.
+    /// Turning it off suits a pure append-only log, such as a write-ahead
+    /// log, that is written and scanned but never looked up by key: the
+    /// index takes memory for every key, and nothing else does.
+    ///
+    /// Without an index, [`load`](crate::ActionKV::load) still reads and
+    /// verifies every record, but keeps none of them, and writes are
+    /// appended without being indexed. Lookups by key, such as
+    /// [`get`](crate::ActionKV::get), fail with `Unsupported`, and so do
+    /// [`stats`](crate::ActionKV::stats), compaction, merging, saving the
+    /// index and column families, which all need to know which records are
+    /// live. What walks the index, such as [`iter`](crate::ActionKV::iter)
+    /// and [`keys`](crate::ActionKV::keys), finds nothing, and
+    /// [`len`](crate::ActionKV::len) is 0.
+    /// [`read_from`](crate::ActionKV::read_from) and
+    /// [`find`](crate::ActionKV::find) scan the log instead, and work as
+    /// ever. [`Options::auto_compact_ratio`] is ignored.
+    pub build_index: bool,
+    /// Whether [`load`](crate::ActionKV::load) truncates a torn record at
+    /// the end of the log rather than failing. Defaults to off.
+    pub recover_torn_tail: bool,
+    /// Whether [`load`](crate::ActionKV::load) takes zero bytes that run
+    /// from where a record should start to the end of a segment for space
+    /// preallocated past the records, rather than for records. Defaults to
+    /// on. The scan ends there, and a handle that can write cuts the zeros
+    /// off the active segment so new records follow the last one, counting
+    /// them in [`discarded_bytes`](crate::ActionKV::discarded_bytes).
+    ///
+    /// A zeroed record can't pass its checksum in the current format, so
+    /// without this such a log fails to load. In a legacy log, though, eight
+    /// zero bytes are a valid record of an empty key and empty value, which
+    /// load would otherwise index; turn this off for a legacy log that
+    /// really ends in those.
+    pub ignore_zeroed_tail: bool,
+    /// What [`load`](crate::ActionKV::load) does about a damaged record.
+    /// Defaults to [`CorruptionPolicy::Abort`].
+    pub on_corruption: CorruptionPolicy,
+    /// When writes are forced onto the disk. Defaults to
+    /// [`SyncPolicy::Manual`].
+    pub sync_policy: SyncPolicy,
+    /// Size at which the active segment of the log is sealed and writes move
+    /// on to a new segment file, named after the log with `.1`, `.2` and so
+    /// on appended. Defaults to `None`, one file that grows forever.
+    ///
+    /// A segment is sealed after the write that reaches the limit, so it can
+    /// overshoot by one record or batch. Existing segments are read whatever
+    /// this is set to. Only stores opened by path can be segmented.
+    pub max_segment_bytes: Option<u64>,
+    /// Whether lookups check each record they read against its checksum.
+    /// Defaults to on. Turning it off saves hashing every value read, and
+    /// is only safe for logs that are trusted, such as one this process
+    /// just wrote. [`load`](crate::ActionKV::load) and the other full scans
+    /// always verify, since that is how they find torn and corrupt records.
+    pub verify_checksums: bool,
+    /// Whether writes with an empty key fail with `InvalidInput` instead of
+    /// storing it. Defaults to off. Empty values are always allowed: in the
+    /// current format a deletion is a flagged record, not an empty value,
+    /// though in legacy logs the two read back the same.
+    pub reject_empty_keys: bool,
+    /// Whether [`insert`](crate::ActionKV::insert), and the writes built on
+    /// it such as `update`, skip appending a record when the live value of
+    /// the key is already the one being written. Defaults to off. Turning
+    /// it on costs every such write a read of the current value from the
+    /// log, and a checksum, even when the values differ. A value that
+    /// expires never counts as identical, so an insert still clears its
+    /// expiry. Batches always append.
+    pub skip_identical_writes: bool,
+    /// Largest value, in bytes before any compression or encryption, that a
+    /// write accepts. Defaults to `None`, no limit beyond the format's. A
+    /// larger one fails with `InvalidInput` before anything is written, and
+    /// a batch holding one writes none of its records.
+    pub max_value_bytes: Option<u64>,
+    /// Size of the log, every segment counted, past which writes are
+    /// refused with `StorageFull`. Defaults to `None`, no limit. A write is
+    /// refused once the log has reached the limit, so the write that
+    /// crosses it can overshoot by one record or batch. Deletions are
+    /// records too and are refused alike;
+    /// [`compact`](crate::ActionKV::compact) still runs and can bring the
+    /// log back under.
+    pub max_total_bytes: Option<u64>,
+    /// Capacity in bytes of the buffers used to walk or write the log in
+    /// bulk: by `load`, `find`, `insert_batch` and `compact`. Defaults to
+    /// 8 KiB. Larger buffers mean fewer system calls on big logs.
+    pub buffer_capacity: Option<usize>,
+    /// Boundary, in bytes, that every record written through this handle
+    /// starts on, such as 512 or 4096 for readers that work in fixed
+    /// blocks. Defaults to `None`, which packs records end to end, as do 0
+    /// and 1.
+    ///
+    /// The gap before a record is filled by a padding record, which every
+    /// scan skips and which counts as dead bytes. A gap too small to hold
+    /// one is widened by another boundary's worth. Padding needs a record
+    /// flags byte, so writes to a legacy log with this set fail with
+    /// `InvalidInput`. Compaction and merging align what they rewrite the
+    /// same way. Logs with padding can't be read by versions of this crate
+    /// from before it.
+    pub record_alignment: Option<u64>,
+    /// Whether every record written through this handle carries the Unix
+    /// time in milliseconds it was written at, which
+    /// [`modified_since`](crate::ActionKV::modified_since) queries. Defaults
+    /// to off, which costs nothing. The time takes 8 bytes in each record,
+    /// and compaction keeps it.
+    ///
+    /// Logs written with this set can't be read by versions of this crate
+    /// from before it, though the ones written without it still can. The
+    /// time needs a record flags byte, so writes to a legacy log with this
+    /// set fail with `InvalidInput`.
+    pub record_write_times: bool,
+    /// Fraction of the log's record bytes that may be dead, as
+    /// [`Stats::dead_ratio`](crate::Stats::dead_ratio) measures it, before a
+    /// write compacts the log on its own. Defaults to `None`, which leaves
+    /// compaction to explicit calls to [`compact`](crate::ActionKV::compact)
+    /// and never stalls a write on it.
+    ///
+    /// The check runs as each write starts, against a running count of dead
+    /// bytes rather than a scan, so the write that crosses the ratio is the
+    /// one after it that pays for the compaction. The count needs an index
+    /// of the whole log, so nothing is compacted until the store has been
+    /// loaded, unless it was created empty. The log is compacted into a
+    /// file next to it, named after it with `.compact` appended. Only stores
+    /// opened by path compact on their own.
+    pub auto_compact_ratio: Option<f64>,
+    /// Compression of values written through this handle. Defaults to
+    /// none. Unlike the layout settings, this applies to existing logs too.
+    #[cfg(feature = "compression")]
+    pub compression: Option<Compression>,
+    /// Key that values written through this handle are encrypted with, and
+    /// that encrypted values are decrypted with. Defaults to none. Keys
+    /// are stored in the clear, and so are values written without a key.
+    ///
+    /// The key is kept nowhere but here. A store whose key is lost can't be
+    /// read back, by this crate or anything else.
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<EncryptionKey>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            endianness: Endianness::default(),
+            checksum: ChecksumAlgorithm::default(),
+            index: IndexKind::default(),
+            index_capacity: 0,
+            build_index: true,
+            recover_torn_tail: false,
+            ignore_zeroed_tail: true,
+            on_corruption: CorruptionPolicy::default(),
+            sync_policy: SyncPolicy::default(),
+            max_segment_bytes: None,
+            verify_checksums: true,
+            reject_empty_keys: false,
+            skip_identical_writes: false,
+            max_value_bytes: None,
+            max_total_bytes: None,
+            buffer_capacity: None,
+            record_alignment: None,
+            record_write_times: false,
+            auto_compact_ratio: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+}
+
+/// When a store calls [`sync`](crate::ActionKV::sync) on its own.
+///
+/// Every write reaches the operating system before it returns whatever the
+/// policy, so a process crash loses nothing. The policy bounds what a power
+/// loss or operating system crash can take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Only explicit calls to `sync` reach the disk. A crash can lose every
+    /// write since the last one.
+    #[default]
+    Manual,
+    /// Every write is synced before it returns. Nothing acknowledged is
+    /// lost, at the cost of one `fsync` per write.
+    ///
+    /// That cost is large: each write waits for the disk, which on most
+    /// hardware limits a store to somewhere between hundreds and a few
+    /// thousand writes a second, however small. Batches sync once for all
+    /// their records, so [`insert_batch`](crate::ActionKV::insert_batch)
+    /// and `Batched` are the ways to win the throughput back.
+    Always,
+    /// Group commit: writes are synced together once `writes` of them are
+    /// pending, or once `interval` has passed since the last sync. A crash
+    /// can lose at most `writes - 1` writes, all made within `interval` of
+    /// a sync.
+    ///
+    /// The policy is checked as each write completes, with no background
+    /// thread, so writes made just before the store goes quiet stay pending
+    /// until the next write or a call to [`flush`](crate::ActionKV::flush)
+    /// or `sync`, which commits them at once.
+    Batched { writes: usize, interval: Duration },
+}
+
+/// What [`load`](crate::ActionKV::load) does when it reads a damaged record,
+/// one that fails its checksum or can't be decoded.
+///
+/// A torn record at the end of the log is handled by
+/// [`Options::recover_torn_tail`] under `Abort` and `Skip`, and like any
+/// other damage under `Truncate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptionPolicy {
+    /// Stops with the error, such as [`Error::Corrupt`](crate::Error) with
+    /// the record's offset. The records before it stay indexed.
+    #[default]
+    Abort,
+    /// Skips the damaged record and carries on where
+    /// [`verify`](crate::ActionKV::verify) would, at the next offset that
+    /// holds an intact record, so records after damage that shifted the
+    /// bytes are still indexed. What the scan passes over is counted in
+    /// [`skipped_bytes`](crate::ActionKV::skipped_bytes). Damage with no
+    /// intact record after it is a torn tail, as
+    /// [`Options::recover_torn_tail`] describes.
+    ///
+    /// A value that itself holds a record in the log's format can be
+    /// mistaken for one, so this is for salvaging what is left of a damaged
+    /// log rather than for everyday opens.
+    Skip,
+    /// Cuts the log off where the damaged record starts, dropping it and
+    /// everything after it, and counts the bytes in
+    /// [`discarded_bytes`](crate::ActionKV::discarded_bytes). Only the
+    /// active segment can be cut, by a handle that can write; damage
+    /// anywhere else stops the load as under `Abort`.
+    Truncate,
+}
+
+/// Buffer capacity when [`Options::buffer_capacity`] is unset.
+const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+
+impl Options {
+    pub(crate) fn buffer_capacity(&self) -> usize {
+        self.buffer_capacity.unwrap_or(DEFAULT_BUFFER_CAPACITY)
+    }
+}
@@ -0,0 +1,69 @@
+//! Positioned reads, which let lookups share a log without moving its
+//! cursor.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::storage::Storage;
+
+/// Reads storage sequentially from `position` through a shared reference,
+/// using [`Storage::read_at`].
+pub(crate) struct ReadAt<'a, S> {
+    f: &'a S,
+    position: u64,
+}
+
+impl<'a, S: Storage> ReadAt<'a, S> {
+    pub(crate) fn new(f: &'a S, position: u64) -> Self {
+        ReadAt { f, position }
+    }
+
+    /// Where the next read starts.
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<S: Storage> Read for ReadAt<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.f.read_at(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Moves where the next read starts, which lets a `BufReader` skip what it
+/// doesn't need to read.
+impl<S: Storage> Seek for ReadAt<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.f.byte_len()?.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek outside the storage")
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_from_position_without_moving_cursor() {
+        let mut f = tempfile::tempfile().unwrap();
+        f.write_all(b"hello world").unwrap();
+        f.seek(SeekFrom::Start(2)).unwrap();
+
+        let mut buf = String::new();
+        ReadAt::new(&f, 6).read_to_string(&mut buf).unwrap();
+
+        assert_eq!(buf, "world");
+        #[cfg(unix)]
+        assert_eq!(f.stream_position().unwrap(), 2);
+    }
+}
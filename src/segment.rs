@@ -0,0 +1,387 @@
+//! Segmented logs, which continue in a new file once the active one is full.
+//!
+//! Segment 0 is the file the store was opened with, and segment `n` is that
+//! path with `.n` appended. Every segment is a complete log with its own
+//! header, all in the format of segment 0. A record is addressed by a
+//! position that packs its segment number above the low [`OFFSET_BITS`]
+//! bits of its offset, so in segment 0 a position is just the offset.
+//!
+//! [`merge_segments`](ActionKV::merge_segments) and
+//! [`compact`](ActionKV::compact) give each segment they write a hint file,
+//! the segment's path with `.hint` appended. It is an index sidecar listing
+//! the key and position of every record the segment held when it was
+//! written, none of them deletions, and the segment's length then.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::format::Format;
+use crate::{encode_value, family, lock, now_millis, padding_len, sidecar};
+use crate::{write_padding, write_record};
+use crate::{ActionKV, ByteString, Result, Storage};
+
+/// Bits of a position that hold the offset within a segment.
+pub(crate) const OFFSET_BITS: u32 = 48;
+
+/// Highest segment number a position can hold.
+pub(crate) const MAX_SEGMENT: u32 = (1 << (64 - OFFSET_BITS)) - 1;
+
+/// The position of the byte at `offset` in `segment`.
+pub(crate) fn position(segment: u32, offset: u64) -> u64 {
+    (segment as u64) << OFFSET_BITS | offset
+}
+
+/// Splits a position into its segment number and offset.
+pub(crate) fn split(position: u64) -> (u32, u64) {
+    (
+        (position >> OFFSET_BITS) as u32,
+        position & ((1 << OFFSET_BITS) - 1),
+    )
+}
+
+/// The file holding `segment` of the log at `base`.
+pub(crate) fn path(base: &Path, segment: u32) -> PathBuf {
+    if segment == 0 {
+        return base.to_path_buf();
+    }
+
+    let mut name = OsString::from(base);
+    name.push(format!(".{}", segment));
+    PathBuf::from(name)
+}
+
+/// The hint file of `segment` of the log at `base`.
+pub(crate) fn hint_path(base: &Path, segment: u32) -> PathBuf {
+    let mut name = OsString::from(path(base, segment));
+    name.push(".hint");
+    PathBuf::from(name)
+}
+
+/// Deletes the hint file of `segment`, if it has one.
+pub(crate) fn remove_hint(base: &Path, segment: u32) -> io::Result<()> {
+    match fs::remove_file(hint_path(base, segment)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Numbers of the segments after the first that exist for the log at
+/// `base`, in order.
+pub(crate) fn existing(base: &Path) -> io::Result<Vec<u32>> {
+    let name = match base.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return Ok(Vec::new()),
+    };
+    let dir = match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let number = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(name))
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .filter(|digits| !digits.starts_with('0') && digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse().ok())
+            .filter(|number| (1..=MAX_SEGMENT).contains(number));
+        segments.extend(number);
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// What [`ActionKV::merge_segments`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MergeReport {
+    /// How many bytes smaller the sealed segments are.
+    pub bytes_reclaimed: u64,
+    /// How many fewer segment files there are.
+    pub segments_removed: usize,
+}
+
+/// A segment being written by a merge.
+struct Merged {
+    number: u32,
+    tmp: PathBuf,
+    w: BufWriter<File>,
+    len: u64,
+    sequence: u64,
+    /// Every record written, with its column family.
+    entries: Vec<(u32, ByteString, u64)>,
+}
+
+impl Merged {
+    fn create(base: &Path, number: u32, format: Format, capacity: usize) -> io::Result<Merged> {
+        let mut tmp = OsString::from(path(base, number));
+        tmp.push(".merge");
+        let tmp = PathBuf::from(tmp);
+
+        let mut w = BufWriter::with_capacity(capacity, File::create(&tmp)?);
+        w.write_all(&format.header())?;
+        Ok(Merged {
+            number,
+            tmp,
+            w,
+            len: format.data_start(),
+            sequence: 0,
+            entries: Vec::new(),
+        })
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Indexes segment `number` from its hint file and scans the records
+    /// appended since, returning how many keys and records that was. `None`
+    /// means it has no hint file that fits it.
+    pub(crate) fn load_hint(&mut self, number: u32) -> Result<Option<usize>> {
+        let base = match &self.path {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+        let hint = match sidecar::load(&hint_path(base, number))? {
+            Some(hint) => hint,
+            None => return Ok(None),
+        };
+        let (hinted, len) = split(hint.log_len);
+        let segment_len = self.segment_storage(number)?.byte_len()?;
+        if hinted != number || len < self.format.data_start() || len > segment_len {
+            return Ok(None);
+        }
+
+        // Hinted segments hold no deletions: they replace every segment
+        // before them, so there is nothing older for one to delete.
+        let keys = hint.index.len();
+        self.sequence = self.sequence.max(hint.sequence);
+        for (key, position) in hint.index {
+            self.index.insert(key, position);
+        }
+        Ok(Some(keys + self.scan_from(hint.log_len)?))
+    }
+}
+
+impl ActionKV {
+    /// Rewrites the sealed segments of a segmented log keeping only the live
+    /// record of each key, and reports how much smaller they came out. The
+    /// active segment isn't touched.
+    ///
+    /// The live records are written in log order into as few segments as
+    /// [`Options::max_segment_bytes`](crate::Options::max_segment_bytes)
+    /// allows, taking the numbers of the first old segments, and each gets a
+    /// hint file so [`load`](ActionKV::load) can index it without reading
+    /// it. Deleted and expired keys are dropped. This runs under
+    /// `&mut self` and reads every live sealed record, so it takes about as
+    /// long as compacting the sealed segments would, but the active segment
+    /// and its recent writes are never copied.
+    ///
+    /// The old segments are replaced from the first on. A crash partway
+    /// leaves new segments followed by old ones, which are newer than
+    /// anything moved out of them, so the log still loads to the same state.
+    pub fn merge_segments(&mut self) -> Result<MergeReport> {
+        self.check_writable()?;
+        self.check_indexed()?;
+        let base = match &self.path {
+            Some(base) if !self.sealed.is_empty() => base.clone(),
+            _ => return Ok(MergeReport::default()),
+        };
+
+        let numbers: Vec<u32> = self.sealed.iter().map(|(number, _)| *number).collect();
+        let mut old_len = 0;
+        for (_, f) in &self.sealed {
+            old_len += f.metadata()?.len();
+        }
+        let mut positions = family::positions(&self.index, &self.families);
+        positions.retain(|position| split(*position).0 != self.segment);
+
+        let format = self.format;
+        let capacity = self.options.buffer_capacity();
+        let now = now_millis();
+        // The first segment holds the lock, so there is always one.
+        let mut merged = vec![Merged::create(&base, numbers[0], format, capacity)?];
+        let mut write_times = Vec::new();
+        for old_position in positions {
+            // Never give a corrupt record a fresh checksum.
+            let record = self.record_at(old_position, true)?;
+            if record.is_expired(now) {
+                continue;
+            }
+
+            let mut last = merged.len() - 1;
+            let full = self
+                .options
+                .max_segment_bytes
+                .is_some_and(|max| merged[last].len >= max);
+            // Past the last old number everything goes in one segment.
+            if full && merged.len() < numbers.len() {
+                merged.push(Merged::create(
+                    &base,
+                    numbers[merged.len()],
+                    format,
+                    capacity,
+                )?);
+                last += 1;
+            }
+
+            let current = &mut merged[last];
+            let padding = padding_len(format, current.len, self.options.record_alignment);
+            write_padding(&mut current.w, format, padding)?;
+            current.len += padding;
+            let (value, flags) = encode_value(&self.options, format, &record.key, &record.value)?;
+            let len = write_record(
+                &mut current.w,
+                format,
+                &record.key,
+                &value,
+                flags,
+                record.sequence,
+                record.extras(),
+            )?;
+            let at = position(current.number, current.len);
+            if record.written_at != 0 {
+                write_times.push((at, record.times()));
+            }
+            current.entries.push((record.family, record.key, at));
+            current.len += len;
+            current.sequence = current.sequence.max(record.sequence);
+        }
+
+        // The merged segments take the numbers, and so the positions, of the
+        // old ones.
+        let active = self.segment;
+        self.write_times
+            .retain(|position, _| split(*position).0 == active);
+
+        let mut sealed = Vec::with_capacity(merged.len());
+        let mut new_len = 0;
+        for segment in merged {
+            segment
+                .w
+                .into_inner()
+                .map_err(io::Error::from)?
+                .sync_all()?;
+
+            // Lock the new first segment before it takes the old one's
+            // place, as compaction does, and never leave an old hint next
+            // to a new segment.
+            let f = ActionKV::open_file(&segment.tmp)?;
+            if segment.number == 0 {
+                lock(&f, &segment.tmp, false)?;
+            }
+            remove_hint(&base, segment.number)?;
+            fs::rename(&segment.tmp, path(&base, segment.number))?;
+            // Hints only have room for the default column family.
+            if self.families.is_empty() && self.index.has_keys() {
+                let end = position(segment.number, segment.len);
+                let entries = segment
+                    .entries
+                    .iter()
+                    .map(|(_, key, position)| (key, position));
+                sidecar::save(
+                    &hint_path(&base, segment.number),
+                    end,
+                    segment.sequence,
+                    entries,
+                )?;
+            }
+
+            new_len += segment.len;
+            let kind = self.index.kind();
+            for (family, key, position) in segment.entries {
+                family::index_mut(&mut self.index, &mut self.families, kind, family)
+                    .insert(key, position);
+            }
+            sealed.push((segment.number, f));
+        }
+
+        let segments_removed = numbers.len() - sealed.len();
+        for number in &numbers[sealed.len()..] {
+            fs::remove_file(path(&base, *number))?;
+            remove_hint(&base, *number)?;
+        }
+        self.sealed = sealed;
+        self.write_times.extend(write_times);
+        self.recount()?;
+
+        Ok(MergeReport {
+            bytes_reclaimed: old_len.saturating_sub(new_len),
+            segments_removed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_in_segment_zero_are_offsets() {
+        assert_eq!(position(0, 37), 37);
+        assert_eq!(split(position(3, 37)), (3, 37));
+        assert_eq!(split(position(MAX_SEGMENT, 6)), (MAX_SEGMENT, 6));
+    }
+
+    #[test]
+    fn existing_finds_numbered_siblings_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("store.akv");
+        for name in [
+            "store.akv",
+            "store.akv.2",
+            "store.akv.10",
+            "store.akv.idx",
+            "store.akv.01",
+        ] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        assert_eq!(existing(&base).unwrap(), [2, 10]);
+        assert_eq!(path(&base, 10), dir.path().join("store.akv.10"));
+    }
+
+    #[test]
+    fn merge_keeps_the_live_sealed_records_and_leaves_the_active_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("store.akv");
+        let options = crate::Options {
+            max_segment_bytes: Some(64),
+            ..crate::Options::default()
+        };
+        let mut store = ActionKV::open_with_options(&base, options.clone()).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.delete(b"b").unwrap();
+        store.insert(b"c", b"1").unwrap();
+
+        let report = store.merge_segments().unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                bytes_reclaimed: 68 + 67 - 37,
+                segments_removed: 1,
+            }
+        );
+        assert!(!path(&base, 1).exists());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"1".to_vec()));
+        store.insert(b"d", b"1").unwrap();
+        drop(store);
+
+        let hint = sidecar::load(&hint_path(&base, 0)).unwrap().unwrap();
+        assert_eq!(
+            hint.index.into_iter().collect::<Vec<_>>(),
+            [(b"a".to_vec(), 6)]
+        );
+        let mut store = ActionKV::open_with_options(&base, options).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.sequence(), 6);
+    }
+}
@@ -0,0 +1,212 @@
+//! A store handle that threads can share.
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{ActionKV, ByteStr, ByteString, Result, Storage};
+
+/// An [`ActionKV`] behind an `Arc<RwLock>`, cloned cheaply into every thread
+/// that needs it.
+///
+/// The whole store is one lock. Lookups take it shared, so any number of
+/// [`get`](SharedActionKV::get)s run at once, each reading the log with
+/// positioned I/O. Writes take it exclusively and run one at a time, and
+/// block lookups while they append. Anything without a method here goes
+/// through [`read`](SharedActionKV::read) or
+/// [`write`](SharedActionKV::write).
+///
+/// A panic while the lock is held leaves the store usable: the store only
+/// runs caller code, such as a [`merge`](ActionKV::merge) combiner, before
+/// it changes anything, or, for an [`on_write`](ActionKV::on_write)
+/// callback, once the write is done.
+#[derive(Debug)]
+pub struct SharedActionKV<S: Storage = File> {
+    store: Arc<RwLock<ActionKV<S>>>,
+}
+
+impl<S: Storage> Clone for SharedActionKV<S> {
+    fn clone(&self) -> Self {
+        SharedActionKV {
+            store: Arc::clone(&self.store),
+        }
+    }
+}
+
+impl SharedActionKV {
+    /// Opens and loads the store at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut store = ActionKV::open(path)?;
+        store.load()?;
+        Ok(SharedActionKV::new(store))
+    }
+
+    /// See [`ActionKV::compact_in_place`]. The compaction runs under the
+    /// exclusive lock, and the compacted log is renamed over the old one
+    /// and swapped into the store before the lock is released, so a lookup
+    /// from another thread either finishes against the old log before the
+    /// compaction starts or waits and reads the new one. None sees a
+    /// position from one log applied to the other.
+    pub fn compact_in_place(&self) -> Result<u64> {
+        self.write_guard().compact_in_place()
+    }
+}
+
+impl<S: Storage> SharedActionKV<S> {
+    /// Shares `store`, which should already be loaded.
+    pub fn new(store: ActionKV<S>) -> Self {
+        SharedActionKV {
+            store: Arc::new(RwLock::new(store)),
+        }
+    }
+
+    /// Runs `f` with the store under the shared lock.
+    pub fn read<T>(&self, f: impl FnOnce(&ActionKV<S>) -> T) -> T {
+        f(&self.read_guard())
+    }
+
+    /// Runs `f` with the store under the exclusive lock.
+    pub fn write<T>(&self, f: impl FnOnce(&mut ActionKV<S>) -> T) -> T {
+        f(&mut self.write_guard())
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        self.read_guard().get(key)
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.read_guard().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read_guard().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read_guard().is_empty()
+    }
+
+    /// See [`ActionKV::insert`], including its durability caveat.
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.write_guard().insert(key, value)
+    }
+
+    pub fn update(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.write_guard().update(key, value)
+    }
+
+    pub fn delete(&self, key: &ByteStr) -> Result<()> {
+        self.write_guard().delete(key)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.write_guard().flush()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.write_guard().sync()
+    }
+
+    fn read_guard(&self) -> RwLockReadGuard<'_, ActionKV<S>> {
+        self.store.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_guard(&self) -> RwLockWriteGuard<'_, ActionKV<S>> {
+        self.store.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn stores_and_their_handles_can_cross_threads() {
+        assert_send_sync::<ActionKV>();
+        assert_send_sync::<ActionKV<Cursor<Vec<u8>>>>();
+        assert_send_sync::<SharedActionKV>();
+    }
+
+    #[test]
+    fn threads_share_one_store() {
+        let store = SharedActionKV::new(ActionKV::from_store(Cursor::new(Vec::new())).unwrap());
+
+        let writers: Vec<_> = (0..4u8)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..50u8 {
+                        store.insert(&[t, i], &[i]).unwrap();
+                        assert_eq!(store.get(&[t, i]).unwrap(), Some(vec![i]));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(store.len(), 200);
+        store.delete(&[0, 0]).unwrap();
+        assert!(!store.contains_key(&[0, 0]));
+        assert_eq!(store.read(|store| store.iter().count()), 199);
+    }
+
+    #[test]
+    fn lookups_during_compaction_see_one_log_or_the_other() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SharedActionKV::open(&dir.path().join("store.akv")).unwrap();
+        for round in 0..5u8 {
+            for key in 0..50u8 {
+                store.insert(&[key], &[key, round]).unwrap();
+            }
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        for key in 0..50u8 {
+                            assert_eq!(store.get(&[key]).unwrap(), Some(vec![key, 4]));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..20 {
+            store.compact_in_place().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(store.len(), 50);
+    }
+
+    #[test]
+    fn a_panicking_merge_leaves_the_store_usable() {
+        let store = SharedActionKV::new(ActionKV::from_store(Cursor::new(Vec::new())).unwrap());
+        store.insert(b"a", b"1").unwrap();
+
+        let clone = store.clone();
+        let result = thread::spawn(move || {
+            clone.write(|store| store.merge(b"a", |_| panic!("combiner failed")))
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        store.insert(b"a", b"2").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+}
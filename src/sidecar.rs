@@ -0,0 +1,241 @@
+//! Index sidecar files, which let a store skip rescanning the log on open.
+//!
+//! Layout: `MAGIC | version u8 | log_len u64 | sequence u64 | count u64`,
+//! followed by
+//! `count` entries of `key_len u32 | key | position u64`, and last a CRC-32C
+//! of everything before it as a `u32`. Integers are little endian.
+//!
+//! A sidecar that fails its checksum is treated like any other malformed
+//! one, so a damaged sidecar costs a full scan rather than a wrong index.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::checksum::{Checksum, ChecksumAlgorithm};
+use crate::ByteString;
+
+const MAGIC: &[u8; 4] = b"AKVI";
+const VERSION: u8 = 3;
+
+/// Reads or writes through to `inner`, taking the checksum of the bytes
+/// that pass.
+struct Summed<T> {
+    inner: T,
+    checksum: Box<dyn Checksum>,
+}
+
+impl<T> Summed<T> {
+    fn new(inner: T) -> Self {
+        Summed {
+            inner,
+            checksum: ChecksumAlgorithm::Crc32c.hasher(),
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        self.checksum.finish() as u32
+    }
+}
+
+impl<W: Write> Write for Summed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Summed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// An index as it was when the sidecar was saved.
+pub(crate) struct Snapshot {
+    /// Length of the log the index describes.
+    pub(crate) log_len: u64,
+    /// Highest sequence number in that much of the log.
+    pub(crate) sequence: u64,
+    pub(crate) index: HashMap<ByteString, u64>,
+}
+
+/// Writes the sidecar to a temporary file and renames it over `path`, so a
+/// crash never leaves a half-written sidecar behind. The temporary file is
+/// `path` with `.tmp` appended, so it can't be another file that only
+/// differs from `path` in its extension.
+pub(crate) fn save<'a, I>(path: &Path, log_len: u64, sequence: u64, entries: I) -> io::Result<()>
+where
+    I: ExactSizeIterator<Item = (&'a ByteString, &'a u64)>,
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    {
+        let mut f = Summed::new(BufWriter::new(File::create(&tmp)?));
+        f.write_all(MAGIC)?;
+        f.write_u8(VERSION)?;
+        f.write_u64::<LittleEndian>(log_len)?;
+        f.write_u64::<LittleEndian>(sequence)?;
+        f.write_u64::<LittleEndian>(entries.len() as u64)?;
+        for (key, position) in entries {
+            let key_len = u32::try_from(key.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "key too long for a sidecar")
+            })?;
+            f.write_u32::<LittleEndian>(key_len)?;
+            f.write_all(key)?;
+            f.write_u64::<LittleEndian>(*position)?;
+        }
+        let checksum = f.checksum();
+        let mut f = f.inner;
+        f.write_u32::<LittleEndian>(checksum)?;
+        f.into_inner()?.sync_all()?;
+    }
+    fs::rename(tmp, path)
+}
+
+/// Reads a sidecar, returning `None` when it is missing or isn't one.
+pub(crate) fn load(path: &Path) -> io::Result<Option<Snapshot>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    match read(&mut Summed::new(BufReader::new(f))) {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(err) if is_malformed(&err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn read<R: Read>(f: &mut Summed<R>) -> io::Result<Snapshot> {
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    if &magic != MAGIC || f.read_u8()? != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an index sidecar",
+        ));
+    }
+
+    let log_len = f.read_u64::<LittleEndian>()?;
+    let sequence = f.read_u64::<LittleEndian>()?;
+    let count = f.read_u64::<LittleEndian>()?;
+    let mut index = HashMap::new();
+    for _ in 0..count {
+        let key_len = f.read_u32::<LittleEndian>()?;
+        let mut key = ByteString::new();
+        f.by_ref().take(key_len as u64).read_to_end(&mut key)?;
+        if key.len() != key_len as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let position = f.read_u64::<LittleEndian>()?;
+        if position >= log_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sidecar points past the end of its log",
+            ));
+        }
+        index.insert(key, position);
+    }
+
+    let checksum = f.checksum();
+    if f.inner.read_u32::<LittleEndian>()? != checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "sidecar fails its checksum",
+        ));
+    }
+
+    Ok(Snapshot {
+        log_len,
+        sequence,
+        index,
+    })
+}
+
+fn is_malformed(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.idx");
+        let mut index = HashMap::new();
+        index.insert(b"a".to_vec(), 4);
+        index.insert(b"bb".to_vec(), 20);
+
+        save(&path, 40, 7, index.iter()).unwrap();
+        let snapshot = load(&path).unwrap().unwrap();
+
+        assert_eq!(snapshot.log_len, 40);
+        assert_eq!(snapshot.sequence, 7);
+        assert_eq!(snapshot.index, index);
+    }
+
+    #[test]
+    fn save_leaves_files_with_another_extension_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.idx");
+        let other = dir.path().join("store.tmp");
+        fs::write(&other, b"not the sidecar").unwrap();
+
+        save(&path, 0, 0, HashMap::new().iter()).unwrap();
+        assert_eq!(fs::read(&other).unwrap(), b"not the sidecar");
+        assert!(!dir.path().join("store.idx.tmp").exists());
+        assert!(load(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn missing_or_truncated_sidecar_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.idx");
+        assert!(load(&path).unwrap().is_none());
+
+        let mut index = HashMap::new();
+        index.insert(b"a".to_vec(), 4);
+        save(&path, 40, 1, index.iter()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        assert!(load(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn sidecar_that_fails_its_checksum_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.idx");
+        let mut index = HashMap::new();
+        index.insert(b"a".to_vec(), 4);
+        save(&path, 40, 1, index.iter()).unwrap();
+
+        // The position of `a`, still inside the log, so only the checksum
+        // can tell.
+        let mut bytes = fs::read(&path).unwrap();
+        let position = bytes.len() - 12;
+        bytes[position] = 20;
+        fs::write(&path, bytes).unwrap();
+        assert!(load(&path).unwrap().is_none());
+    }
+}
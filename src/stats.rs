@@ -0,0 +1,43 @@
+//! Store introspection.
+
+/// A snapshot of how a store's log is used, from
+/// [`ActionKV::stats`](crate::ActionKV::stats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of keys in the index of the default column family.
+    pub live_keys: usize,
+    /// Length of the whole log, including its header.
+    pub log_bytes: u64,
+    /// Bytes taken by the records the indexes of every column family point
+    /// at.
+    pub live_bytes: u64,
+    /// Bytes taken by every other record. Compaction reclaims these.
+    pub dead_bytes: u64,
+}
+
+impl Stats {
+    /// Fraction of the records' bytes that are dead, from 0 to 1. Compaction
+    /// is worth it once this gets large; 0.5 means it would halve the log.
+    pub fn dead_ratio(&self) -> f64 {
+        let records = self.live_bytes + self.dead_bytes;
+        if records == 0 {
+            return 0.0;
+        }
+
+        self.dead_bytes as f64 / records as f64
+    }
+}
+
+/// What [`compact`](crate::ActionKV::compact) would make of the log, from
+/// [`estimate_compaction`](crate::ActionKV::estimate_compaction).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Number of keys of the default column family that would be kept.
+    pub live_keys: usize,
+    /// Length of the whole log as it is, including its header.
+    pub total_bytes: u64,
+    /// Length of the compacted log, including its header.
+    pub live_bytes: u64,
+    /// Bytes compaction would reclaim, as it would return them.
+    pub dead_bytes: u64,
+}
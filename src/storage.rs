@@ -0,0 +1,165 @@
+//! What a store's log can live in.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::ActionKV;
+
+/// Byte storage that can hold a log.
+///
+/// Implemented for [`File`], which [`ActionKV::open`](crate::ActionKV::open)
+/// uses, and for `Cursor<Vec<u8>>`, which keeps a whole store in memory.
+/// Writes seek to the end first, so storage doesn't have to be opened in
+/// append mode. Only [`write_at`](Storage::write_at) writes anywhere else.
+pub trait Storage: Read + Write + Seek {
+    /// Reads from `position` into `buf` without moving the cursor, returning
+    /// how many bytes were read. Zero means `position` is at or past the end.
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize>;
+
+    /// The whole storage as one slice, for storage that can provide it
+    /// without copying. Lookups then parse records straight from it.
+    fn mapped(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Length of the storage in bytes.
+    fn byte_len(&self) -> io::Result<u64>;
+
+    /// Truncates the storage to `len` bytes, or zero-extends it.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Overwrites the bytes at `position` with `buf`, which must lie within
+    /// the storage. Storage opened in append mode puts the bytes at the end
+    /// instead; they are cut off again and the write fails with
+    /// `Unsupported`.
+    fn write_at(&mut self, buf: &[u8], position: u64) -> io::Result<()> {
+        let len = self.byte_len()?;
+        self.seek(io::SeekFrom::Start(position))?;
+        self.write_all(buf)?;
+        self.flush()?;
+        if self.byte_len()? > len {
+            self.set_len(len)?;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "storage opened in append mode can't be written in place",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Forces written bytes onto durable media, where there are any.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Opens the file at `path` for another segment of a log split by
+    /// [`Options::max_segment_bytes`](crate::Options::max_segment_bytes),
+    /// creating it if needed. Storage that doesn't live in files can't hold
+    /// segments, and fails with `Unsupported`.
+    fn open_segment(path: &Path) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        let _ = path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this storage cannot hold log segments",
+        ))
+    }
+}
+
+impl Storage for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, position)
+    }
+
+    // Windows has no read that leaves the cursor alone. Moving it is harmless
+    // because every write seeks to the end first.
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, position)
+    }
+
+    fn byte_len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn open_segment(path: &Path) -> io::Result<Self> {
+        ActionKV::open_file(path)
+    }
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        let bytes = self.get_ref();
+        let start = bytes.len().min(position as usize);
+        let n = buf.len().min(bytes.len() - start);
+        buf[..n].copy_from_slice(&bytes[start..start + n]);
+        Ok(n)
+    }
+
+    fn byte_len(&self) -> io::Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_reads_at_a_position() {
+        let f = Cursor::new(b"hello world".to_vec());
+        let mut buf = [0; 8];
+
+        assert_eq!(f.read_at(&mut buf, 6).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(f.read_at(&mut buf, 11).unwrap(), 0);
+        assert_eq!(f.read_at(&mut buf, 100).unwrap(), 0);
+        assert_eq!(f.position(), 0);
+    }
+
+    #[test]
+    fn cursor_set_len_truncates_and_extends() {
+        let mut f = Cursor::new(b"hello".to_vec());
+        Storage::set_len(&mut f, 2).unwrap();
+        assert_eq!(f.get_ref(), b"he");
+        Storage::set_len(&mut f, 4).unwrap();
+        assert_eq!(f.get_ref(), b"he\0\0");
+    }
+
+    #[test]
+    fn write_at_refuses_a_file_in_append_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        std::fs::write(&path, b"hello").unwrap();
+
+        let mut f = File::options().read(true).write(true).open(&path).unwrap();
+        f.write_at(b"j", 0).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"jello");
+
+        let mut f = File::options().append(true).open(&path).unwrap();
+        let err = f.write_at(b"y", 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(std::fs::read(&path).unwrap(), b"jello");
+    }
+}
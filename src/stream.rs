@@ -0,0 +1,342 @@
+//! Streaming reads and writes of single values, for values too large to
+//! hold in memory whole.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+
+use crate::checksum::Checksum;
+use crate::format::{FLAG_COMPRESSED, FLAG_ENCRYPTED};
+use crate::read_at::ReadAt;
+use crate::{encryption, now_millis, segment, ActionKV, ByteStr, ByteString, Error, RecordPrefix};
+use crate::{Extras, RecordValue, Result, Storage, MAX_PREALLOCATION};
+
+/// The value of one key, read from the log as it is consumed, from
+/// [`ActionKV::get_reader`].
+pub struct ValueReader<'a, S: Storage = File> {
+    inner: Inner<'a, S>,
+}
+
+enum Inner<'a, S> {
+    /// A value stored as it is, read straight out of its record.
+    Log {
+        r: ReadAt<'a, S>,
+        remaining: u64,
+        /// The checksum so far and the record to check it against, unless
+        /// checksums are off or the check has been made.
+        check: Option<(Box<dyn Checksum>, RecordPrefix)>,
+        position: u64,
+    },
+    /// A value that had to be decoded whole.
+    Decoded(Cursor<ByteString>),
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// [`get`](ActionKV::get) that reads the value from the log as the
+    /// returned reader is read, rather than into one allocation up front,
+    /// so a huge value can be copied to another sink a buffer at a time.
+    ///
+    /// With [`Options::verify_checksums`](crate::Options) on, as it is by
+    /// default, the checksum is computed as the value goes by and checked
+    /// at its end: the read that would return the end of the value fails
+    /// with `InvalidData` instead, carrying an [`Error::Corrupt`], once
+    /// every byte has already been handed out. Callers that can't take back
+    /// what they wrote should treat the copy as invalid until the reader
+    /// has reached its end.
+    ///
+    /// A compressed or encrypted value can only be decoded whole, so it is
+    /// read and checked in full before this returns, as `get` would.
+    pub fn get_reader(&self, key: &ByteStr) -> Result<Option<ValueReader<'_, S>>> {
+        self.check_indexed()?;
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let (number, offset) = segment::split(position);
+        let mut r = ReadAt::new(self.segment_storage(number)?, offset);
+        let truncated = |err| match err {
+            Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Error::Truncated { offset: position }
+            }
+            err => err,
+        };
+        let prefix = RecordPrefix::read(&mut r, self.format).map_err(truncated)?;
+        let expires_at = prefix.expires_at(self.format)?;
+        if expires_at != 0 && expires_at <= now_millis() {
+            return Ok(None);
+        }
+
+        if prefix.flags & (FLAG_COMPRESSED | FLAG_ENCRYPTED) != 0 {
+            let record = self.record_at(position, self.options.verify_checksums)?;
+            if record.key != key {
+                return Ok(None);
+            }
+            return Ok(Some(ValueReader {
+                inner: Inner::Decoded(Cursor::new(record.value)),
+            }));
+        }
+
+        let mut stored_key =
+            ByteString::with_capacity(prefix.key_len.min(MAX_PREALLOCATION) as usize);
+        r.by_ref()
+            .take(prefix.key_len)
+            .read_to_end(&mut stored_key)?;
+        if (stored_key.len() as u64) < prefix.key_len {
+            return Err(Error::Truncated { offset: position });
+        }
+        // An index of hashed keys can point at another key's record.
+        if stored_key != key {
+            return Ok(None);
+        }
+
+        let remaining = prefix.val_len;
+        let check = if self.options.verify_checksums {
+            let mut hasher = prefix.hasher(self.format);
+            hasher.update(&stored_key);
+            Some((hasher, prefix))
+        } else {
+            None
+        };
+        Ok(Some(ValueReader {
+            inner: Inner::Log {
+                r,
+                remaining,
+                check,
+                position,
+            },
+        }))
+    }
+
+    /// [`insert`](ActionKV::insert) that copies the value from `value` as
+    /// it is written, a buffer at a time, rather than taking it whole. The
+    /// reader must yield exactly `value_len` bytes: fewer fail with
+    /// `UnexpectedEof`, more with `InvalidInput`, and nothing is stored.
+    ///
+    /// A record's checksum comes before its value, so the value is read
+    /// once, with zeros written where the checksum goes, and the checksum
+    /// is written over them once the value has gone by. That needs storage
+    /// that can be written in place, which rules out a file opened in
+    /// append mode: see [`Storage::write_at`]. If the write fails partway,
+    /// the record is cut off the log again. A crash before the checksum is
+    /// written leaves a record that fails its checksum at the end of the
+    /// log, for [`Options::recover_torn_tail`](crate::Options) to handle.
+    ///
+    /// The value is stored as it is, even with
+    /// [`Options::compression`](crate::Options) set. Encryption needs the
+    /// whole value, so a store with an encryption key refuses with
+    /// `InvalidInput`. [`on_write`](ActionKV::on_write) callbacks aren't
+    /// called, as there is no value in memory to hand them.
+    pub fn insert_stream<R: Read>(
+        &mut self,
+        key: &ByteStr,
+        value: &mut R,
+        value_len: u64,
+    ) -> Result<()> {
+        self.check_writable()?;
+        self.check_value_len(value_len)?;
+        if encryption::enabled(&self.options) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a streamed value can't be encrypted",
+            )
+            .into());
+        }
+
+        let mut value = StreamedValue {
+            r: value,
+            len: value_len,
+        };
+        let position = self.append_with(key, &mut value, value_len, 0, Extras::default())?;
+        self.index.insert(key.to_vec(), position);
+        Ok(())
+    }
+}
+
+/// A value being copied from a reader into its record by
+/// [`ActionKV::insert_stream`].
+struct StreamedValue<'r, R> {
+    r: &'r mut R,
+    len: u64,
+}
+
+impl<R: Read> RecordValue for StreamedValue<'_, R> {
+    fn hash(&mut self, _: &mut dyn Checksum) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Copies exactly `len` bytes from the reader to `f`.
+    fn write_to(&mut self, f: &mut dyn Write, hasher: &mut dyn Checksum) -> io::Result<()> {
+        let mut buf = vec![0; cmp::min(self.len, 64 * 1024) as usize];
+        let mut remaining = self.len;
+        while remaining > 0 {
+            let len = cmp::min(buf.len() as u64, remaining) as usize;
+            let n = match self.r.read(&mut buf[..len]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "value ended after {} of {} bytes",
+                            self.len - remaining,
+                            self.len
+                        ),
+                    ))
+                }
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            hasher.update(&buf[..n]);
+            f.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        if self.r.read(&mut [0])? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("value is longer than {} bytes", self.len),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Read for ValueReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (r, remaining, check, position) = match &mut self.inner {
+            Inner::Decoded(value) => return value.read(buf),
+            Inner::Log {
+                r,
+                remaining,
+                check,
+                position,
+            } => (r, remaining, check, *position),
+        };
+
+        if *remaining == 0 {
+            if let Some((hasher, prefix)) = check.take() {
+                prefix
+                    .check(hasher.finish(), position)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+            return Ok(0);
+        }
+
+        let len = cmp::min(buf.len() as u64, *remaining) as usize;
+        let n = r.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                Error::Truncated { offset: position },
+            ));
+        }
+
+        if let Some((hasher, _)) = check {
+            hasher.update(&buf[..n]);
+        }
+        *remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with(value: &[u8]) -> ActionKV<Cursor<Vec<u8>>> {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"big", value).unwrap();
+        store
+    }
+
+    #[test]
+    fn get_reader_streams_the_value_in_small_reads() {
+        let value: ByteString = (0..10_000u32).map(|i| i as u8).collect();
+        let store = store_with(&value);
+        assert!(store.get_reader(b"missing").unwrap().is_none());
+
+        let mut r = store.get_reader(b"big").unwrap().unwrap();
+        let mut copy = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match r.read(&mut buf).unwrap() {
+                0 => break,
+                n => copy.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(copy, value);
+    }
+
+    #[test]
+    fn insert_stream_writes_exactly_value_len_bytes() {
+        let value: ByteString = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut store = store_with(b"old");
+        store
+            .insert_stream(b"big", &mut &value[..], value.len() as u64)
+            .unwrap();
+        assert_eq!(store.get(b"big").unwrap(), Some(value.clone()));
+        let len = store.f.get_ref().len();
+
+        let err = store
+            .insert_stream(b"big", &mut &value[..], value.len() as u64 + 1)
+            .unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+        let err = store
+            .insert_stream(b"big", &mut &value[..], value.len() as u64 - 1)
+            .unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::InvalidInput));
+        assert_eq!(store.f.get_ref().len(), len);
+        assert_eq!(store.get(b"big").unwrap(), Some(value));
+    }
+
+    #[test]
+    fn insert_stream_patches_the_checksum_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert_stream(b"a", &mut &b"streamed"[..], 8).unwrap();
+        store.insert(b"b", b"after").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"streamed".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"after".to_vec()));
+        drop(store);
+
+        let f = File::options().read(true).append(true).open(&path).unwrap();
+        let len = f.metadata().unwrap().len();
+        let mut store = ActionKV::from_store(f).unwrap();
+        store.load().unwrap();
+        let err = store
+            .insert_stream(b"c", &mut &b"streamed"[..], 8)
+            .unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::Unsupported));
+        assert_eq!(store.f.metadata().unwrap().len(), len);
+        assert_eq!(store.get(b"c").unwrap(), None);
+    }
+
+    #[test]
+    fn get_reader_fails_at_the_end_of_a_corrupt_value() {
+        let mut store = store_with(&[1; 100]);
+        let end = store.f.get_ref().len();
+        store.f.get_mut()[end - 1] ^= 0xff;
+
+        let mut copy = Vec::new();
+        let err = store
+            .get_reader(b"big")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut copy)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(copy.len(), 100);
+
+        store.options.verify_checksums = false;
+        let mut copy = Vec::new();
+        let mut r = store.get_reader(b"big").unwrap().unwrap();
+        r.read_to_end(&mut copy).unwrap();
+        assert_eq!(copy.len(), 100);
+    }
+}
@@ -0,0 +1,133 @@
+//! Writes staged in memory and appended together.
+
+use std::collections::HashMap;
+use std::fs::File;
+
+use crate::format::FLAG_TOMBSTONE;
+use crate::{ActionKV, BatchRecord, ByteStr, ByteString, Result, Storage};
+
+/// Inserts and deletes staged in memory, from [`ActionKV::begin`], that
+/// reach the log only when [`commit`](Transaction::commit) is called.
+/// Dropping a transaction without committing it discards them.
+///
+/// Reads through the transaction see its own staged writes over the
+/// store's values. The store itself sees none of them until the commit,
+/// and can't be used otherwise while the transaction borrows it.
+#[derive(Debug)]
+pub struct Transaction<'a, S: Storage = File> {
+    store: &'a mut ActionKV<S>,
+    /// The last write staged for each key, `None` for a deletion.
+    staged: HashMap<ByteString, Option<ByteString>>,
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Starts a [`Transaction`] over the default column family.
+    pub fn begin(&mut self) -> Transaction<'_, S> {
+        Transaction {
+            store: self,
+            staged: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Storage> Transaction<'_, S> {
+    /// The value of `key` as the transaction would leave it: the staged
+    /// write if there is one, otherwise the live value in the store.
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        match self.staged.get(key) {
+            Some(staged) => Ok(staged.clone()),
+            None => self.store.get(key),
+        }
+    }
+
+    /// Stages `value` for `key`, replacing anything staged for it before.
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.staged.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    /// Stages the deletion of `key`, replacing anything staged for it
+    /// before.
+    pub fn delete(&mut self, key: &ByteStr) {
+        self.staged.insert(key.to_vec(), None);
+    }
+
+    /// Number of keys with a staged write.
+    pub fn len(&self) -> usize {
+        self.staged.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty()
+    }
+
+    /// Appends the last staged write of every key as one batch, through a
+    /// single buffered writer and one flush, and indexes them.
+    ///
+    /// The records still land one after another, so a commit is not atomic
+    /// against failures: a write that fails partway, or a crash before the
+    /// flush is done, keeps the records that reached the log in full and
+    /// loses the rest, as [`insert_batch`](ActionKV::insert_batch)
+    /// describes.
+    pub fn commit(self) -> Result<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+
+        let delete_flags = if self.store.format.has_flags() {
+            FLAG_TOMBSTONE
+        } else {
+            0
+        };
+        let records: Vec<_> = self
+            .staged
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => BatchRecord::new(key, value, 0),
+                None => BatchRecord::new(key, b"", delete_flags),
+            })
+            .collect();
+        self.store.append_batch(&records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn staged_writes_show_through_the_transaction_only() {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+
+        let mut tx = store.begin();
+        tx.insert(b"a", b"10");
+        tx.delete(b"b");
+        tx.insert(b"c", b"3");
+        tx.delete(b"c");
+        tx.insert(b"c", b"30");
+        assert_eq!(tx.get(b"a").unwrap(), Some(b"10".to_vec()));
+        assert_eq!(tx.get(b"b").unwrap(), None);
+        assert_eq!(tx.len(), 3);
+        drop(tx);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        let mut tx = store.begin();
+        tx.insert(b"a", b"10");
+        tx.delete(b"b");
+        tx.insert(b"c", b"30");
+        tx.commit().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"10".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"30".to_vec()));
+
+        let f = store.into_inner();
+        let mut store = ActionKV::from_store(f).unwrap();
+        assert_eq!(store.load().unwrap(), 5);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"30".to_vec()));
+    }
+}
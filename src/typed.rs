@@ -0,0 +1,191 @@
+//! A typed layer over the byte-level store.
+
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{ActionKV, Error, Result, Storage};
+
+/// An [`ActionKV`] whose keys and values are `K` and `V`, encoded with
+/// bincode.
+///
+/// Keys are compared by their encoding, so [`IndexKind::Sorted`] orders them
+/// by their bincode bytes rather than by `K`'s own ordering.
+///
+/// Encoding failures are [`Error::Io`] errors of kind `InvalidInput` that
+/// carry the bincode error, and decoding failures are
+/// [`Error::BadFormat`] errors.
+///
+/// [`IndexKind::Sorted`]: crate::IndexKind::Sorted
+#[derive(Debug)]
+pub struct TypedActionKV<K, V, S: Storage = File> {
+    store: ActionKV<S>,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedActionKV<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(TypedActionKV::new(ActionKV::open(path)?))
+    }
+}
+
+impl<K, V, S> TypedActionKV<K, V, S>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    S: Storage,
+{
+    /// Wraps a byte-level store. Existing records must hold bincode encoded
+    /// `K` and `V`, or reading them fails.
+    pub fn new(store: ActionKV<S>) -> Self {
+        TypedActionKV {
+            store,
+            types: PhantomData,
+        }
+    }
+
+    /// The byte-level store underneath.
+    pub fn inner(&self) -> &ActionKV<S> {
+        &self.store
+    }
+
+    pub fn inner_mut(&mut self) -> &mut ActionKV<S> {
+        &mut self.store
+    }
+
+    pub fn into_inner(self) -> ActionKV<S> {
+        self.store
+    }
+
+    /// See [`ActionKV::load`].
+    pub fn load(&mut self) -> Result<usize> {
+        self.store.load()
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.store.get(&encode(key)?)? {
+            None => Ok(None),
+            Some(value) => decode(&value).map(Some),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        Ok(self.store.contains_key(&encode(key)?))
+    }
+
+    /// See [`ActionKV::insert`], including its durability caveat.
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        self.store.insert(&encode(key)?, &encode(value)?)
+    }
+
+    pub fn update(&mut self, key: &K, value: &V) -> Result<()> {
+        self.insert(key, value)
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        self.store.delete(&encode(key)?)
+    }
+
+    /// Iterates over every live pair, in no particular order.
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        self.store
+            .iter()
+            .map(|kv| kv.and_then(|kv| Ok((decode(&kv.key)?, decode(&kv.value)?))))
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.store.flush()
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.store.sync()
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    bincode::serialize(value)
+        .map_err(|err| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, err)))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    bincode::deserialize(bytes)
+        .map_err(|err| Error::BadFormat(format!("undecodable typed record: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    fn store() -> TypedActionKV<u64, User, Cursor<Vec<u8>>> {
+        TypedActionKV::new(ActionKV::from_store(Cursor::new(Vec::new())).unwrap())
+    }
+
+    #[test]
+    fn round_trips_structs() {
+        let mut store = store();
+        let ada = User {
+            name: "Ada".to_string(),
+            age: 36,
+        };
+        store.insert(&1, &ada).unwrap();
+        store
+            .insert(
+                &2,
+                &User {
+                    name: "Alan".to_string(),
+                    age: 41,
+                },
+            )
+            .unwrap();
+        store.delete(&2).unwrap();
+
+        let bytes = store.into_inner().into_inner().into_inner();
+        let mut store: TypedActionKV<u64, User, _> =
+            TypedActionKV::new(ActionKV::from_store(Cursor::new(bytes)).unwrap());
+        store.load().unwrap();
+
+        assert_eq!(store.get(&1).unwrap(), Some(ada));
+        assert_eq!(store.get(&2).unwrap(), None);
+        assert!(store.contains_key(&1).unwrap());
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.iter().count(), 1);
+    }
+
+    #[test]
+    fn undecodable_values_are_bad_format() {
+        let mut store = store();
+        store
+            .inner_mut()
+            .insert(&bincode::serialize(&7u64).unwrap(), b"\x01")
+            .unwrap();
+
+        let err = store.get(&7).unwrap_err();
+        assert!(matches!(err, Error::BadFormat(_)));
+    }
+}
@@ -0,0 +1,248 @@
+//! Integrity checks over the whole log.
+
+use std::io;
+use std::io::BufReader;
+
+use crate::format::KNOWN_FLAGS;
+use crate::read_at::ReadAt;
+use crate::{process_record, segment, ActionKV, ByteStr, ByteString, Error, Result, Storage};
+
+/// What [`ActionKV::verify`] found in a log.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Records that match their checksums.
+    pub valid_records: usize,
+    /// Bytes after the segment headers that belong to no valid record.
+    pub damaged_bytes: u64,
+    /// Every damaged record in log order, as the [`Error::Corrupt`],
+    /// [`Error::Truncated`] or [`Error::BadFormat`] that reading it fails
+    /// with. A `Corrupt` error carries the stored and recomputed checksums.
+    pub damage: Vec<Error>,
+}
+
+impl VerifyReport {
+    /// Whether every record is intact.
+    pub fn is_clean(&self) -> bool {
+        self.damage.is_empty()
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Reads every record of the log and checks it against its checksum,
+    /// whatever [`Options::verify_checksums`](crate::Options) says.
+    ///
+    /// Unlike [`load`](ActionKV::load), which stops at the first damaged
+    /// record, this reports it and carries on. When a record fails its
+    /// checksum but its lengths point at a plausible record header, reading
+    /// resumes there. Otherwise it resumes at the first later offset that
+    /// holds an intact record, so a value that itself contains a record in
+    /// the log's format can be mistaken for one.
+    ///
+    /// Nothing is indexed or repaired. Only failing to read the log is an
+    /// error.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for (number, f) in self.segments() {
+            self.verify_segment(number, f, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// Checks every position in the index against the log, and returns the
+    /// keys whose positions no longer hold a live record of that key, in
+    /// key order.
+    ///
+    /// A position fails when it lies outside the log's records, when the
+    /// record there is damaged, or when it is a deletion or another key's
+    /// record, as happens when the log is replaced or rewritten underneath
+    /// a loaded store. Checksums are always verified. Nothing is repaired:
+    /// [`load`](ActionKV::load) rebuilds the index from the log.
+    pub fn validate_index(&self) -> Result<Vec<ByteString>> {
+        if !self.index.has_keys() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an index of hashed keys has no keys to validate",
+            )
+            .into());
+        }
+        let mut drifted = Vec::new();
+        for (key, position) in self.index.iter() {
+            if !self.holds(*position, key)? {
+                drifted.push(key.clone());
+            }
+        }
+        drifted.sort_unstable();
+        Ok(drifted)
+    }
+
+    /// Whether a live record of `key` starts at `position`.
+    fn holds(&self, position: u64, key: &ByteStr) -> Result<bool> {
+        if !self.within_records(position)? {
+            return Ok(false);
+        }
+        match self.stored_record_at(position, true) {
+            Ok(record) => Ok(!record.tombstone && record.key == key),
+            Err(err) if err.is_damage() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Adds what [`verify`](ActionKV::verify) finds in one segment to
+    /// `report`.
+    fn verify_segment(&self, number: u32, f: &S, report: &mut VerifyReport) -> Result<()> {
+        // Positions of the segment's bytes, which subtract like offsets.
+        let base = segment::position(number, 0);
+        let len = base + f.byte_len()?;
+        let reader = |position| BufReader::new(ReadAt::new(f, position - base));
+        let consumed =
+            |r: &BufReader<ReadAt<'_, S>>| base + r.get_ref().position() - r.buffer().len() as u64;
+        let mut valid_bytes = 0;
+
+        let mut position = base + self.format.data_start();
+        let mut r = reader(position);
+        while position < len {
+            match process_record(&mut r, self.format, position, true) {
+                Ok(record) => {
+                    let next = consumed(&r);
+                    report.valid_records += !record.padding as usize;
+                    valid_bytes += next - position;
+                    position = next;
+                }
+                Err(err) if err.is_damage() => {
+                    let end = match err {
+                        Error::Corrupt { .. } => Some(consumed(&r)),
+                        _ => None,
+                    };
+                    report.damage.push(err);
+
+                    match self.resync(position, end, len)? {
+                        Some(next) => {
+                            position = next;
+                            r = reader(position);
+                        }
+                        None => break,
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        report.damaged_bytes += (len - base).saturating_sub(self.format.data_start()) - valid_bytes;
+        Ok(())
+    }
+
+    /// Finds where reading can resume after the damaged record at `start`:
+    /// `end`, where the record says it ends, if a record header fits there,
+    /// or else the first intact record after `start`.
+    pub(crate) fn resync(&self, start: u64, end: Option<u64>, len: u64) -> Result<Option<u64>> {
+        if let Some(end) = end.filter(|end| *end < len) {
+            if self.fits_header(end, len)? {
+                return Ok(Some(end));
+            }
+        }
+
+        for position in start + 1..len {
+            if !self.fits_header(position, len)? {
+                continue;
+            }
+            match self.stored_record_at(position, true) {
+                Ok(_) => return Ok(Some(position)),
+                Err(err) if err.is_damage() => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the bytes at `position` read as a record header with known
+    /// flags and a record that fits in the log. This rules out most offsets
+    /// without reading the data their lengths would make up.
+    fn fits_header(&self, position: u64, len: u64) -> Result<bool> {
+        match self.header_at(position) {
+            Ok(header) => Ok(header.flags & !KNOWN_FLAGS == 0
+                && header
+                    .key_len
+                    .checked_add(header.val_len)
+                    .is_some_and(|data_len| data_len <= len - position)),
+            Err(err) if err.is_damage() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn three_records() -> ActionKV<Cursor<Vec<u8>>> {
+        let mut store = ActionKV::from_store(Cursor::new(Vec::new())).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.insert(b"c", b"3").unwrap();
+        store
+    }
+
+    #[test]
+    fn verify_reports_a_bad_checksum_and_reads_on() {
+        let mut store = three_records();
+        let report = store.verify().unwrap();
+        assert!(report.is_clean());
+        assert_eq!((report.valid_records, report.damaged_bytes), (3, 0));
+
+        // Last byte of the value of the record at 37.
+        store.f.get_mut()[67] ^= 0xff;
+
+        let report = store.verify().unwrap();
+        assert_eq!((report.valid_records, report.damaged_bytes), (2, 31));
+        assert!(matches!(
+            report.damage[..],
+            [Error::Corrupt { offset: 37, .. }]
+        ));
+    }
+
+    #[test]
+    fn verify_resynchronizes_after_a_garbled_header() {
+        let mut store = three_records();
+        // Top byte of the key length of the record at 37.
+        store.f.get_mut()[48] = 0x80;
+
+        let report = store.verify().unwrap();
+        assert_eq!((report.valid_records, report.damaged_bytes), (2, 31));
+        assert!(matches!(
+            report.damage[..],
+            [Error::Truncated { offset: 37 }]
+        ));
+    }
+
+    #[test]
+    fn verify_reports_a_torn_tail() {
+        let mut store = three_records();
+        let len = store.f.get_ref().len();
+        store.f.get_mut().truncate(len - 3);
+
+        let report = store.verify().unwrap();
+        assert_eq!((report.valid_records, report.damaged_bytes), (2, 28));
+        assert!(matches!(
+            report.damage[..],
+            [Error::Truncated { offset: 68 }]
+        ));
+    }
+
+    #[test]
+    fn validate_index_finds_positions_that_drifted() {
+        let mut store = three_records();
+        assert!(store.validate_index().unwrap().is_empty());
+
+        store.index.insert(b"a".to_vec(), 37);
+        store.index.insert(b"z".to_vec(), 1 << 20);
+        // Last byte of the value of the record at 68.
+        store.f.get_mut()[98] ^= 0xff;
+
+        assert_eq!(
+            store.validate_index().unwrap(),
+            [b"a".to_vec(), b"c".to_vec(), b"z".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn actionkv(store: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_actionkv"))
+        .arg(store)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn insert_get_delete_list() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path().join("store.akv");
+
+    stdout(&actionkv(&store, &["insert", "a", "1"]));
+    stdout(&actionkv(&store, &["insert", "b", "2"]));
+    stdout(&actionkv(&store, &["update", "a", "3"]));
+    assert_eq!(stdout(&actionkv(&store, &["get", "a"])), "3\n");
+
+    stdout(&actionkv(&store, &["delete", "b"]));
+    let missing = actionkv(&store, &["get", "b"]);
+    assert!(!missing.status.success());
+
+    assert_eq!(stdout(&actionkv(&store, &["list"])), "a\t3\n");
+}
+
+#[test]
+fn unknown_action_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = actionkv(&dir.path().join("store.akv"), &["frobnicate"]);
+    assert_eq!(output.status.code(), Some(2));
+}